@@ -0,0 +1,932 @@
+use crate::vm::{Cmd, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error;
+use std::fmt;
+
+// 1行1命令のテキストをVec<Cmd>に変換する
+//
+//     entry main
+//     main:
+//         frame 0 1   ; ローカル変数の数, オペランドスタックの最大使用量
+//         const 5
+//         ret
+//
+// 命令名はCmdの名前をスネークケースにしたもの。アドレスを取るオペランドには数値の代わりにラベルを書ける
+// ;から行末まではコメント
+//
+//     .macro push2 a b    ; マクロ定義。本体中の\aや\bは呼び出し時の引数に、\@は展開ごとに異なる番号に置き換わる
+//         const \a
+//         const \b
+//     .endmacro
+//     .include "lib.s"     ; 別のファイルをその場に展開する
+//     .const SLOTS = 4     ; 名前付きの定数
+//         frame SLOTS SLOTS*2+1
+//
+// 数値のオペランドには整数と定数の + - * / % と括弧からなる式を空白を挟まずに書ける。式はアセンブル時に計算する
+//
+// マクロの展開やインクルードで生じた行のエラーは、展開した行の行番号で報告する
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AsmError {
+    // 1始まりの行番号
+    pub line: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, message: String) -> AsmError {
+        AsmError { line, message }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for AsmError {}
+
+struct Line<'a> {
+    line: usize,
+    mnemonic: &'a str,
+    operands: Vec<&'a str>,
+}
+
+struct Assembler<'a> {
+    labels: HashMap<&'a str, usize>,
+    consts: HashMap<&'a str, Value>,
+}
+
+// 逆アセンブルでラベルや定数の名前を復元するための情報
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Symbols {
+    // アドレスと、そこに付いた最初のラベル
+    pub labels: BTreeMap<usize, String>,
+    pub consts: BTreeMap<String, Value>,
+}
+
+impl<'a> Assembler<'a> {
+    // indexはこの命令のアドレス
+    fn cmd(&self, index: usize, line: &Line) -> Result<Cmd, AsmError> {
+        let arity = match line.mnemonic {
+            "frame" | "tail_call" | "make_closure" => 2,
+            "ret" | "add" | "sub" | "mul" | "div" | "mod" | "eq" | "ne" | "lt" | "le" | "gt"
+            | "ge" | "heap_load" | "heap_store" | "str_concat" | "str_len" | "str_eq" | "print"
+            | "read_int" | "and" | "or" | "not" | "xor" | "shl" | "shr" | "dup" | "swap"
+            | "pop" | "call_indirect" | "call_closure" => 0,
+            "call" | "local_load" | "local_store" | "arg_load" | "arg_store" | "pop_r"
+            | "const" | "entry" | "jump_if" | "jump" | "alloc" | "str_const" | "const_load"
+            | "native_call" | "jump_rel" | "jump_if_rel" | "call_rel" | "func_ref"
+            | "capture_load" => 1,
+            mnemonic => {
+                return Err(AsmError::new(
+                    line.line,
+                    format!("unknown instruction `{}`", mnemonic),
+                ))
+            }
+        };
+        if line.operands.len() != arity {
+            return Err(AsmError::new(
+                line.line,
+                format!(
+                    "`{}` takes {} operand(s) but {} given",
+                    line.mnemonic,
+                    arity,
+                    line.operands.len()
+                ),
+            ));
+        }
+        let count = |i: usize| self.count(line, line.operands[i]);
+        let address = |i: usize| self.address(line, line.operands[i]);
+        let relative = |i: usize| self.relative(index, line, line.operands[i]);
+        Ok(match line.mnemonic {
+            "frame" => Cmd::Frame(count(0)?, count(1)?),
+            "ret" => Cmd::Ret,
+            "call" => Cmd::Call(address(0)?),
+            "local_load" => Cmd::LocalLoad(count(0)?),
+            "local_store" => Cmd::LocalStore(count(0)?),
+            "arg_load" => Cmd::ArgLoad(count(0)?),
+            "arg_store" => Cmd::ArgStore(count(0)?),
+            "pop_r" => Cmd::PopR(count(0)?),
+            "const" => Cmd::Const(self.value(line, line.operands[0])?),
+            "add" => Cmd::Add,
+            "sub" => Cmd::Sub,
+            "mul" => Cmd::Mul,
+            "div" => Cmd::Div,
+            "mod" => Cmd::Mod,
+            "entry" => Cmd::Entry(address(0)?),
+            "eq" => Cmd::Eq,
+            "ne" => Cmd::Ne,
+            "lt" => Cmd::Lt,
+            "le" => Cmd::Le,
+            "gt" => Cmd::Gt,
+            "ge" => Cmd::Ge,
+            "jump_if" => Cmd::JumpIf(address(0)?),
+            "jump" => Cmd::Jump(address(0)?),
+            "alloc" => Cmd::Alloc(count(0)?),
+            "heap_load" => Cmd::HeapLoad,
+            "heap_store" => Cmd::HeapStore,
+            "str_const" => Cmd::StrConst(count(0)?),
+            "str_concat" => Cmd::StrConcat,
+            "str_len" => Cmd::StrLen,
+            "str_eq" => Cmd::StrEq,
+            "const_load" => Cmd::ConstLoad(count(0)?),
+            "native_call" => Cmd::NativeCall(count(0)?),
+            "print" => Cmd::Print,
+            "read_int" => Cmd::ReadInt,
+            "and" => Cmd::And,
+            "or" => Cmd::Or,
+            "not" => Cmd::Not,
+            "xor" => Cmd::Xor,
+            "shl" => Cmd::Shl,
+            "shr" => Cmd::Shr,
+            "dup" => Cmd::Dup,
+            "swap" => Cmd::Swap,
+            "pop" => Cmd::Pop,
+            "tail_call" => Cmd::TailCall(address(0)?, count(1)?),
+            "func_ref" => Cmd::FuncRef(address(0)?),
+            "call_indirect" => Cmd::CallIndirect,
+            "make_closure" => Cmd::MakeClosure(address(0)?, count(1)?),
+            "call_closure" => Cmd::CallClosure,
+            "capture_load" => Cmd::CaptureLoad(count(0)?),
+            "jump_rel" => Cmd::JumpRel(relative(0)?),
+            "jump_if_rel" => Cmd::JumpIfRel(relative(0)?),
+            "call_rel" => Cmd::CallRel(relative(0)?),
+            _ => unreachable!(),
+        })
+    }
+
+    fn count(&self, line: &Line, operand: &str) -> Result<usize, AsmError> {
+        match self.value(line, operand)? {
+            x if x >= 0 => Ok(x as usize),
+            _ => Err(AsmError::new(
+                line.line,
+                format!("invalid number `{}`", operand),
+            )),
+        }
+    }
+
+    fn value(&self, line: &Line, operand: &str) -> Result<Value, AsmError> {
+        eval(operand, &self.consts).map_err(|message| AsmError::new(line.line, message))
+    }
+
+    // ラベルならindexからの相対アドレスにする
+    fn relative(&self, index: usize, line: &Line, operand: &str) -> Result<isize, AsmError> {
+        if let Some(addr) = self.labels.get(operand) {
+            return Ok(*addr as isize - index as isize);
+        }
+        if is_label(operand) && !self.consts.contains_key(operand) {
+            return Err(AsmError::new(
+                line.line,
+                format!("undefined label `{}`", operand),
+            ));
+        }
+        self.value(line, operand).map(|x| x as isize)
+    }
+
+    fn address(&self, line: &Line, operand: &str) -> Result<usize, AsmError> {
+        if let Some(addr) = self.labels.get(operand) {
+            return Ok(*addr);
+        }
+        if is_label(operand) && !self.consts.contains_key(operand) {
+            return Err(AsmError::new(
+                line.line,
+                format!("undefined label `{}`", operand),
+            ));
+        }
+        self.count(line, operand)
+    }
+}
+
+// 定数式を計算する。失敗したらエラーメッセージを返す
+fn eval(expr: &str, consts: &HashMap<&str, Value>) -> Result<Value, String> {
+    let mut parser = ExprParser {
+        expr,
+        rest: expr,
+        consts,
+    };
+    let x = parser.sum()?;
+    if !parser.rest.is_empty() {
+        return Err(parser.invalid());
+    }
+    Ok(x)
+}
+
+struct ExprParser<'a, 'b> {
+    expr: &'a str,
+    // まだ読んでいない部分
+    rest: &'a str,
+    consts: &'b HashMap<&'b str, Value>,
+}
+
+impl<'a, 'b> ExprParser<'a, 'b> {
+    fn invalid(&self) -> String {
+        format!("invalid value `{}`", self.expr)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.rest.starts_with(c) {
+            self.rest = &self.rest[1..];
+            true
+        } else {
+            false
+        }
+    }
+
+    // x ((+|-) x)*
+    fn sum(&mut self) -> Result<Value, String> {
+        let mut x = self.product()?;
+        loop {
+            x = if self.eat('+') {
+                x.checked_add(self.product()?)
+            } else if self.eat('-') {
+                x.checked_sub(self.product()?)
+            } else {
+                return Ok(x);
+            }
+            .ok_or_else(|| format!("overflow in `{}`", self.expr))?;
+        }
+    }
+
+    // x ((*|/|%) x)*
+    fn product(&mut self) -> Result<Value, String> {
+        let mut x = self.unary()?;
+        loop {
+            let op = match self.rest.chars().next() {
+                Some(op @ '*') | Some(op @ '/') | Some(op @ '%') => op,
+                _ => return Ok(x),
+            };
+            self.rest = &self.rest[1..];
+            let y = self.unary()?;
+            if op != '*' && y == 0 {
+                return Err(format!("division by zero in `{}`", self.expr));
+            }
+            x = match op {
+                '*' => x.checked_mul(y),
+                '/' => x.checked_div(y),
+                _ => x.checked_rem(y),
+            }
+            .ok_or_else(|| format!("overflow in `{}`", self.expr))?;
+        }
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        if self.eat('-') {
+            // i64::MINを書けるよう、数値の直前のマイナスは数値の一部として読む
+            if self.rest.starts_with(|c: char| c.is_ascii_digit()) {
+                return self.number(true);
+            }
+            return self
+                .unary()?
+                .checked_neg()
+                .ok_or_else(|| format!("overflow in `{}`", self.expr));
+        }
+        if self.eat('(') {
+            let x = self.sum()?;
+            if !self.eat(')') {
+                return Err(self.invalid());
+            }
+            return Ok(x);
+        }
+        if self.rest.starts_with(|c: char| c.is_ascii_digit()) {
+            return self.number(false);
+        }
+        let len = self
+            .rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+            .unwrap_or(self.rest.len());
+        let name = &self.rest[..len];
+        if !is_label(name) {
+            return Err(self.invalid());
+        }
+        self.rest = &self.rest[len..];
+        self.consts
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("undefined constant `{}`", name))
+    }
+
+    fn number(&mut self, negative: bool) -> Result<Value, String> {
+        let len = self
+            .rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.rest.len());
+        let digits = &self.rest[..len];
+        self.rest = &self.rest[len..];
+        let text = if negative {
+            format!("-{}", digits)
+        } else {
+            digits.to_string()
+        };
+        text.parse().map_err(|_| self.invalid())
+    }
+}
+
+fn is_label(s: &str) -> bool {
+    let mut chars = s.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+// マクロやインクルードがこれより深く入れ子になったら再帰しているとみなす
+const MAX_DEPTH: usize = 16;
+
+#[derive(Clone)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<String>,
+}
+
+// マクロとインクルードを展開し、(元の行番号, 行)の列にする
+struct Preprocessor<'a> {
+    loader: &'a dyn Fn(&str) -> Result<String, String>,
+    macros: HashMap<String, Macro>,
+    expansions: usize,
+    lines: Vec<(usize, String)>,
+}
+
+impl<'a> Preprocessor<'a> {
+    // originがあれば、sourceの全行をその行番号で扱う
+    fn source(
+        &mut self,
+        source: &str,
+        origin: Option<usize>,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let mut lines = source.lines().enumerate();
+        while let Some((i, text)) = lines.next() {
+            let line = origin.unwrap_or(i + 1);
+            let text = strip_comment(text);
+            let mut words = text.split_whitespace();
+            match words.next() {
+                Some(".macro") => {
+                    let name = words
+                        .next()
+                        .ok_or_else(|| AsmError::new(line, "`.macro` needs a name".to_string()))?;
+                    let params = words.map(|p| p.to_string()).collect();
+                    let mut body = Vec::new();
+                    loop {
+                        match lines.next() {
+                            Some((_, text)) if strip_comment(text) == ".endmacro" => break,
+                            Some((_, text)) => body.push(strip_comment(text).to_string()),
+                            None => {
+                                return Err(AsmError::new(
+                                    line,
+                                    format!("unterminated macro `{}`", name),
+                                ))
+                            }
+                        }
+                    }
+                    if self
+                        .macros
+                        .insert(name.to_string(), Macro { params, body })
+                        .is_some()
+                    {
+                        return Err(AsmError::new(line, format!("duplicate macro `{}`", name)));
+                    }
+                }
+                Some(".endmacro") => {
+                    return Err(AsmError::new(
+                        line,
+                        "`.endmacro` without `.macro`".to_string(),
+                    ))
+                }
+                _ => self.line(line, text, depth)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn line(&mut self, line: usize, mut text: &str, depth: usize) -> Result<(), AsmError> {
+        // ラベルはそのまま残し、続く命令だけを展開する
+        while let Some(pos) = text.find(':') {
+            self.lines.push((line, text[..=pos].to_string()));
+            text = text[pos + 1..].trim();
+        }
+        let mut words = text.split_whitespace();
+        let first = match words.next() {
+            Some(first) => first,
+            None => return Ok(()),
+        };
+        if depth >= MAX_DEPTH {
+            return Err(AsmError::new(
+                line,
+                format!("`{}` is nested too deeply", first),
+            ));
+        }
+        if first == ".include" {
+            let name = text[first.len()..].trim();
+            if name.len() < 2 || !name.starts_with('"') || !name.ends_with('"') {
+                return Err(AsmError::new(line, format!("invalid include `{}`", name)));
+            }
+            let source =
+                (self.loader)(&name[1..name.len() - 1]).map_err(|e| AsmError::new(line, e))?;
+            return self.source(&source, Some(line), depth + 1);
+        }
+        let m = match self.macros.get(first) {
+            Some(m) => m.clone(),
+            None => {
+                self.lines.push((line, text.to_string()));
+                return Ok(());
+            }
+        };
+        let args = words.collect::<Vec<_>>();
+        if args.len() != m.params.len() {
+            return Err(AsmError::new(
+                line,
+                format!(
+                    "macro `{}` takes {} argument(s) but {} given",
+                    first,
+                    m.params.len(),
+                    args.len()
+                ),
+            ));
+        }
+        self.expansions += 1;
+        // \abが\aの置き換えで壊れないよう長い名前から置き換える
+        let mut params = m.params.iter().zip(args).collect::<Vec<_>>();
+        params.sort_by_key(|(param, _)| std::cmp::Reverse(param.len()));
+        for body in &m.body {
+            let mut text = body.replace("\\@", &self.expansions.to_string());
+            for (param, arg) in &params {
+                text = text.replace(&format!("\\{}", param), arg);
+            }
+            self.line(line, &text, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(text: &str) -> &str {
+    text.split(';').next().unwrap().trim()
+}
+
+// .includeを使わないプログラムを変換する
+pub fn assemble(source: &str) -> Result<Vec<Cmd>, AsmError> {
+    assemble_with(source, |name| Err(format!("cannot include `{}`", name)))
+}
+
+// .includeしたファイルの中身をloaderで読む
+pub fn assemble_with(
+    source: &str,
+    loader: impl Fn(&str) -> Result<String, String>,
+) -> Result<Vec<Cmd>, AsmError> {
+    assemble_with_symbols(source, loader).map(|(cmds, _)| cmds)
+}
+
+// assemble_withに加えて、disassemble_with_symbolsに渡すラベルと定数の名前を返す
+pub fn assemble_with_symbols(
+    source: &str,
+    loader: impl Fn(&str) -> Result<String, String>,
+) -> Result<(Vec<Cmd>, Symbols), AsmError> {
+    let mut preprocessor = Preprocessor {
+        loader: &loader,
+        macros: HashMap::new(),
+        expansions: 0,
+        lines: Vec::new(),
+    };
+    preprocessor.source(source, None, 0)?;
+
+    let mut lines = Vec::new();
+    let mut labels = HashMap::new();
+    let mut consts = HashMap::new();
+    let mut symbols = Symbols::default();
+    for (line, text) in &preprocessor.lines {
+        let line = *line;
+        let mut text = text.as_str();
+        // 同じ行に命令を続けて書いてもよい
+        while let Some(pos) = text.find(':') {
+            let label = text[..pos].trim();
+            if !is_label(label) {
+                return Err(AsmError::new(line, format!("invalid label `{}`", label)));
+            }
+            if labels.insert(label, lines.len()).is_some() {
+                return Err(AsmError::new(line, format!("duplicate label `{}`", label)));
+            }
+            symbols
+                .labels
+                .entry(lines.len())
+                .or_insert_with(|| label.to_string());
+            text = text[pos + 1..].trim();
+        }
+        let mut words = text.split_whitespace();
+        if words.next() == Some(".const") {
+            // .const NAME = 式
+            let (name, expr) = match text[".const".len()..].split_once('=') {
+                Some((name, expr)) => (name.trim(), expr.trim()),
+                None => return Err(AsmError::new(line, format!("invalid `{}`", text))),
+            };
+            if !is_label(name) {
+                return Err(AsmError::new(
+                    line,
+                    format!("invalid constant name `{}`", name),
+                ));
+            }
+            let x = eval(expr, &consts).map_err(|message| AsmError::new(line, message))?;
+            if consts.insert(name, x).is_some() {
+                return Err(AsmError::new(
+                    line,
+                    format!("duplicate constant `{}`", name),
+                ));
+            }
+            symbols.consts.insert(name.to_string(), x);
+            continue;
+        }
+        let mut words = text.split_whitespace();
+        if let Some(mnemonic) = words.next() {
+            lines.push(Line {
+                line,
+                mnemonic,
+                operands: words.collect(),
+            });
+        }
+    }
+
+    let asm = Assembler { labels, consts };
+    let cmds = lines
+        .iter()
+        .enumerate()
+        .map(|(i, line)| asm.cmd(i, line))
+        .collect::<Result<_, _>>()?;
+    Ok((cmds, symbols))
+}
+
+// アセンブラで読み戻せる形式で書き出す
+// 分岐や呼び出しの行き先にはL<アドレス>のラベルを付け、各行の末尾にアドレスをコメントで添える
+pub fn disassemble(cmds: &[Cmd]) -> String {
+    disassemble_with_symbols(cmds, &Symbols::default())
+}
+
+// symbolsにあるラベルは元の名前で書き、定数は先頭に.constで書き出す
+// 命令のオペランドは数値に戻っているので、定数の名前には置き換えない
+pub fn disassemble_with_symbols(cmds: &[Cmd], symbols: &Symbols) -> String {
+    let name = |addr: usize| match symbols.labels.get(&addr) {
+        Some(label) => label.clone(),
+        None => format!("L{}", addr),
+    };
+    let targets = cmds
+        .iter()
+        .enumerate()
+        .filter_map(|(addr, cmd)| target(cmd, addr))
+        .filter(|addr| *addr < cmds.len())
+        .collect::<HashSet<_>>();
+    let mut out = String::new();
+    for (name, x) in &symbols.consts {
+        out += &format!(".const {} = {}\n", name, x);
+    }
+    for (addr, cmd) in cmds.iter().enumerate() {
+        let label = if targets.contains(&addr) || symbols.labels.contains_key(&addr) {
+            format!("{}:", name(addr))
+        } else {
+            String::new()
+        };
+        let (mnemonic, mut operands) = mnemonic(cmd);
+        let mut comment = format!("; {}", addr);
+        if let Some(target) = target(cmd, addr) {
+            if target < cmds.len() {
+                operands[0] = name(target);
+            } else {
+                comment += " (target out of range)";
+            }
+        }
+        let text = format!("{} {}", mnemonic, operands.join(" "));
+        out += &format!("{:<8}{:<24}{}\n", label, text.trim_end(), comment);
+    }
+    out
+}
+
+// addrにあるcmdの飛び先
+fn target(cmd: &Cmd, addr: usize) -> Option<usize> {
+    match cmd.absolute(addr) {
+        Cmd::Call(x)
+        | Cmd::TailCall(x, _)
+        | Cmd::FuncRef(x)
+        | Cmd::MakeClosure(x, _)
+        | Cmd::Entry(x)
+        | Cmd::JumpIf(x)
+        | Cmd::Jump(x) => Some(x),
+        _ => None,
+    }
+}
+
+fn mnemonic(cmd: &Cmd) -> (&'static str, Vec<String>) {
+    match *cmd {
+        Cmd::Frame(x, y) => ("frame", vec![x.to_string(), y.to_string()]),
+        Cmd::Ret => ("ret", vec![]),
+        Cmd::Call(x) => ("call", vec![x.to_string()]),
+        Cmd::LocalLoad(x) => ("local_load", vec![x.to_string()]),
+        Cmd::LocalStore(x) => ("local_store", vec![x.to_string()]),
+        Cmd::ArgLoad(x) => ("arg_load", vec![x.to_string()]),
+        Cmd::ArgStore(x) => ("arg_store", vec![x.to_string()]),
+        Cmd::PopR(x) => ("pop_r", vec![x.to_string()]),
+        Cmd::Const(x) => ("const", vec![x.to_string()]),
+        Cmd::Add => ("add", vec![]),
+        Cmd::Sub => ("sub", vec![]),
+        Cmd::Mul => ("mul", vec![]),
+        Cmd::Div => ("div", vec![]),
+        Cmd::Mod => ("mod", vec![]),
+        Cmd::Entry(x) => ("entry", vec![x.to_string()]),
+        Cmd::Eq => ("eq", vec![]),
+        Cmd::Ne => ("ne", vec![]),
+        Cmd::Lt => ("lt", vec![]),
+        Cmd::Le => ("le", vec![]),
+        Cmd::Gt => ("gt", vec![]),
+        Cmd::Ge => ("ge", vec![]),
+        Cmd::JumpIf(x) => ("jump_if", vec![x.to_string()]),
+        Cmd::Jump(x) => ("jump", vec![x.to_string()]),
+        Cmd::Alloc(x) => ("alloc", vec![x.to_string()]),
+        Cmd::HeapLoad => ("heap_load", vec![]),
+        Cmd::HeapStore => ("heap_store", vec![]),
+        Cmd::StrConst(x) => ("str_const", vec![x.to_string()]),
+        Cmd::StrConcat => ("str_concat", vec![]),
+        Cmd::StrLen => ("str_len", vec![]),
+        Cmd::StrEq => ("str_eq", vec![]),
+        Cmd::ConstLoad(x) => ("const_load", vec![x.to_string()]),
+        Cmd::NativeCall(x) => ("native_call", vec![x.to_string()]),
+        Cmd::Print => ("print", vec![]),
+        Cmd::ReadInt => ("read_int", vec![]),
+        Cmd::JumpRel(x) => ("jump_rel", vec![x.to_string()]),
+        Cmd::JumpIfRel(x) => ("jump_if_rel", vec![x.to_string()]),
+        Cmd::CallRel(x) => ("call_rel", vec![x.to_string()]),
+        Cmd::And => ("and", vec![]),
+        Cmd::Or => ("or", vec![]),
+        Cmd::Not => ("not", vec![]),
+        Cmd::Xor => ("xor", vec![]),
+        Cmd::Shl => ("shl", vec![]),
+        Cmd::Shr => ("shr", vec![]),
+        Cmd::Dup => ("dup", vec![]),
+        Cmd::Swap => ("swap", vec![]),
+        Cmd::Pop => ("pop", vec![]),
+        Cmd::TailCall(x, y) => ("tail_call", vec![x.to_string(), y.to_string()]),
+        Cmd::FuncRef(x) => ("func_ref", vec![x.to_string()]),
+        Cmd::CallIndirect => ("call_indirect", vec![]),
+        Cmd::MakeClosure(x, y) => ("make_closure", vec![x.to_string(), y.to_string()]),
+        Cmd::CallClosure => ("call_closure", vec![]),
+        Cmd::CaptureLoad(x) => ("capture_load", vec![x.to_string()]),
+    }
+}
+
+#[test]
+fn test() {
+    use crate::vm::VM;
+
+    let cmds = assemble(
+        "
+        entry main
+    main:
+        frame 0 4
+        const 182
+        const 1029
+        call gcd
+        pop_r 2
+        ret
+
+    ; gcd(a:1, b:0)
+    gcd:
+        frame 0 4
+        arg_load 0
+        const 0
+        eq
+        jump_if zero
+        jump rec
+    zero: arg_load 1
+        jump end
+    rec:
+        arg_load 0
+        arg_load 0
+        arg_load 1
+        mod
+        call gcd
+        pop_r 2
+    end:
+        ret
+        ",
+    )
+    .unwrap();
+    assert_eq!(
+        cmds[..5],
+        [
+            Cmd::Entry(1),
+            Cmd::Frame(0, 4),
+            Cmd::Const(182),
+            Cmd::Const(1029),
+            Cmd::Call(7),
+        ]
+    );
+    assert_eq!(cmds[11], Cmd::JumpIf(13));
+    assert_eq!(VM::new(cmds).run(), Ok(7));
+
+    assert_eq!(
+        assemble("const -3\nnop"),
+        Err(AsmError::new(2, "unknown instruction `nop`".to_string()))
+    );
+    assert_eq!(
+        assemble("jump nowhere"),
+        Err(AsmError::new(1, "undefined label `nowhere`".to_string()))
+    );
+    assert_eq!(
+        assemble("a:\na: ret"),
+        Err(AsmError::new(2, "duplicate label `a`".to_string()))
+    );
+    assert_eq!(
+        assemble("frame 1"),
+        Err(AsmError::new(
+            1,
+            "`frame` takes 2 operand(s) but 1 given".to_string()
+        ))
+    );
+    assert_eq!(
+        assemble("const x"),
+        Err(AsmError::new(1, "undefined constant `x`".to_string()))
+    );
+}
+
+#[test]
+fn test_const() {
+    use crate::vm::VM;
+
+    let source = "
+        .const SLOTS = 4
+        .const BIG = -(SLOTS+1)*-3%7
+        entry main
+    main:
+        frame 0 SLOTS*2+1
+        const BIG
+        const SLOTS/3
+        add
+        jump_rel SLOTS-3
+    end: ret
+    ";
+    let (cmds, symbols) = assemble_with_symbols(source, |_| Err(String::new())).unwrap();
+    assert_eq!(
+        cmds,
+        vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 9),
+            Cmd::Const(1),
+            Cmd::Const(1),
+            Cmd::Add,
+            Cmd::JumpRel(1),
+            Cmd::Ret,
+        ]
+    );
+    assert_eq!(VM::new(cmds.clone()).run(), Ok(2));
+    assert_eq!(symbols.consts["BIG"], 1);
+    assert_eq!(symbols.labels[&6], "end");
+
+    let text = disassemble_with_symbols(&cmds, &symbols);
+    assert!(text.starts_with(".const BIG = 1\n.const SLOTS = 4\n        entry main"));
+    assert!(text.contains("main:   frame 0 9"));
+    assert!(text.contains("jump_rel end"));
+    assert_eq!(assemble(&text), Ok(cmds));
+
+    assert_eq!(
+        assemble("const -9223372036854775808"),
+        Ok(vec![Cmd::Const(i64::MIN)])
+    );
+    assert_eq!(
+        assemble(".const A = 1\n.const A = 2"),
+        Err(AsmError::new(2, "duplicate constant `A`".to_string()))
+    );
+    assert_eq!(
+        assemble(".const A = B"),
+        Err(AsmError::new(1, "undefined constant `B`".to_string()))
+    );
+    assert_eq!(
+        assemble("const 1/(2-2)"),
+        Err(AsmError::new(
+            1,
+            "division by zero in `1/(2-2)`".to_string()
+        ))
+    );
+    assert_eq!(
+        assemble("const 9223372036854775807+1"),
+        Err(AsmError::new(
+            1,
+            "overflow in `9223372036854775807+1`".to_string()
+        ))
+    );
+    assert_eq!(
+        assemble("frame 0 1-2"),
+        Err(AsmError::new(1, "invalid number `1-2`".to_string()))
+    );
+    assert_eq!(
+        assemble("const (1"),
+        Err(AsmError::new(1, "invalid value `(1`".to_string()))
+    );
+}
+
+#[test]
+fn test_macro() {
+    use crate::vm::VM;
+
+    let source = "
+        .macro push2 a ab ; 2つ積む
+            const \\ab
+            const \\a
+        .endmacro
+        ; [除数, 被除数]を商にする。除数が0なら0にする
+        .macro safe_div
+            local_store 0
+            local_store 1
+            local_load 1
+            jump_if nonzero\\@
+            const 0
+            jump end\\@
+        nonzero\\@:
+            local_load 1
+            local_load 0
+            div
+        end\\@:
+        .endmacro
+
+        entry main
+    main:
+        frame 2 3
+        push2 7 2
+        safe_div
+        push2 7 0
+        safe_div
+        add
+        ret
+    ";
+    let cmds = assemble(source).unwrap();
+    assert_eq!(cmds[2..4], [Cmd::Const(2), Cmd::Const(7)]);
+    assert_eq!(VM::new(cmds).run(), Ok(3));
+
+    let loader = |name: &str| match name {
+        "lib.s" => Ok(".macro seven\nconst 7\n.endmacro".to_string()),
+        "self.s" => Ok(".include \"self.s\"".to_string()),
+        _ => Err(format!("{} not found", name)),
+    };
+    assert_eq!(
+        assemble_with(".include \"lib.s\"\nseven\nseven", loader),
+        Ok(vec![Cmd::Const(7), Cmd::Const(7)])
+    );
+    assert_eq!(
+        assemble_with("ret\n.include \"none.s\"", loader),
+        Err(AsmError::new(2, "none.s not found".to_string()))
+    );
+    assert_eq!(
+        assemble_with(".include \"self.s\"", loader),
+        Err(AsmError::new(
+            1,
+            "`.include` is nested too deeply".to_string()
+        ))
+    );
+    assert_eq!(
+        assemble(".include \"lib.s\""),
+        Err(AsmError::new(1, "cannot include `lib.s`".to_string()))
+    );
+    assert_eq!(
+        assemble(".macro m x\nconst \\x\n.endmacro\nm"),
+        Err(AsmError::new(
+            4,
+            "macro `m` takes 1 argument(s) but 0 given".to_string()
+        ))
+    );
+    assert_eq!(
+        assemble("ret\n.macro m\nret"),
+        Err(AsmError::new(2, "unterminated macro `m`".to_string()))
+    );
+    assert_eq!(
+        assemble(".macro m\nm\n.endmacro\nm"),
+        Err(AsmError::new(4, "`m` is nested too deeply".to_string()))
+    );
+}
+
+#[test]
+#[cfg(feature = "frontend")]
+fn test_disassemble() {
+    use crate::genprog::{generate, ALL_WORKLOADS};
+
+    let cmds = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 2),
+        Cmd::Const(-1),
+        Cmd::JumpIf(5),
+        Cmd::Jump(9),
+        Cmd::Ret,
+        Cmd::JumpRel(-4),
+        Cmd::CallRel(5),
+    ];
+    assert_eq!(
+        disassemble(&cmds),
+        "        entry L1                ; 0
+L1:     frame 0 2               ; 1
+L2:     const -1                ; 2
+        jump_if L5              ; 3
+        jump 9                  ; 4 (target out of range)
+L5:     ret                     ; 5
+        jump_rel L2             ; 6
+        call_rel 5              ; 7 (target out of range)
+"
+    );
+    assert_eq!(assemble(&disassemble(&cmds)), Ok(cmds));
+
+    for &workload in &ALL_WORKLOADS {
+        let cmds = generate(workload, 3).convert();
+        assert_eq!(assemble(&disassemble(&cmds)), Ok(cmds));
+    }
+}
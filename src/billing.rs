@@ -0,0 +1,124 @@
+use std::collections::BTreeMap;
+
+// 一回の実行、あるいはテナントごとに合計した資源の使用量
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub instructions: u64,
+    // 燃料制限をして実行した分の消費燃料
+    pub gas: u64,
+    // NativeCall, Print, ReadIntの回数
+    pub host_calls: u64,
+    // ヒープに確保したバイト数。解放した分は差し引かない
+    pub allocated_bytes: u64,
+}
+
+impl Usage {
+    pub fn add(&mut self, other: &Usage) {
+        self.instructions += other.instructions;
+        self.gas += other.gas;
+        self.host_calls += other.host_calls;
+        self.allocated_bytes += other.allocated_bytes;
+    }
+}
+
+// テナントIDごとの使用量の集計
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Ledger {
+    tenants: BTreeMap<String, Usage>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    pub fn record(&mut self, tenant: &str, usage: &Usage) {
+        self.tenants
+            .entry(tenant.to_string())
+            .or_default()
+            .add(usage);
+    }
+
+    // 別のVMの集計をまとめる
+    pub fn merge(&mut self, other: &Ledger) {
+        for (tenant, usage) in &other.tenants {
+            self.record(tenant, usage);
+        }
+    }
+
+    pub fn usage(&self, tenant: &str) -> Option<&Usage> {
+        self.tenants.get(tenant)
+    }
+
+    // テナントID順
+    pub fn tenants(&self) -> impl Iterator<Item = (&str, &Usage)> + '_ {
+        self.tenants
+            .iter()
+            .map(|(tenant, usage)| (tenant.as_str(), usage))
+    }
+
+    pub fn total(&self) -> Usage {
+        let mut total = Usage::default();
+        for usage in self.tenants.values() {
+            total.add(usage);
+        }
+        total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+
+    // テナントごとに一行ずつ、命令数、消費燃料、ホスト呼び出し回数、確保したバイト数を並べる
+    pub fn report(&self) -> String {
+        let mut out = format!(
+            "{:<16} {:>12} {:>12} {:>10} {:>12}\n",
+            "tenant", "instructions", "gas", "host_calls", "alloc_bytes"
+        );
+        for (tenant, usage) in self.tenants() {
+            out += &format!(
+                "{:<16} {:>12} {:>12} {:>10} {:>12}\n",
+                tenant, usage.instructions, usage.gas, usage.host_calls, usage.allocated_bytes
+            );
+        }
+        out
+    }
+}
+
+#[test]
+fn test() {
+    let usage = |instructions, host_calls| Usage {
+        instructions,
+        gas: instructions,
+        host_calls,
+        allocated_bytes: 8,
+    };
+    let mut ledger = Ledger::new();
+    assert!(ledger.is_empty());
+    ledger.record("b", &usage(10, 1));
+    ledger.record("a", &usage(5, 0));
+    ledger.record("b", &usage(1, 2));
+    assert_eq!(
+        ledger.usage("b"),
+        Some(&Usage {
+            instructions: 11,
+            gas: 11,
+            host_calls: 3,
+            allocated_bytes: 16,
+        })
+    );
+    assert_eq!(ledger.usage("c"), None);
+    assert_eq!(
+        ledger.tenants().map(|(t, _)| t).collect::<Vec<_>>(),
+        vec!["a", "b"]
+    );
+
+    let mut other = Ledger::new();
+    other.record("c", &usage(4, 0));
+    ledger.merge(&other);
+    assert_eq!(ledger.total().instructions, 20);
+    assert_eq!(
+        ledger.report().lines().nth(3),
+        Some("c                           4            4          0            8")
+    );
+}
@@ -0,0 +1,191 @@
+use crate::trace::Trace;
+use crate::vm::{Value, VmError, VmView, VM};
+use std::collections::BTreeMap;
+
+// ウォッチポイントを置くスロット。どちらも実行中のフレームから見た番号
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slot {
+    Local(usize),
+    Arg(usize),
+}
+
+// run_until_breakが止まった理由
+#[derive(Clone, Debug, PartialEq)]
+pub enum StopReason {
+    // 次にこのアドレスの命令を実行するところで止まった
+    Breakpoint(usize),
+    // 直前の命令でスロットの値が変わった
+    Watchpoint { slot: Slot, old: Value, new: Value },
+    // エントリ関数から戻った。値は戻り値
+    Halted(Value),
+}
+
+type Condition = Box<dyn Fn(&VmView) -> bool>;
+
+pub struct Debugger {
+    vm: VM,
+    breakpoints: BTreeMap<usize, Option<Condition>>,
+    watchpoints: Vec<Slot>,
+    // 直前のrun_until_breakで実行した命令のpc
+    last_run: Trace,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            last_run: Trace::new(),
+        }
+    }
+
+    pub fn vm(&self) -> &VM {
+        &self.vm
+    }
+
+    pub fn into_vm(self) -> VM {
+        self.vm
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc, None);
+    }
+
+    // conditionが成り立つときだけ止まるブレークポイント
+    pub fn add_conditional_breakpoint(
+        &mut self,
+        pc: usize,
+        condition: impl Fn(&VmView) -> bool + 'static,
+    ) {
+        self.breakpoints.insert(pc, Some(Box::new(condition)));
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    pub fn add_watchpoint(&mut self, slot: Slot) {
+        self.watchpoints.push(slot);
+    }
+
+    pub fn remove_watchpoint(&mut self, slot: Slot) {
+        self.watchpoints.retain(|x| *x != slot);
+    }
+
+    pub fn last_run(&self) -> &Trace {
+        &self.last_run
+    }
+
+    // 直前のrun_until_breakで回ったループを "loop at 4..12 executed 3 times" のように1行ずつ
+    pub fn summary(&self) -> String {
+        self.last_run.report()
+    }
+
+    fn read(&self, slot: Slot) -> Option<Value> {
+        match slot {
+            Slot::Local(i) => self.vm.local(i),
+            Slot::Arg(i) => self.vm.arg(i),
+        }
+    }
+
+    // 少なくとも一命令は実行してから、ブレークポイントかウォッチポイントに当たるか実行を終えるまで進める
+    pub fn run_until_break(&mut self) -> Result<StopReason, VmError> {
+        self.last_run = Trace::new();
+        loop {
+            let fp = self.vm.view().fp;
+            self.last_run.record(self.vm.pc());
+            let before = self
+                .watchpoints
+                .iter()
+                .map(|slot| self.read(*slot))
+                .collect::<Vec<_>>();
+            // 一命令ごとにスタックを写さないよう、実行後の状態はviewで見る
+            self.vm.step_cmd()?;
+            let state = self.vm.view();
+            if self.vm.is_halted() {
+                return Ok(StopReason::Halted(
+                    state.stack.last().cloned().ok_or(VmError::StackUnderflow)?,
+                ));
+            }
+            // 呼び出しや戻りでフレームが変わったときは比べない
+            if state.fp == fp {
+                for (slot, old) in self.watchpoints.iter().zip(before) {
+                    if let (Some(old), Some(new)) = (old, self.read(*slot)) {
+                        if old != new {
+                            return Ok(StopReason::Watchpoint {
+                                slot: *slot,
+                                old,
+                                new,
+                            });
+                        }
+                    }
+                }
+            }
+            match self.breakpoints.get(&state.pc) {
+                Some(None) => return Ok(StopReason::Breakpoint(state.pc)),
+                Some(Some(condition)) if condition(&state) => {
+                    return Ok(StopReason::Breakpoint(state.pc))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::vm::Cmd;
+
+    // 0..3のループでローカル変数0を数え上げる
+    let program = vec![
+        Cmd::Entry(1),      // 0
+        Cmd::Frame(1, 2),   // 1
+        Cmd::Const(0),      // 2
+        Cmd::LocalStore(0), // 3
+        Cmd::Const(3),      // 4
+        Cmd::LocalLoad(0),  // 5
+        Cmd::Eq,            // 6
+        Cmd::JumpIf(13),    // 7
+        Cmd::Const(1),      // 8
+        Cmd::LocalLoad(0),  // 9
+        Cmd::Add,           // 10
+        Cmd::LocalStore(0), // 11
+        Cmd::Jump(4),       // 12
+        Cmd::LocalLoad(0),  // 13
+        Cmd::Ret,           // 14
+    ];
+
+    let mut debugger = Debugger::new(VM::new(program.clone()));
+    debugger.add_breakpoint(8);
+    assert_eq!(debugger.run_until_break(), Ok(StopReason::Breakpoint(8)));
+    assert_eq!(debugger.vm().local(0), Some(0));
+    assert_eq!(debugger.run_until_break(), Ok(StopReason::Breakpoint(8)));
+    assert_eq!(debugger.vm().local(0), Some(1));
+    debugger.remove_breakpoint(8);
+    assert_eq!(debugger.run_until_break(), Ok(StopReason::Halted(3)));
+    assert_eq!(debugger.last_run().pcs().next(), Some(8));
+
+    // 止まらずに最後まで実行すると、3周したループがまとめて報告される
+    let mut debugger = Debugger::new(VM::new(program.clone()));
+    assert_eq!(debugger.run_until_break(), Ok(StopReason::Halted(3)));
+    assert_eq!(debugger.summary(), "loop at 4..12 executed 3 times\n");
+
+    let mut debugger = Debugger::new(VM::new(program.clone()));
+    debugger.add_conditional_breakpoint(8, |state| state.stack[2] == 2);
+    assert_eq!(debugger.run_until_break(), Ok(StopReason::Breakpoint(8)));
+    assert_eq!(debugger.vm().local(0), Some(2));
+
+    let mut debugger = Debugger::new(VM::new(program));
+    debugger.add_watchpoint(Slot::Local(0));
+    // 0を書いたときは値が変わらない
+    assert_eq!(
+        debugger.run_until_break(),
+        Ok(StopReason::Watchpoint {
+            slot: Slot::Local(0),
+            old: 0,
+            new: 1
+        })
+    );
+    assert_eq!(debugger.vm().pc(), 12);
+}
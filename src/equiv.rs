@@ -0,0 +1,159 @@
+use crate::llang::{Func, LLang, Op};
+use crate::symexec::{explore, End};
+use crate::vm::{Value, VmError, VM};
+
+const CORNER_CASES: [Value; 6] = [0, 1, 2, 3, 7, 64];
+
+// 記号実行で経路を探すときの最大命令数
+const SYMBOLIC_STEPS: usize = 1000;
+
+// 一回の実行で使える命令数
+const FUEL: u64 = 100_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub args: Vec<Value>,
+    pub left: Result<Value, VmError>,
+    pub right: Result<Value, VmError>,
+}
+
+// 2つのプログラムの関数funcに同じ引数を与えて実行し、結果が食い違う引数を探す
+// 各実行はFUEL命令で打ち切る。片方だけ打ち切られたら食い違いとし、両方打ち切られた引数は比べない
+pub fn check_equivalent(
+    left: &LLang,
+    right: &LLang,
+    func: usize,
+    arity: usize,
+    random_cases: usize,
+) -> Result<(), Divergence> {
+    let mut cases = Vec::new();
+    for &x in &CORNER_CASES {
+        cases.push(vec![x; arity]);
+        for i in 0..arity {
+            let mut args = vec![0; arity];
+            args[i] = x;
+            cases.push(args);
+        }
+    }
+    let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+    for _ in 0..random_cases {
+        cases.push((0..arity).map(|_| (rng.next() % 256) as Value).collect());
+    }
+    // 特定の値でだけ通る分岐も試すため、両方の関数を記号実行して各経路を通る引数も加える。
+    // 命令数の上限で打ち切った経路は停止するとは限らないので使わない
+    for llang in &[left, right] {
+        for path in explore(llang, func, arity, SYMBOLIC_STEPS) {
+            if path.end != End::Cut && !cases.contains(&path.args) {
+                cases.push(path.args);
+            }
+        }
+    }
+
+    for args in cases {
+        let l = call(left, func, &args);
+        let r = call(right, func, &args);
+        if l == Err(VmError::OutOfFuel) && r == Err(VmError::OutOfFuel) {
+            continue;
+        }
+        if l != r {
+            return Err(Divergence {
+                args,
+                left: l,
+                right: r,
+            });
+        }
+    }
+    Ok(())
+}
+
+// argsを引数にfuncを呼ぶエントリ関数を足して実行する。args[i]はArgLoad(i)で読める
+fn call(llang: &LLang, func: usize, args: &[Value]) -> Result<Value, VmError> {
+    VM::new(with_entry(llang, func, args).convert()).run_with_fuel(FUEL)
+}
+
+// argsを引数にfuncを呼ぶエントリ関数を足したプログラム
+pub(crate) fn with_entry(llang: &LLang, func: usize, args: &[Value]) -> LLang {
+    let mut llang = llang.clone();
+    let entry = llang.funcs.iter().map(|f| f.id + 1).max().unwrap_or(0);
+    let mut ops = args.iter().rev().map(|x| Op::Const(*x)).collect::<Vec<_>>();
+    ops.push(Op::Call(func));
+    ops.push(Op::PopR(args.len() + 2));
+    llang.funcs.push(Func {
+        id: entry,
+        name: None,
+        local_count: 0,
+        ops,
+    });
+    llang.entry = entry;
+    llang
+}
+
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+#[test]
+fn test() {
+    let program = |ops| LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops,
+        }],
+    };
+
+    let a_plus_b = program(vec![Op::ArgLoad(0), Op::ArgLoad(1), Op::Add]);
+    let b_plus_a = program(vec![Op::ArgLoad(1), Op::ArgLoad(0), Op::Add]);
+    let a_plus_a = program(vec![Op::ArgLoad(0), Op::ArgLoad(0), Op::Add]);
+
+    assert_eq!(check_equivalent(&a_plus_b, &b_plus_a, 0, 2, 100), Ok(()));
+    assert_eq!(
+        check_equivalent(&a_plus_b, &a_plus_a, 0, 2, 100),
+        Err(Divergence {
+            args: vec![1, 0],
+            left: Ok(1),
+            right: Ok(2),
+        })
+    );
+
+    // 停止しない方は打ち切って食い違いとする。両方止まらなければ比べない
+    let spin = program(vec![Op::Jump(0)]);
+    assert_eq!(
+        check_equivalent(&a_plus_b, &spin, 0, 2, 100),
+        Err(Divergence {
+            args: vec![0, 0],
+            left: Ok(0),
+            right: Err(VmError::OutOfFuel),
+        })
+    );
+    assert_eq!(check_equivalent(&spin, &spin, 0, 2, 0), Ok(()));
+
+    // 1000のときだけ食い違うのはランダムな引数では見つからないが、記号実行で見つかる
+    let zero = program(vec![Op::Const(0)]);
+    let is_1000 = program(vec![
+        Op::ArgLoad(0),
+        Op::Const(1000),
+        Op::Eq,
+        Op::JumpIf(6),
+        Op::Const(0),
+        Op::Jump(7),
+        Op::Const(1),
+    ]);
+    assert_eq!(
+        check_equivalent(&zero, &is_1000, 0, 1, 100),
+        Err(Divergence {
+            args: vec![1000],
+            left: Ok(0),
+            right: Ok(1),
+        })
+    );
+}
@@ -0,0 +1,272 @@
+use crate::vm::{Cmd, Tracer, Value, VmView};
+use std::collections::BTreeMap;
+
+// 一命令の実行を、実行前後の状態から一文で説明する
+// labelsはアドレスから名前へ。アセンブラのSymbols::labelsをそのまま渡せる
+pub fn explain(
+    cmd: &Cmd,
+    before: &VmView,
+    after: &VmView,
+    labels: &BTreeMap<usize, String>,
+) -> String {
+    // 実行前のスタックの上からi番目
+    let top = |i: usize| -> Value {
+        before
+            .stack
+            .len()
+            .checked_sub(i + 1)
+            .map(|i| before.stack[i])
+            .unwrap_or(0)
+    };
+    let result = after.stack.last().cloned().unwrap_or(0);
+    let func = |addr: usize| match labels.get(&addr) {
+        Some(name) => format!("function {}", name),
+        None => format!("function at {}", addr),
+    };
+    let target = |addr: usize| match labels.get(&addr) {
+        Some(name) => format!("{} ({})", name, addr),
+        None => addr.to_string(),
+    };
+    let binary = |op: &str| {
+        format!(
+            "pop {} and {}, push {} {} {} = {}",
+            top(0),
+            top(1),
+            top(0),
+            op,
+            top(1),
+            result
+        )
+    };
+
+    let text = match cmd.absolute(before.pc) {
+        Cmd::Frame(local_count, _) => format!(
+            "save frame pointer {}, reserve {} local(s)",
+            before.fp, local_count
+        ),
+        Cmd::Ret if after.pc == 0 => format!("return {} from the entry function and halt", top(0)),
+        Cmd::Ret => format!(
+            "return {} to address {}, restore frame pointer {}",
+            top(0),
+            after.pc,
+            after.fp
+        ),
+        Cmd::Entry(i) => format!("push return address 0, jump to {}", func(i)),
+        Cmd::Call(i) => format!(
+            "push return address {}, jump to {}",
+            before.pc + 1,
+            func(i)
+        ),
+        Cmd::CallIndirect => format!(
+            "pop function address {}, push return address {}, jump to {}",
+            top(0),
+            before.pc + 1,
+            func(after.pc)
+        ),
+        Cmd::TailCall(i, argc) => format!(
+            "replace the current frame with {} argument(s), jump to {} which returns to the current caller",
+            argc,
+            func(i)
+        ),
+        Cmd::FuncRef(i) => format!("push the address {} of {}", i, func(i)),
+        Cmd::MakeClosure(i, n) => format!(
+            "pop {} captured value(s), push a closure of {} at {}",
+            n,
+            func(i),
+            result
+        ),
+        Cmd::CallClosure => format!(
+            "call closure {}, push return address {}, jump to {}",
+            top(0),
+            before.pc + 1,
+            func(after.pc)
+        ),
+        Cmd::CaptureLoad(i) => format!("push captured value {} ({})", i, result),
+        Cmd::LocalLoad(i) => format!("push local {} ({})", i, result),
+        Cmd::LocalStore(i) => format!("pop {} into local {}", top(0), i),
+        Cmd::ArgLoad(i) => format!("push argument {} ({})", i, result),
+        Cmd::ArgStore(i) => format!("pop {} into argument {}", top(0), i),
+        Cmd::PopR(n) => format!(
+            "keep the top value {} and drop {} value(s) below it",
+            top(0),
+            n.saturating_sub(1)
+        ),
+        Cmd::Const(x) => format!("push {}", x),
+        Cmd::ConstLoad(i) => format!("push {} from the constant pool ({})", result, i),
+        Cmd::Add => binary("+"),
+        Cmd::Sub => binary("-"),
+        Cmd::Mul => binary("*"),
+        Cmd::Div => binary("/"),
+        Cmd::Mod => binary("%"),
+        Cmd::Eq => binary("=="),
+        Cmd::Ne => binary("!="),
+        Cmd::Lt => binary("<"),
+        Cmd::Le => binary("<="),
+        Cmd::Gt => binary(">"),
+        Cmd::Ge => binary(">="),
+        Cmd::And => binary("&"),
+        Cmd::Or => binary("|"),
+        Cmd::Xor => binary("^"),
+        Cmd::Shl => binary("<<"),
+        Cmd::Shr => binary(">>"),
+        Cmd::Not => format!("pop {}, push {} (logical not)", top(0), result),
+        Cmd::JumpIf(i) if top(0) != 0 => {
+            format!("pop {}, which is not 0, so jump to {}", top(0), target(i))
+        }
+        Cmd::JumpIf(_) => format!("pop 0, so go on to {}", after.pc),
+        Cmd::Jump(i) => format!("jump to {}", target(i)),
+        Cmd::Alloc(n) => format!(
+            "allocate {} cell(s) on the heap, push its address {}",
+            n, result
+        ),
+        Cmd::HeapLoad => format!(
+            "pop address {} and offset {}, push the heap value {}",
+            top(0),
+            top(1),
+            result
+        ),
+        Cmd::HeapStore => format!(
+            "pop address {}, offset {} and value {}, store the value on the heap",
+            top(0),
+            top(1),
+            top(2)
+        ),
+        Cmd::StrConst(i) => format!(
+            "copy string constant {} to the heap, push its address {}",
+            i, result
+        ),
+        Cmd::StrConcat => format!(
+            "pop strings at {} and {}, push their concatenation at {}",
+            top(0),
+            top(1),
+            result
+        ),
+        Cmd::StrLen => format!("pop string at {}, push its length {}", top(0), result),
+        Cmd::StrEq => format!(
+            "pop strings at {} and {}, push {} (1 if equal)",
+            top(0),
+            top(1),
+            result
+        ),
+        Cmd::NativeCall(i) => format!("call host function {}, push its result {}", i, result),
+        Cmd::Print => format!("pop {} and print it", top(0)),
+        Cmd::ReadInt => format!("read {} from the input and push it", result),
+        Cmd::Dup => format!("push another copy of {}", top(0)),
+        Cmd::Swap => format!("swap {} and {}", top(0), top(1)),
+        Cmd::Pop => format!("discard {}", top(0)),
+        Cmd::JumpRel(_) | Cmd::JumpIfRel(_) | Cmd::CallRel(_) => unreachable!(),
+    };
+    // Call(7) を Call 7 のように書く
+    let name = format!("{:?}", cmd)
+        .replace('(', " ")
+        .replace([',', ')'], "");
+    format!("{}: {}", name, text)
+}
+
+// 実行前の状態。Tracerには実行前と実行後で別々に渡されるので取っておく
+struct Before {
+    pc: usize,
+    fp: usize,
+    stack: Vec<Value>,
+}
+
+// 命令を実行するたびに、そのpcと説明をsinkに渡す
+pub struct ExplainTracer {
+    labels: BTreeMap<usize, String>,
+    before: Option<Before>,
+    sink: Box<dyn FnMut(usize, String)>,
+}
+
+impl ExplainTracer {
+    pub fn new(
+        labels: BTreeMap<usize, String>,
+        sink: impl FnMut(usize, String) + 'static,
+    ) -> ExplainTracer {
+        ExplainTracer {
+            labels,
+            before: None,
+            sink: Box::new(sink),
+        }
+    }
+}
+
+impl Tracer for ExplainTracer {
+    fn on_before_cmd(&mut self, view: &VmView, _cmd: &Cmd) {
+        self.before = Some(Before {
+            pc: view.pc,
+            fp: view.fp,
+            stack: view.stack.to_vec(),
+        });
+    }
+
+    fn on_after_cmd(&mut self, view: &VmView, cmd: &Cmd) {
+        if let Some(before) = self.before.take() {
+            let before_view = VmView {
+                pc: before.pc,
+                fp: before.fp,
+                sp: before.stack.len(),
+                stack: &before.stack,
+            };
+            let text = explain(cmd, &before_view, view, &self.labels);
+            (self.sink)(before.pc, text);
+        }
+    }
+}
+
+#[test]
+fn test() {
+    use crate::vm::VM;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(4),
+        Cmd::Const(3),
+        Cmd::Call(10),
+        Cmd::PopR(4),
+        Cmd::Dup,
+        Cmd::JumpIfRel(2),
+        Cmd::Ret,
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(1),
+        Cmd::ArgLoad(0),
+        Cmd::Sub,
+        Cmd::Ret,
+    ];
+    let labels = vec![
+        (1, "main".to_string()),
+        (9, "done".to_string()),
+        (10, "sub".to_string()),
+    ]
+    .into_iter()
+    .collect();
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let sink = lines.clone();
+    let mut vm = VM::new(program);
+    vm.set_tracer(Box::new(ExplainTracer::new(labels, move |pc, text| {
+        sink.borrow_mut().push(format!("{} {}", pc, text))
+    })));
+    assert_eq!(vm.run(), Ok(-1));
+    assert_eq!(
+        *lines.borrow(),
+        vec![
+            "0 Entry 1: push return address 0, jump to function main",
+            "1 Frame 0 4: save frame pointer 0, reserve 0 local(s)",
+            "2 Const 4: push 4",
+            "3 Const 3: push 3",
+            "4 Call 10: push return address 5, jump to function sub",
+            "10 Frame 0 2: save frame pointer 1, reserve 0 local(s)",
+            "11 ArgLoad 1: push argument 1 (4)",
+            "12 ArgLoad 0: push argument 0 (3)",
+            "13 Sub: pop 3 and 4, push 3 - 4 = -1",
+            "14 Ret: return -1 to address 5, restore frame pointer 1",
+            "5 PopR 4: keep the top value -1 and drop 3 value(s) below it",
+            "6 Dup: push another copy of -1",
+            "7 JumpIfRel 2: pop -1, which is not 0, so jump to done (9)",
+            "9 Ret: return -1 from the entry function and halt",
+        ]
+    );
+}
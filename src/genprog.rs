@@ -0,0 +1,177 @@
+use crate::llang::{Func, LLang, Op};
+use crate::vm::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Workload {
+    CallHeavy,
+    BranchHeavy,
+    ArithmeticHeavy,
+    DeepRecursion,
+}
+
+pub const ALL_WORKLOADS: [Workload; 4] = [
+    Workload::CallHeavy,
+    Workload::BranchHeavy,
+    Workload::ArithmeticHeavy,
+    Workload::DeepRecursion,
+];
+
+const MODULUS: Value = 65521;
+
+// nは反復回数(DeepRecursionでは再帰の深さ)
+pub fn generate(workload: Workload, n: usize) -> LLang {
+    match workload {
+        Workload::CallHeavy => LLang {
+            entry: 0,
+            funcs: vec![
+                counted_loop(n, |_| {
+                    vec![
+                        Op::LocalLoad(1),
+                        Op::LocalLoad(0),
+                        Op::Call(1),
+                        Op::PopR(4),
+                        Op::LocalStore(1),
+                    ]
+                }),
+                Func {
+                    id: 1,
+                    name: None,
+                    local_count: 0,
+                    ops: vec![Op::ArgLoad(0), Op::ArgLoad(1), Op::Add],
+                },
+            ],
+        },
+        Workload::BranchHeavy => LLang {
+            entry: 0,
+            funcs: vec![counted_loop(n, |s| {
+                vec![
+                    Op::Const(3),
+                    Op::LocalLoad(0),
+                    Op::Mod,
+                    Op::Const(0),
+                    Op::Eq,
+                    Op::JumpIf(s + 10),
+                    Op::LocalLoad(1),
+                    Op::Const(2),
+                    Op::Add,
+                    Op::Jump(s + 13),
+                    Op::LocalLoad(1),
+                    Op::Const(1),
+                    Op::Add,
+                    Op::LocalStore(1),
+                ]
+            })],
+        },
+        Workload::ArithmeticHeavy => LLang {
+            entry: 0,
+            funcs: vec![counted_loop(n, |_| {
+                vec![
+                    Op::Const(MODULUS),
+                    Op::LocalLoad(1),
+                    Op::LocalLoad(0),
+                    Op::Add,
+                    Op::Const(7),
+                    Op::Add,
+                    Op::Mod,
+                    Op::LocalStore(1),
+                ]
+            })],
+        },
+        Workload::DeepRecursion => LLang {
+            entry: 0,
+            funcs: vec![
+                Func {
+                    id: 0,
+                    name: None,
+                    local_count: 0,
+                    ops: vec![Op::Const(0), Op::Call(1), Op::PopR(3)],
+                },
+                // f(k) = if k == n { 0 } else { f(k + 1) + 1 }
+                Func {
+                    id: 1,
+                    name: None,
+                    local_count: 0,
+                    ops: vec![
+                        Op::ArgLoad(0),
+                        Op::Const(n as Value),
+                        Op::Eq,
+                        Op::JumpIf(12),
+                        Op::ArgLoad(0),
+                        Op::Const(1),
+                        Op::Add,
+                        Op::Call(1),
+                        Op::PopR(3),
+                        Op::Const(1),
+                        Op::Add,
+                        Op::Jump(13),
+                        Op::Const(0),
+                    ],
+                },
+            ],
+        },
+    }
+}
+
+// generateしたプログラムの実行結果
+pub fn expected(workload: Workload, n: usize) -> Value {
+    let n = n as Value;
+    match workload {
+        Workload::CallHeavy => (0..n).sum(),
+        Workload::BranchHeavy => (0..n).map(|i| if i % 3 == 0 { 1 } else { 2 }).sum(),
+        Workload::ArithmeticHeavy => (0..n).fold(0, |acc, i| (acc + i + 7) % MODULUS),
+        Workload::DeepRecursion => n,
+    }
+}
+
+// ローカル変数0をカウンタ、1をアキュムレータとしてbodyをn回繰り返し、アキュムレータを返す関数
+// bodyにはbodyの先頭のインデックスが渡される
+fn counted_loop(n: usize, body: impl FnOnce(usize) -> Vec<Op>) -> Func {
+    let mut ops = vec![
+        Op::Const(0),
+        Op::LocalStore(0),
+        Op::Const(0),
+        Op::LocalStore(1),
+        Op::LocalLoad(0),
+        Op::Const(n as Value),
+        Op::Eq,
+    ];
+    let body = body(ops.len() + 1);
+    let end = ops.len() + 1 + body.len() + 5;
+    ops.push(Op::JumpIf(end));
+    ops.extend(body);
+    ops.extend(vec![
+        Op::LocalLoad(0),
+        Op::Const(1),
+        Op::Add,
+        Op::LocalStore(0),
+        Op::Jump(4),
+        Op::LocalLoad(1),
+    ]);
+    Func {
+        id: 0,
+        name: None,
+        local_count: 2,
+        ops,
+    }
+}
+
+#[test]
+#[cfg(feature = "tools")]
+fn test() {
+    use crate::verifier::verify;
+    use crate::vm::VM;
+
+    for &workload in &ALL_WORKLOADS {
+        for &n in &[0, 1, 10, 50] {
+            let program = generate(workload, n).convert();
+            assert_eq!(verify(&program), vec![]);
+            assert_eq!(
+                VM::new(program).run(),
+                Ok(expected(workload, n)),
+                "{:?} {}",
+                workload,
+                n
+            );
+        }
+    }
+}
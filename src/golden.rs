@@ -0,0 +1,233 @@
+use crate::vm::Value;
+use std::fmt;
+
+// 一命令の実行記録。スタックのうち実行前後で変わらなかった底の部分を除き、取り除かれた値と積まれた値を持つ
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub pc: usize,
+    pub popped: Vec<Value>,
+    pub pushed: Vec<Value>,
+}
+
+impl Step {
+    pub fn new(pc: usize, before: &[Value], after: &[Value]) -> Step {
+        let common = before.iter().zip(after).take_while(|(x, y)| x == y).count();
+        Step {
+            pc,
+            popped: before[common..].to_vec(),
+            pushed: after[common..].to_vec(),
+        }
+    }
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let values = |xs: &[Value]| {
+            xs.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        write!(
+            f,
+            "{}: [{}] -> [{}]",
+            self.pc,
+            values(&self.popped),
+            values(&self.pushed)
+        )
+    }
+}
+
+// 比べる対象
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    // pcの列だけ
+    Pcs,
+    // pcと、取り除かれた値と積まれた値
+    PcsAndValues,
+}
+
+impl Projection {
+    // 記録するときに値を持つか
+    fn values(self) -> bool {
+        self == Projection::PcsAndValues
+    }
+
+    fn same(self, x: &Step, y: &Step) -> bool {
+        match self {
+            Projection::Pcs => x.pc == y.pc,
+            Projection::PcsAndValues => x == y,
+        }
+    }
+}
+
+// 最初に食い違った命令と、その直前の数命令
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    // 何命令目で食い違ったか
+    pub index: usize,
+    // Noneならそのトレースはそこで終わっている
+    pub expected: Option<Step>,
+    pub actual: Option<Step>,
+    // 食い違う直前の、両方で一致した命令
+    pub context: Vec<Step>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let step = |step: &Option<Step>| match step {
+            Some(step) => step.to_string(),
+            None => "end of trace".to_string(),
+        };
+        writeln!(f, "trace diverged at step {}", self.index)?;
+        for (i, s) in self.context.iter().enumerate() {
+            writeln!(f, "  #{} {}", self.index - self.context.len() + i, s)?;
+        }
+        writeln!(f, "  expected: {}", step(&self.expected))?;
+        writeln!(f, "  actual:   {}", step(&self.actual))
+    }
+}
+
+// 実行中の記録。値を記録しないときはスタックを写さない。写すときも前回の領域を使い回す
+#[derive(Clone, Debug)]
+pub(crate) struct Recorder {
+    pub(crate) trace: GoldenTrace,
+    projection: Projection,
+    before: Vec<Value>,
+}
+
+impl Recorder {
+    pub(crate) fn new(projection: Projection) -> Recorder {
+        Recorder {
+            trace: GoldenTrace::new(),
+            projection,
+            before: Vec::new(),
+        }
+    }
+
+    // 命令を実行する前に呼ぶ
+    pub(crate) fn before(&mut self, stack: &[Value]) {
+        if self.projection.values() {
+            self.before.clear();
+            self.before.extend_from_slice(stack);
+        }
+    }
+
+    // 命令を実行できたときに呼ぶ
+    pub(crate) fn after(&mut self, pc: usize, stack: &[Value]) {
+        let step = if self.projection.values() {
+            Step::new(pc, &self.before, stack)
+        } else {
+            Step {
+                pc,
+                popped: Vec::new(),
+                pushed: Vec::new(),
+            }
+        };
+        self.trace.steps.push(step);
+    }
+}
+
+// Divergenceに含める直前の命令数
+const CONTEXT: usize = 3;
+
+// 参照用に保存しておく実行記録
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GoldenTrace {
+    pub steps: Vec<Step>,
+}
+
+impl GoldenTrace {
+    pub fn new() -> GoldenTrace {
+        GoldenTrace::default()
+    }
+
+    // selfを期待する記録としてactualと比べる。一致すればNone
+    pub fn compare(&self, actual: &GoldenTrace, projection: Projection) -> Option<Divergence> {
+        let index = (0..)
+            .find(|&i| match (self.steps.get(i), actual.steps.get(i)) {
+                (Some(x), Some(y)) => !projection.same(x, y),
+                _ => true,
+            })
+            .unwrap();
+        if index == self.steps.len() && index == actual.steps.len() {
+            return None;
+        }
+        Some(Divergence {
+            index,
+            expected: self.steps.get(index).cloned(),
+            actual: actual.steps.get(index).cloned(),
+            context: actual.steps[index.saturating_sub(CONTEXT)..index].to_vec(),
+        })
+    }
+
+    // 一行に一命令ずつ、Stepの表示形式で書く
+    pub fn to_text(&self) -> String {
+        self.steps.iter().map(|s| format!("{}\n", s)).collect()
+    }
+
+    pub fn from_text(text: &str) -> Option<GoldenTrace> {
+        let values = |s: &str| -> Option<Vec<Value>> {
+            let s = s.strip_prefix('[')?.strip_suffix(']')?;
+            s.split_whitespace().map(|x| x.parse().ok()).collect()
+        };
+        let mut steps = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let (pc, rest) = line.trim().split_once(": ")?;
+            let (popped, pushed) = rest.split_once(" -> ")?;
+            steps.push(Step {
+                pc: pc.parse().ok()?,
+                popped: values(popped)?,
+                pushed: values(pushed)?,
+            });
+        }
+        Some(GoldenTrace { steps })
+    }
+}
+
+#[test]
+fn test() {
+    let step = |pc, popped: &[Value], pushed: &[Value]| Step {
+        pc,
+        popped: popped.to_vec(),
+        pushed: pushed.to_vec(),
+    };
+    assert_eq!(Step::new(3, &[1, 2, 3], &[1, 5]), step(3, &[2, 3], &[5]));
+    assert_eq!(Step::new(3, &[1], &[1, 2]), step(3, &[], &[2]));
+
+    let expected = GoldenTrace {
+        steps: vec![
+            step(0, &[], &[0]),
+            step(1, &[], &[1]),
+            step(2, &[], &[2]),
+            step(3, &[1, 2], &[3]),
+            step(4, &[3], &[]),
+        ],
+    };
+    let text = expected.to_text();
+    assert_eq!(text.lines().nth(3), Some("3: [1 2] -> [3]"));
+    assert_eq!(GoldenTrace::from_text(&text), Some(expected.clone()));
+    assert_eq!(GoldenTrace::from_text("3: [1 x] -> []"), None);
+    assert_eq!(expected.compare(&expected, Projection::PcsAndValues), None);
+
+    let mut actual = expected.clone();
+    actual.steps[3].pushed = vec![-1];
+    assert_eq!(expected.compare(&actual, Projection::Pcs), None);
+    let divergence = expected.compare(&actual, Projection::PcsAndValues).unwrap();
+    assert_eq!(divergence.index, 3);
+    assert_eq!(divergence.context, expected.steps[..3].to_vec());
+    assert_eq!(
+        divergence.to_string(),
+        "trace diverged at step 3
+  #0 0: [] -> [0]
+  #1 1: [] -> [1]
+  #2 2: [] -> [2]
+  expected: 3: [1 2] -> [3]
+  actual:   3: [1 2] -> [-1]
+"
+    );
+
+    actual.steps.truncate(1);
+    let divergence = expected.compare(&actual, Projection::Pcs).unwrap();
+    assert_eq!((divergence.index, divergence.actual), (1, None));
+}
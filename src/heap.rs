@@ -0,0 +1,326 @@
+use crate::vm::{Value, VmError};
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+
+// Allocで確保したブロックを一つのVec<Value>に並べたヒープ
+// アドレス0はどのブロックも指さない
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heap {
+    cells: Vec<Value>,
+    // ブロックの先頭アドレスと長さ
+    blocks: BTreeMap<usize, usize>,
+    // 回収した領域の先頭アドレスと長さ。隣り合う領域はまとめておく
+    free: BTreeMap<usize, usize>,
+    // 確保中のセル数
+    live: usize,
+    stats: HeapStats,
+}
+
+// clearするまでの確保の累計。バイト数はセル数 * 8
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HeapStats {
+    // Allocで確保したブロック数
+    pub blocks: usize,
+    // 文字列として確保したブロック数
+    pub strings: usize,
+    // クロージャとして確保したブロック数
+    pub closures: usize,
+    // 確保したバイト数の合計
+    pub allocated_bytes: usize,
+    // 同時に確保されていたバイト数の最大
+    pub high_water_bytes: usize,
+}
+
+pub const CELL_BYTES: usize = mem::size_of::<Value>();
+
+// 上限を指定していなくても、確保中のセル数がこれを超える確保は断る
+pub const MAX_CELLS: usize = 1 << 24;
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap {
+            cells: vec![0],
+            blocks: BTreeMap::new(),
+            free: BTreeMap::new(),
+            live: 0,
+            stats: HeapStats::default(),
+        }
+    }
+}
+
+// 長さ0のブロックも他のブロックと先頭アドレスが重ならないよう1セル使う
+pub fn footprint(len: usize) -> usize {
+    len.max(1)
+}
+
+impl Heap {
+    pub fn new() -> Heap {
+        Heap::default()
+    }
+
+    // 0で初期化したsize個のセルを確保し、先頭アドレスを返す。MAX_CELLSを超えるならNone
+    pub fn alloc(&mut self, size: usize) -> Option<Value> {
+        let addr = self.allocate(size)?;
+        self.stats.blocks += 1;
+        Some(addr)
+    }
+
+    fn allocate(&mut self, size: usize) -> Option<Value> {
+        let need = footprint(size);
+        let live = self
+            .live
+            .checked_add(need)
+            .filter(|&live| live <= MAX_CELLS)?;
+        self.live = live;
+        self.stats.allocated_bytes += need * CELL_BYTES;
+        self.stats.high_water_bytes = self.stats.high_water_bytes.max(self.live * CELL_BYTES);
+        let found = self
+            .free
+            .iter()
+            .find(|(_, len)| **len >= need)
+            .map(|(addr, len)| (*addr, *len));
+        let addr = match found {
+            Some((addr, len)) => {
+                self.free.remove(&addr);
+                if len > need {
+                    self.free.insert(addr + need, len - need);
+                }
+                for x in &mut self.cells[addr..addr + need] {
+                    *x = 0;
+                }
+                addr
+            }
+            None => {
+                let addr = self.cells.len();
+                self.cells.resize(addr + need, 0);
+                addr
+            }
+        };
+        self.blocks.insert(addr, size);
+        Some(addr as Value)
+    }
+
+    // addrはブロックの先頭でなければならない
+    fn index(&self, addr: Value, offset: Value) -> Result<usize, VmError> {
+        let len = if addr >= 0 && offset >= 0 {
+            self.blocks.get(&(addr as usize))
+        } else {
+            None
+        };
+        match len {
+            Some(&len) if (offset as usize) < len => Ok(addr as usize + offset as usize),
+            _ => Err(VmError::InvalidHeapAccess { addr, offset }),
+        }
+    }
+
+    pub fn load(&self, addr: Value, offset: Value) -> Result<Value, VmError> {
+        self.index(addr, offset).map(|i| self.cells[i])
+    }
+
+    pub fn store(&mut self, addr: Value, offset: Value, x: Value) -> Result<(), VmError> {
+        let i = self.index(addr, offset)?;
+        self.cells[i] = x;
+        Ok(())
+    }
+
+    // 確保したブロックの先頭アドレスと長さ
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.blocks.iter().map(|(addr, len)| (*addr, *len))
+    }
+
+    // 文字列は長さのセルに続けて1バイトずつセルに並べたブロック
+    pub fn alloc_bytes(&mut self, bytes: &[u8]) -> Option<Value> {
+        let addr = self.allocate(bytes.len().checked_add(1)?)?;
+        self.stats.strings += 1;
+        let start = addr as usize;
+        self.cells[start] = bytes.len() as Value;
+        for (x, b) in self.cells[start + 1..].iter_mut().zip(bytes) {
+            *x = *b as Value;
+        }
+        Some(addr)
+    }
+
+    // クロージャは関数の先頭アドレスのセルに続けて捕捉した値を並べたブロック
+    pub fn alloc_closure(&mut self, func: Value, captures: &[Value]) -> Option<Value> {
+        let addr = self.allocate(captures.len().checked_add(1)?)?;
+        self.stats.closures += 1;
+        let start = addr as usize;
+        self.cells[start] = func;
+        self.cells[start + 1..start + 1 + captures.len()].copy_from_slice(captures);
+        Some(addr)
+    }
+
+    pub fn load_bytes(&self, addr: Value) -> Result<Vec<u8>, VmError> {
+        let len = self.load(addr, 0)?;
+        let block_len = self.blocks.get(&(addr as usize)).cloned().unwrap_or(0);
+        if len < 0 || len as usize + 1 != block_len {
+            return Err(VmError::InvalidHeapAccess { addr, offset: 0 });
+        }
+        let start = addr as usize + 1;
+        Ok(self.cells[start..start + len as usize]
+            .iter()
+            .map(|x| *x as u8)
+            .collect())
+    }
+
+    // 確保中のセル数
+    pub fn live_cells(&self) -> usize {
+        self.live
+    }
+
+    pub fn stats(&self) -> &HeapStats {
+        &self.stats
+    }
+
+    // rootsから辿れないブロックを回収し、回収したブロック数を返す
+    // 値が整数かアドレスかは区別できないので、ブロックの先頭と一致する値はすべて参照とみなす
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) -> usize {
+        let mut marked = BTreeSet::new();
+        let mut work = roots.into_iter().collect::<Vec<_>>();
+        while let Some(x) = work.pop() {
+            if x <= 0 {
+                continue;
+            }
+            let addr = x as usize;
+            if let Some(&len) = self.blocks.get(&addr) {
+                if marked.insert(addr) {
+                    work.extend_from_slice(&self.cells[addr..addr + len]);
+                }
+            }
+        }
+
+        let garbage = self
+            .blocks
+            .iter()
+            .filter(|(addr, _)| !marked.contains(addr))
+            .map(|(addr, len)| (*addr, *len))
+            .collect::<Vec<_>>();
+        for &(addr, len) in &garbage {
+            self.blocks.remove(&addr);
+            self.live -= footprint(len);
+            self.release(addr, footprint(len));
+        }
+        garbage.len()
+    }
+
+    fn release(&mut self, mut addr: usize, mut len: usize) {
+        if let Some((&prev, &prev_len)) = self.free.range(..addr).next_back() {
+            if prev + prev_len == addr {
+                self.free.remove(&prev);
+                addr = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(addr + len)) {
+            len += next_len;
+        }
+        self.free.insert(addr, len);
+    }
+
+    pub fn clear(&mut self) {
+        *self = Heap::new();
+    }
+}
+
+#[test]
+fn test() {
+    let mut heap = Heap::new();
+    let a = heap.alloc(2).unwrap();
+    let b = heap.alloc(3).unwrap();
+    assert_eq!((a, b), (1, 3));
+    heap.store(b, 2, 7).unwrap();
+    assert_eq!(heap.load(b, 2), Ok(7));
+    assert_eq!(heap.load(a, 1), Ok(0));
+    assert_eq!(
+        heap.load(a, 2),
+        Err(VmError::InvalidHeapAccess { addr: a, offset: 2 })
+    );
+    assert_eq!(
+        heap.load(0, 0),
+        Err(VmError::InvalidHeapAccess { addr: 0, offset: 0 })
+    );
+    assert_eq!(
+        heap.store(b + 1, 0, 1),
+        Err(VmError::InvalidHeapAccess {
+            addr: b + 1,
+            offset: 0
+        })
+    );
+    assert_eq!(
+        heap.load(a, -1),
+        Err(VmError::InvalidHeapAccess {
+            addr: a,
+            offset: -1
+        })
+    );
+    assert_eq!(heap.blocks().collect::<Vec<_>>(), vec![(1, 2), (3, 3)]);
+
+    assert_eq!(heap.alloc(usize::MAX), None);
+    assert_eq!(heap.alloc(MAX_CELLS), None);
+    assert_eq!(heap.live_cells(), 5);
+    assert_eq!(heap.stats().blocks, 2);
+}
+
+#[test]
+fn test_collect() {
+    let mut heap = Heap::new();
+    let a = heap.alloc(1).unwrap();
+    let b = heap.alloc(2).unwrap();
+    heap.alloc(0).unwrap();
+    let c = heap.alloc(1).unwrap();
+    // a -> c
+    heap.store(a, 0, c).unwrap();
+    assert_eq!(heap.collect(vec![7, a]), 2);
+    assert_eq!(
+        heap.blocks().collect::<Vec<_>>(),
+        vec![(a as usize, 1), (c as usize, 1)]
+    );
+    assert_eq!(heap.live_cells(), 2);
+
+    // bと長さ0のブロックの跡地はまとめて再利用される
+    let e = heap.alloc(3).unwrap();
+    assert_eq!(e, b);
+    assert_eq!(heap.load(e, 1), Ok(0));
+    assert_eq!(heap.alloc(1), Some(6));
+
+    assert_eq!(heap.collect(vec![]), 4);
+    assert_eq!(heap.alloc(6), Some(a));
+    assert_eq!(
+        heap.stats(),
+        &HeapStats {
+            blocks: 7,
+            strings: 0,
+            closures: 0,
+            allocated_bytes: 15 * CELL_BYTES,
+            high_water_bytes: 6 * CELL_BYTES,
+        }
+    );
+}
+
+#[test]
+fn test_bytes() {
+    let mut heap = Heap::new();
+    let a = heap.alloc_bytes(b"abc").unwrap();
+    let b = heap.alloc_bytes(b"").unwrap();
+    assert_eq!(heap.load_bytes(a), Ok(b"abc".to_vec()));
+    assert_eq!(heap.load_bytes(b), Ok(vec![]));
+    assert_eq!(heap.load(a, 1), Ok(b'a' as Value));
+
+    let c = heap.alloc(2).unwrap();
+    assert_eq!(
+        heap.load_bytes(c),
+        Err(VmError::InvalidHeapAccess { addr: c, offset: 0 })
+    );
+}
+
+#[test]
+fn test_closure() {
+    let mut heap = Heap::new();
+    let a = heap.alloc_closure(8, &[3, 4]).unwrap();
+    let b = heap.alloc_closure(9, &[]).unwrap();
+    assert_eq!(heap.blocks().collect::<Vec<_>>(), vec![(1, 3), (4, 1)]);
+    assert_eq!((heap.load(a, 0), heap.load(a, 2)), (Ok(8), Ok(4)));
+    assert_eq!(heap.load(b, 0), Ok(9));
+    let stats = heap.stats();
+    assert_eq!((stats.blocks, stats.strings, stats.closures), (0, 0, 2));
+}
@@ -0,0 +1,47 @@
+// インタプリタ本体(vm, profile, trace, program)は常に使える
+// アセンブラや検査・加工の道具はtools、llangとその周辺はfrontendフィーチャで有効になる
+
+#[cfg(feature = "tools")]
+pub mod asm;
+pub mod billing;
+pub mod debugger;
+#[cfg(feature = "frontend")]
+pub mod equiv;
+pub mod explain;
+#[cfg(feature = "frontend")]
+pub mod genprog;
+pub mod golden;
+pub mod heap;
+#[cfg(feature = "frontend")]
+pub mod llang;
+#[cfg(feature = "frontend")]
+pub mod llang_format;
+pub mod memo;
+#[cfg(feature = "frontend")]
+pub mod module;
+#[cfg(feature = "frontend")]
+pub mod opt;
+#[cfg(feature = "frontend")]
+pub mod pass;
+pub mod profile;
+pub mod program;
+#[cfg(all(feature = "tools", feature = "frontend"))]
+pub mod reduce;
+#[cfg(feature = "frontend")]
+pub mod slice;
+#[cfg(feature = "frontend")]
+pub mod specialize;
+#[cfg(feature = "frontend")]
+pub mod stack_estimate;
+#[cfg(feature = "frontend")]
+pub mod symexec;
+pub mod trace;
+mod varint;
+#[cfg(feature = "tools")]
+pub mod verifier;
+pub mod vm;
+
+#[cfg(feature = "frontend")]
+pub use llang::{Func, LLang, Op};
+pub use program::Program;
+pub use vm::{Cmd, Value, VmConfig, VM};
@@ -1,156 +1,728 @@
-use crate::vm::{Cmd, VM};
+use crate::program::Program;
+use crate::vm::{Cmd, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq)]
 enum LLangCmd {
-    Frame(usize),
+    Frame(usize, usize),
     Ret,
-    Call(FnIndex),
+    Call(FnId),
+    CallName(String),
+    // TailCall(関数, 引数の数)
+    TailCall(FnId, usize),
+    TailCallName(String, usize),
+    FuncRef(FnId),
+    CallIndirect,
+    MakeClosure(FnId, usize),
+    CallClosure,
+    CaptureLoad(usize),
     LocalLoad(usize),
     LocalStore(usize),
     ArgLoad(usize),
     ArgStore(usize),
     PopR(usize),
-    Const(usize),
+    Const(Value),
     Add,
+    Sub,
+    Mul,
+    Div,
     Mod,
-    Entry(FnIndex),
+    Entry(FnId),
     Eq,
-    JumpIf(RelativeFnIndex),
-    Jump(RelativeFnIndex),
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    JumpIf(RelativeFnId),
+    Jump(RelativeFnId),
+    Alloc(usize),
+    HeapLoad,
+    HeapStore,
+    StrConst(usize),
+    StrConcat,
+    StrLen,
+    StrEq,
+    NativeCall(usize),
+    Print,
+    ReadInt,
+    And,
+    Or,
+    Not,
+    Xor,
+    Shl,
+    Shr,
+    Dup,
+    Swap,
+    Pop,
 }
 
 #[derive(Clone, Debug, PartialEq)]
-struct FnIndex(usize);
+struct FnId(usize);
 
 #[derive(Clone, Debug, PartialEq)]
-struct RelativeFnIndex(FnIndex, usize);
+struct RelativeFnId(FnId, usize);
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct LLang {
-    entry: usize,
-    funcs: Vec<Func>,
+    // エントリ関数のID
+    pub entry: usize,
+    pub funcs: Vec<Func>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Func {
-    local_count: usize,
-    ops: Vec<Op>,
+    // Vec内の位置に依存しない関数のID。Op::Callはこれで関数を参照する
+    pub id: usize,
+    // Op::CallNameで参照するための名前。関数を足し引きしても変わらない
+    pub name: Option<String>,
+    pub local_count: usize,
+    pub ops: Vec<Op>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Op {
     Call(usize),
+    // 名前で関数を呼ぶ。リンク時に解決する
+    CallName(String),
     LocalLoad(usize),
     LocalStore(usize),
     ArgLoad(usize),
     ArgStore(usize),
-    Const(usize),
+    Const(Value),
     Add,
+    Sub,
+    Mul,
+    Div,
     Mod,
     Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
     JumpIf(usize),
     Jump(usize),
     PopR(usize),
+    Alloc(usize),
+    HeapLoad,
+    HeapStore,
+    StrConst(usize),
+    StrConcat,
+    StrLen,
+    StrEq,
+    // NativeCall(ホスト関数の番号, 引数の数)。引数の数はスタックの深さの計算にだけ使う
+    NativeCall(usize, usize),
+    Print,
+    ReadInt,
+    And,
+    Or,
+    Not,
+    Xor,
+    Shl,
+    Shr,
+    Dup,
+    Swap,
+    Pop,
+    // 関数IDの関数を指す値を積む。CallIndirectで呼べる
+    FuncRef(usize),
+    // popした値が指す関数を呼ぶ。引数はその下に積んでおき、Callと同じくPopRで片付ける
+    CallIndirect,
+    // MakeClosure(関数ID, 捕捉する値の数)。積んだ値を捕捉したクロージャを積む
+    MakeClosure(usize, usize),
+    // スタックトップのクロージャを呼ぶ。クロージャは呼ばれた関数のArgLoad(0)になり、
+    // 本来の引数はArgLoad(1)から読む。PopRの数にはクロージャの分も含める
+    CallClosure,
+    // クロージャとして呼ばれた関数で、捕捉したi番目の値を積む
+    CaptureLoad(usize),
+}
+
+// 関数単位で変換した命令列。関数IDは未解決のまま持つので、キャッシュしておいて別の組み合わせでリンクできる
+#[derive(Clone, Debug, PartialEq)]
+pub struct Fragment {
+    id: usize,
+    name: Option<String>,
+    cmds: Vec<LLangCmd>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 struct CmdGen {
     cmds: Vec<LLangCmd>,
-    funcs: Vec<usize>,
+    // 関数IDから関数の先頭アドレスへのシンボルテーブル
+    symbols: HashMap<usize, usize>,
+    // 関数名から関数の先頭アドレスへのシンボルテーブル
+    names: HashMap<String, usize>,
+    // 関数の先頭アドレスから名前へ。名前のない関数は#関数ID
+    labels: BTreeMap<usize, String>,
+}
+
+// リンクで関数の参照を解決できなかった
+#[derive(Clone, Debug, PartialEq)]
+pub enum SymbolError {
+    UnresolvedId(usize),
+    UnresolvedName(String),
+    DuplicateId(usize),
+    DuplicateName(String),
+}
+
+impl fmt::Display for SymbolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SymbolError::UnresolvedId(id) => write!(f, "unresolved function id {}", id),
+            SymbolError::UnresolvedName(name) => write!(f, "unresolved function `{}`", name),
+            SymbolError::DuplicateId(id) => write!(f, "duplicate function id {}", id),
+            SymbolError::DuplicateName(name) => write!(f, "duplicate function `{}`", name),
+        }
+    }
 }
 
+impl error::Error for SymbolError {}
+
 impl CmdGen {
     fn new() -> CmdGen {
         CmdGen {
             cmds: Vec::new(),
-            funcs: Vec::new(),
+            symbols: HashMap::new(),
+            names: HashMap::new(),
+            labels: BTreeMap::new(),
         }
     }
 
     fn push(&mut self, cmd: LLangCmd) {
-        if let &LLangCmd::Frame(_) = &cmd {
-            self.funcs.push(self.cmds.len());
-        }
         self.cmds.push(cmd);
     }
 
-    fn to_cmds(self) -> Vec<Cmd> {
-        let cmds = self.cmds;
-        let funcs = self.funcs;
-        cmds.into_iter()
-            .map(|cmd| match cmd {
-                LLangCmd::Frame(x) => Cmd::Frame(x),
-                LLangCmd::Ret => Cmd::Ret,
-                LLangCmd::Call(FnIndex(i)) => Cmd::Call(funcs[i]),
-                LLangCmd::LocalLoad(x) => Cmd::LocalLoad(x),
-                LLangCmd::LocalStore(x) => Cmd::LocalStore(x),
-                LLangCmd::ArgLoad(x) => Cmd::ArgLoad(x),
-                LLangCmd::ArgStore(x) => Cmd::ArgStore(x),
-                LLangCmd::PopR(x) => Cmd::PopR(x),
-                LLangCmd::Const(x) => Cmd::Const(x),
-                LLangCmd::Add => Cmd::Add,
-                LLangCmd::Mod => Cmd::Mod,
-                LLangCmd::Entry(FnIndex(i)) => Cmd::Entry(funcs[i]),
-                LLangCmd::Eq => Cmd::Eq,
-                LLangCmd::JumpIf(RelativeFnIndex(FnIndex(i), x)) => Cmd::JumpIf(funcs[i] + x + 1),
-                LLangCmd::Jump(RelativeFnIndex(FnIndex(i), x)) => Cmd::Jump(funcs[i] + x + 1),
+    fn push_fragment(&mut self, fragment: &Fragment) -> Result<(), SymbolError> {
+        if self.symbols.insert(fragment.id, self.cmds.len()).is_some() {
+            return Err(SymbolError::DuplicateId(fragment.id));
+        }
+        if let Some(name) = &fragment.name {
+            if self.names.insert(name.clone(), self.cmds.len()).is_some() {
+                return Err(SymbolError::DuplicateName(name.clone()));
+            }
+        }
+        let label = match &fragment.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", fragment.id),
+        };
+        self.labels.insert(self.cmds.len(), label);
+        self.cmds.extend(fragment.cmds.iter().cloned());
+        Ok(())
+    }
+
+    fn resolve(&self, FnId(id): &FnId) -> Result<usize, SymbolError> {
+        self.symbols
+            .get(id)
+            .cloned()
+            .ok_or(SymbolError::UnresolvedId(*id))
+    }
+
+    fn resolve_name(&self, name: &str) -> Result<usize, SymbolError> {
+        self.names
+            .get(name)
+            .cloned()
+            .ok_or_else(|| SymbolError::UnresolvedName(name.to_string()))
+    }
+
+    fn into_cmds(self) -> Result<Vec<Cmd>, SymbolError> {
+        self.cmds
+            .iter()
+            .map(|cmd| {
+                Ok(match cmd.clone() {
+                    LLangCmd::Frame(x, y) => Cmd::Frame(x, y),
+                    LLangCmd::Ret => Cmd::Ret,
+                    LLangCmd::Call(id) => Cmd::Call(self.resolve(&id)?),
+                    LLangCmd::CallName(name) => Cmd::Call(self.resolve_name(&name)?),
+                    LLangCmd::TailCall(id, argc) => Cmd::TailCall(self.resolve(&id)?, argc),
+                    LLangCmd::TailCallName(name, argc) => {
+                        Cmd::TailCall(self.resolve_name(&name)?, argc)
+                    }
+                    LLangCmd::FuncRef(id) => Cmd::FuncRef(self.resolve(&id)?),
+                    LLangCmd::CallIndirect => Cmd::CallIndirect,
+                    LLangCmd::MakeClosure(id, n) => Cmd::MakeClosure(self.resolve(&id)?, n),
+                    LLangCmd::CallClosure => Cmd::CallClosure,
+                    LLangCmd::CaptureLoad(x) => Cmd::CaptureLoad(x),
+                    LLangCmd::LocalLoad(x) => Cmd::LocalLoad(x),
+                    LLangCmd::LocalStore(x) => Cmd::LocalStore(x),
+                    LLangCmd::ArgLoad(x) => Cmd::ArgLoad(x),
+                    LLangCmd::ArgStore(x) => Cmd::ArgStore(x),
+                    LLangCmd::PopR(x) => Cmd::PopR(x),
+                    LLangCmd::Const(x) => Cmd::Const(x),
+                    LLangCmd::Add => Cmd::Add,
+                    LLangCmd::Sub => Cmd::Sub,
+                    LLangCmd::Mul => Cmd::Mul,
+                    LLangCmd::Div => Cmd::Div,
+                    LLangCmd::Mod => Cmd::Mod,
+                    LLangCmd::Entry(id) => Cmd::Entry(self.resolve(&id)?),
+                    LLangCmd::Eq => Cmd::Eq,
+                    LLangCmd::Ne => Cmd::Ne,
+                    LLangCmd::Lt => Cmd::Lt,
+                    LLangCmd::Le => Cmd::Le,
+                    LLangCmd::Gt => Cmd::Gt,
+                    LLangCmd::Ge => Cmd::Ge,
+                    LLangCmd::Alloc(x) => Cmd::Alloc(x),
+                    LLangCmd::HeapLoad => Cmd::HeapLoad,
+                    LLangCmd::HeapStore => Cmd::HeapStore,
+                    LLangCmd::StrConst(x) => Cmd::StrConst(x),
+                    LLangCmd::StrConcat => Cmd::StrConcat,
+                    LLangCmd::StrLen => Cmd::StrLen,
+                    LLangCmd::StrEq => Cmd::StrEq,
+                    LLangCmd::NativeCall(x) => Cmd::NativeCall(x),
+                    LLangCmd::Print => Cmd::Print,
+                    LLangCmd::ReadInt => Cmd::ReadInt,
+                    LLangCmd::And => Cmd::And,
+                    LLangCmd::Or => Cmd::Or,
+                    LLangCmd::Not => Cmd::Not,
+                    LLangCmd::Xor => Cmd::Xor,
+                    LLangCmd::Shl => Cmd::Shl,
+                    LLangCmd::Shr => Cmd::Shr,
+                    LLangCmd::Dup => Cmd::Dup,
+                    LLangCmd::Swap => Cmd::Swap,
+                    LLangCmd::Pop => Cmd::Pop,
+                    LLangCmd::JumpIf(RelativeFnId(id, x)) => {
+                        Cmd::JumpIf(self.resolve(&id)? + x + 1)
+                    }
+                    LLangCmd::Jump(RelativeFnId(id, x)) => Cmd::Jump(self.resolve(&id)? + x + 1),
+                })
             })
             .collect()
     }
 }
 
+// 関数IDがentryの関数から実行を開始するプログラムとしてfragmentsをリンクする
+// 解決できない参照があればpanicする
+pub fn link(entry: usize, fragments: &[Fragment]) -> Vec<Cmd> {
+    try_link(entry, fragments)
+        .map(|(cmds, _)| cmds)
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+// 命令列と、関数の先頭アドレスから名前へのシンボルマップを返す。シンボルマップはexplainやProfileのレポートに渡せる
+pub fn try_link(
+    entry: usize,
+    fragments: &[Fragment],
+) -> Result<(Vec<Cmd>, BTreeMap<usize, String>), SymbolError> {
+    let mut gen = CmdGen::new();
+    gen.push(LLangCmd::Entry(FnId(entry)));
+    for fragment in fragments {
+        gen.push_fragment(fragment)?;
+    }
+    let labels = gen.labels.clone();
+    Ok((gen.into_cmds()?, labels))
+}
+
+// linkと同じだが、Entry, TailCall, FuncRef, MakeClosure以外の飛び先を相対アドレスにする。それらだけ直せばどこにでも置ける
+pub fn link_relative(entry: usize, fragments: &[Fragment]) -> Vec<Cmd> {
+    link(entry, fragments)
+        .into_iter()
+        .enumerate()
+        .map(|(pc, cmd)| {
+            let offset = |x: usize| x as isize - pc as isize;
+            match cmd {
+                Cmd::Call(x) => Cmd::CallRel(offset(x)),
+                Cmd::JumpIf(x) => Cmd::JumpIfRel(offset(x)),
+                Cmd::Jump(x) => Cmd::JumpRel(offset(x)),
+                cmd => cmd,
+            }
+        })
+        .collect()
+}
+
+// アドレス0に置く前提で作ったcmdsをbaseから置けるよう、絶対アドレスの飛び先をずらす
+pub fn relocate(cmds: &[Cmd], base: usize) -> Vec<Cmd> {
+    cmds.iter()
+        .map(|cmd| match *cmd {
+            Cmd::Entry(x) => Cmd::Entry(x + base),
+            Cmd::Call(x) => Cmd::Call(x + base),
+            Cmd::TailCall(x, argc) => Cmd::TailCall(x + base, argc),
+            Cmd::FuncRef(x) => Cmd::FuncRef(x + base),
+            Cmd::MakeClosure(x, n) => Cmd::MakeClosure(x + base, n),
+            Cmd::JumpIf(x) => Cmd::JumpIf(x + base),
+            Cmd::Jump(x) => Cmd::Jump(x + base),
+            ref cmd => cmd.clone(),
+        })
+        .collect()
+}
+
 impl LLang {
-    fn convert(&self) -> Vec<Cmd> {
-        let mut gen = CmdGen::new();
-        let entry = gen.push(LLangCmd::Entry(FnIndex(self.entry)));
-        for (i, func) in self.funcs.iter().enumerate() {
-            func.convert(i, &mut gen);
+    pub fn convert(&self) -> Vec<Cmd> {
+        self.try_convert().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_convert(&self) -> Result<Vec<Cmd>, SymbolError> {
+        self.convert_with_symbols().map(|(cmds, _)| cmds)
+    }
+
+    pub fn convert_with_symbols(&self) -> Result<(Vec<Cmd>, BTreeMap<usize, String>), SymbolError> {
+        try_link(
+            self.entry,
+            &self.funcs.iter().map(Func::compile).collect::<Vec<_>>(),
+        )
+    }
+
+    pub fn func_by_name(&self, name: &str) -> Option<&Func> {
+        self.funcs.iter().find(|f| f.name.as_deref() == Some(name))
+    }
+
+    // opが呼ぶ関数のID。名前で呼ぶ場合は解決できなければNone
+    pub fn callee(&self, op: &Op) -> Option<usize> {
+        match op {
+            Op::Call(id) => Some(*id),
+            Op::CallName(name) => self.func_by_name(name).map(|f| f.id),
+            _ => None,
+        }
+    }
+
+    // 16ビットに収まらない定数は重複を除いて定数プールに移し、ConstLoadで読む
+    pub fn to_program(&self) -> Program {
+        let mut consts = Vec::new();
+        let mut indices = HashMap::new();
+        let code = self
+            .convert()
+            .into_iter()
+            .map(|cmd| match cmd {
+                Cmd::Const(x) if x != x as i16 as Value => {
+                    Cmd::ConstLoad(*indices.entry(x).or_insert_with(|| {
+                        consts.push(x);
+                        consts.len() - 1
+                    }))
+                }
+                cmd => cmd,
+            })
+            .collect();
+        Program {
+            consts,
+            code,
+            ..Program::default()
         }
-        gen.to_cmds()
     }
 }
 
 impl Func {
-    fn convert(&self, fn_index: usize, gen: &mut CmdGen) {
-        gen.push(LLangCmd::Frame(self.local_count));
-        for op in &self.ops {
-            op.convert(fn_index, gen);
+    // 末尾位置のCall, PopRはTailCallひとつにする
+    pub fn compile(&self) -> Fragment {
+        let tail_calls = self.tail_calls();
+        // ループで深さが決まらないときは前もって確保せず、積むたびに確保する
+        let mut cmds = vec![LLangCmd::Frame(
+            self.local_count,
+            self.max_stack().unwrap_or(0),
+        )];
+        // ops[i]を変換した命令の、Frameを除いた位置
+        let mut offsets = Vec::with_capacity(self.ops.len() + 1);
+        for (i, op) in self.ops.iter().enumerate() {
+            offsets.push(cmds.len() - 1);
+            if i > 0 && tail_calls.contains_key(&(i - 1)) {
+                continue;
+            }
+            cmds.push(match (op, tail_calls.get(&i)) {
+                (Op::Call(x), Some(&argc)) => LLangCmd::TailCall(FnId(*x), argc),
+                (Op::CallName(x), Some(&argc)) => LLangCmd::TailCallName(x.clone(), argc),
+                (op, _) => op.convert(self.id),
+            });
+        }
+        offsets.push(cmds.len() - 1);
+        cmds.push(LLangCmd::Ret);
+        if !tail_calls.is_empty() {
+            for cmd in &mut cmds {
+                if let LLangCmd::JumpIf(RelativeFnId(_, x)) | LLangCmd::Jump(RelativeFnId(_, x)) =
+                    cmd
+                {
+                    if let Some(y) = offsets.get(*x) {
+                        *x = *y;
+                    }
+                }
+            }
+        }
+        Fragment {
+            id: self.id,
+            name: self.name.clone(),
+            cmds,
         }
-        gen.push(LLangCmd::Ret);
+    }
+
+    // 末尾位置の呼び出しのインデックスから、TailCallで渡す引数の数へ
+    // 直後がPopRで、その後すぐ戻る呼び出しが対象。呼び出し時にオペランドスタックにある値をすべて引数として渡す。
+    // 引数の数がこの関数の引数(ArgLoad, ArgStoreの最大のインデックス+1)以下のときだけ、今の引数の場所に収まる
+    fn tail_calls(&self) -> HashMap<usize, usize> {
+        let depths = match self.stack_depths() {
+            Some(depths) => depths,
+            None => return HashMap::new(),
+        };
+        let arity = self
+            .ops
+            .iter()
+            .filter_map(|op| match op {
+                Op::ArgLoad(x) | Op::ArgStore(x) => Some(x + 1),
+                // 0番目の引数のクロージャを読む
+                Op::CaptureLoad(_) => Some(1),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0);
+        let len = self.ops.len();
+        let returns = |i: usize| i == len || self.ops.get(i) == Some(&Op::Jump(len));
+        let jumped_into = |i: usize| {
+            self.ops
+                .iter()
+                .any(|op| matches!(op, Op::Jump(x) | Op::JumpIf(x) if *x == i))
+        };
+        (0..len.saturating_sub(1))
+            .filter_map(|i| match (&self.ops[i], &self.ops[i + 1], depths[i]) {
+                (Op::Call(_), Op::PopR(_), Some(depth))
+                | (Op::CallName(_), Op::PopR(_), Some(depth))
+                    if depth <= arity && returns(i + 2) && !jumped_into(i + 1) =>
+                {
+                    Some((i, depth))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    // ops[start..end]を取り除き、ジャンプ先を詰め直す。取り除いた範囲へのジャンプはその直後へ向ける
+    pub fn remove_ops(&mut self, start: usize, end: usize) {
+        self.replace_ops(start, end, Vec::new());
+    }
+
+    // ops[start..end]をopsに置き換え、ジャンプ先を付け直す。置き換えた範囲へのジャンプはその先頭へ向ける
+    // ops内のジャンプ先は置き換え後のインデックスで書く
+    pub fn replace_ops(&mut self, start: usize, end: usize, ops: Vec<Op>) {
+        let inserted = ops.len();
+        let remap = |x: usize| {
+            if x >= end {
+                x - (end - start) + inserted
+            } else if x >= start {
+                start
+            } else {
+                x
+            }
+        };
+        let remap_op = |op: &Op| match op {
+            Op::JumpIf(x) => Op::JumpIf(remap(*x)),
+            Op::Jump(x) => Op::Jump(remap(*x)),
+            op => op.clone(),
+        };
+        let mut result = self.ops[..start].iter().map(remap_op).collect::<Vec<_>>();
+        result.extend(ops);
+        result.extend(self.ops[end..].iter().map(remap_op));
+        self.ops = result;
+    }
+
+    // オペランドスタックの最大使用量。ループでスタックが伸び続ける場合はNone
+    pub fn max_stack(&self) -> Option<usize> {
+        let depths = self.stack_depths()?;
+        Some(
+            self.ops
+                .iter()
+                .zip(depths)
+                .filter_map(|(op, depth)| {
+                    depth.map(|d| d.max((d + op.push_count()).saturating_sub(op.pop_count())))
+                })
+                .max()
+                .unwrap_or(0),
+        )
+    }
+
+    // 各命令の実行直前のオペランドスタックの最大の深さ。到達しない命令はNone
+    // ループでスタックが伸び続ける場合はNone
+    pub fn stack_depths(&self) -> Option<Vec<Option<usize>>> {
+        // ループがなければ各命令で高々2しか増えないので、これを超えたら発散している
+        let limit = self.ops.len() * 2;
+        let mut depths = vec![None; self.ops.len()];
+        let mut work = vec![(0, 0)];
+        while let Some((i, depth)) = work.pop() {
+            if depth > limit {
+                return None;
+            }
+            // 関数外へのジャンプはRetとみなす
+            if i >= self.ops.len() {
+                continue;
+            }
+            if let Some(d) = depths[i] {
+                if d >= depth {
+                    continue;
+                }
+            }
+            depths[i] = Some(depth);
+
+            let op = &self.ops[i];
+            let next = (depth + op.push_count()).saturating_sub(op.pop_count());
+            for succ in op.successors(i) {
+                work.push((succ, next));
+            }
+        }
+        Some(depths)
     }
 }
 
 impl Op {
-    fn convert(&self, fn_index: usize, gen: &mut CmdGen) {
-        gen.push(match self {
-            Op::Call(x) => LLangCmd::Call(FnIndex(*x)),
+    pub(crate) fn pop_count(&self) -> usize {
+        match self {
+            Op::Call(_) | Op::CallName(_) => 0,
+            Op::CallIndirect => 1,
+            Op::FuncRef(_) => 0,
+            Op::MakeClosure(_, n) => *n,
+            Op::CallClosure => 0,
+            Op::CaptureLoad(_) => 0,
+            Op::LocalLoad(_) => 0,
+            Op::LocalStore(_) => 1,
+            Op::ArgLoad(_) => 0,
+            Op::ArgStore(_) => 1,
+            Op::Const(_) => 0,
+            Op::Add => 2,
+            Op::Sub => 2,
+            Op::Mul => 2,
+            Op::Div => 2,
+            Op::Mod => 2,
+            Op::Eq => 2,
+            Op::Ne => 2,
+            Op::Lt => 2,
+            Op::Le => 2,
+            Op::Gt => 2,
+            Op::Ge => 2,
+            Op::JumpIf(_) => 1,
+            Op::Jump(_) => 0,
+            Op::PopR(x) => *x,
+            Op::Alloc(_) => 0,
+            Op::HeapLoad => 2,
+            Op::HeapStore => 3,
+            Op::StrConst(_) => 0,
+            Op::StrConcat => 2,
+            Op::StrLen => 1,
+            Op::StrEq => 2,
+            Op::NativeCall(_, arity) => *arity,
+            Op::Print => 1,
+            Op::ReadInt => 0,
+            Op::And | Op::Or | Op::Xor | Op::Shl | Op::Shr => 2,
+            Op::Not => 1,
+            Op::Dup => 1,
+            Op::Swap => 2,
+            Op::Pop => 1,
+        }
+    }
+
+    pub(crate) fn push_count(&self) -> usize {
+        match self {
+            // 戻りアドレスと戻り値
+            Op::Call(_) | Op::CallName(_) | Op::CallIndirect | Op::CallClosure => 2,
+            Op::FuncRef(_) => 1,
+            Op::MakeClosure(_, _) => 1,
+            Op::CaptureLoad(_) => 1,
+            Op::LocalLoad(_) => 1,
+            Op::LocalStore(_) => 0,
+            Op::ArgLoad(_) => 1,
+            Op::ArgStore(_) => 0,
+            Op::Const(_) => 1,
+            Op::Add => 1,
+            Op::Sub => 1,
+            Op::Mul => 1,
+            Op::Div => 1,
+            Op::Mod => 1,
+            Op::Eq => 1,
+            Op::Ne => 1,
+            Op::Lt => 1,
+            Op::Le => 1,
+            Op::Gt => 1,
+            Op::Ge => 1,
+            Op::JumpIf(_) => 0,
+            Op::Jump(_) => 0,
+            Op::PopR(_) => 1,
+            Op::Alloc(_) => 1,
+            Op::HeapLoad => 1,
+            Op::HeapStore => 0,
+            Op::StrConst(_) => 1,
+            Op::StrConcat => 1,
+            Op::StrLen => 1,
+            Op::StrEq => 1,
+            Op::NativeCall(_, _) => 1,
+            Op::Print => 0,
+            Op::ReadInt => 1,
+            Op::And | Op::Or | Op::Not | Op::Xor | Op::Shl | Op::Shr => 1,
+            Op::Dup => 2,
+            Op::Swap => 2,
+            Op::Pop => 0,
+        }
+    }
+
+    pub fn successors(&self, index: usize) -> Vec<usize> {
+        match self {
+            Op::JumpIf(x) => vec![index + 1, *x],
+            Op::Jump(x) => vec![*x],
+            _ => vec![index + 1],
+        }
+    }
+
+    fn convert(&self, fn_id: usize) -> LLangCmd {
+        match self {
+            Op::Call(x) => LLangCmd::Call(FnId(*x)),
+            Op::CallName(x) => LLangCmd::CallName(x.clone()),
             Op::LocalLoad(x) => LLangCmd::LocalLoad(*x),
             Op::LocalStore(x) => LLangCmd::LocalStore(*x),
             Op::ArgLoad(x) => LLangCmd::ArgLoad(*x),
             Op::ArgStore(x) => LLangCmd::ArgStore(*x),
             Op::Const(x) => LLangCmd::Const(*x),
             Op::Add => LLangCmd::Add,
+            Op::Sub => LLangCmd::Sub,
+            Op::Mul => LLangCmd::Mul,
+            Op::Div => LLangCmd::Div,
             Op::Mod => LLangCmd::Mod,
             Op::Eq => LLangCmd::Eq,
-            Op::JumpIf(x) => LLangCmd::JumpIf(RelativeFnIndex(FnIndex(fn_index), *x)),
-            Op::Jump(x) => LLangCmd::Jump(RelativeFnIndex(FnIndex(fn_index), *x)),
+            Op::Ne => LLangCmd::Ne,
+            Op::Lt => LLangCmd::Lt,
+            Op::Le => LLangCmd::Le,
+            Op::Gt => LLangCmd::Gt,
+            Op::Ge => LLangCmd::Ge,
+            Op::JumpIf(x) => LLangCmd::JumpIf(RelativeFnId(FnId(fn_id), *x)),
+            Op::Jump(x) => LLangCmd::Jump(RelativeFnId(FnId(fn_id), *x)),
             Op::PopR(x) => LLangCmd::PopR(*x),
-        });
+            Op::Alloc(x) => LLangCmd::Alloc(*x),
+            Op::HeapLoad => LLangCmd::HeapLoad,
+            Op::HeapStore => LLangCmd::HeapStore,
+            Op::StrConst(x) => LLangCmd::StrConst(*x),
+            Op::StrConcat => LLangCmd::StrConcat,
+            Op::StrLen => LLangCmd::StrLen,
+            Op::StrEq => LLangCmd::StrEq,
+            Op::NativeCall(x, _) => LLangCmd::NativeCall(*x),
+            Op::Print => LLangCmd::Print,
+            Op::ReadInt => LLangCmd::ReadInt,
+            Op::And => LLangCmd::And,
+            Op::Or => LLangCmd::Or,
+            Op::Not => LLangCmd::Not,
+            Op::Xor => LLangCmd::Xor,
+            Op::Shl => LLangCmd::Shl,
+            Op::Shr => LLangCmd::Shr,
+            Op::Dup => LLangCmd::Dup,
+            Op::Swap => LLangCmd::Swap,
+            Op::Pop => LLangCmd::Pop,
+            Op::FuncRef(x) => LLangCmd::FuncRef(FnId(*x)),
+            Op::CallIndirect => LLangCmd::CallIndirect,
+            Op::MakeClosure(x, n) => LLangCmd::MakeClosure(FnId(*x), *n),
+            Op::CallClosure => LLangCmd::CallClosure,
+            Op::CaptureLoad(x) => LLangCmd::CaptureLoad(*x),
+        }
     }
 }
 
 #[test]
 fn test() {
+    use crate::vm::VM;
+
     assert_eq!(
         VM::new(
             (LLang {
                 entry: 0,
                 funcs: vec![
                     Func {
+                        id: 0,
+                        name: None,
                         local_count: 0,
                         ops: vec![Op::Const(182), Op::Const(1029), Op::Call(1), Op::PopR(2)]
                     },
                     Func {
+                        id: 1,
+                        name: None,
                         local_count: 0,
                         ops: vec![
                             Op::ArgLoad(0),
@@ -173,6 +745,442 @@ fn test() {
             .convert()
         )
         .run(),
-        7
+        Ok(7)
+    );
+}
+
+#[test]
+fn test_max_stack() {
+    assert_eq!(
+        Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![Op::Const(182), Op::Const(1029), Op::Call(1), Op::PopR(2)]
+        }
+        .max_stack(),
+        Some(4)
+    );
+
+    assert_eq!(
+        Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![Op::Const(1), Op::Jump(0)]
+        }
+        .max_stack(),
+        None
+    );
+}
+
+#[test]
+fn test_unknown_max_stack() {
+    use crate::vm::VM;
+
+    // 戻らないループのせいで深さが決まらないが、実行はできる
+    let func = Func {
+        id: 0,
+        name: None,
+        local_count: 1,
+        ops: vec![
+            Op::Const(2),
+            Op::LocalStore(0),
+            Op::LocalLoad(0),
+            Op::LocalLoad(0),
+            Op::LocalLoad(0),
+            Op::Add,
+            Op::Add,
+            Op::Const(0),
+            Op::JumpIf(2),
+        ],
+    };
+    assert_eq!(func.max_stack(), None);
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![func],
+    };
+    assert_eq!(VM::new(llang.convert()).run(), Ok(6));
+}
+
+#[test]
+fn test_link() {
+    use crate::vm::VM;
+
+    let main = Func {
+        id: 10,
+        name: None,
+        local_count: 0,
+        ops: vec![Op::Const(1), Op::Const(2), Op::Call(3), Op::PopR(4)],
+    }
+    .compile();
+    let add = Func {
+        id: 3,
+        name: None,
+        local_count: 0,
+        ops: vec![Op::ArgLoad(0), Op::ArgLoad(1), Op::Add],
+    }
+    .compile();
+
+    assert_eq!(VM::new(link(10, &[main.clone(), add.clone()])).run(), Ok(3));
+    assert_eq!(VM::new(link(10, &[add, main])).run(), Ok(3));
+}
+
+#[test]
+#[should_panic(expected = "unresolved function id 3")]
+fn test_link_unresolved() {
+    let main = Func {
+        id: 0,
+        name: None,
+        local_count: 0,
+        ops: vec![Op::Call(3)],
+    }
+    .compile();
+    link(0, &[main]);
+}
+
+#[test]
+fn test_names() {
+    use crate::vm::VM;
+
+    let func = |id, name: &str, ops| Func {
+        id,
+        name: Some(name.to_string()),
+        local_count: 0,
+        ops,
+    };
+    let mut llang = LLang {
+        entry: 0,
+        funcs: vec![
+            func(
+                0,
+                "main",
+                vec![
+                    Op::Const(2),
+                    Op::Const(5),
+                    Op::CallName("sub".to_string()),
+                    Op::PopR(4),
+                ],
+            ),
+            func(7, "sub", vec![Op::ArgLoad(0), Op::ArgLoad(1), Op::Sub]),
+        ],
+    };
+    assert_eq!(VM::new(llang.convert()).run(), Ok(-3));
+    assert_eq!(llang.func_by_name("sub").map(|f| f.id), Some(7));
+    assert_eq!(llang.callee(&llang.funcs[0].ops[2]), Some(7));
+
+    // 関数を前に足しても名前での参照は変わらない
+    llang.funcs.insert(0, func(3, "unused", vec![Op::Const(0)]));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(-3));
+    let (_, symbols) = llang.convert_with_symbols().unwrap();
+    assert_eq!(
+        symbols.into_iter().collect::<Vec<_>>(),
+        vec![
+            (1, "unused".to_string()),
+            (4, "main".to_string()),
+            (10, "sub".to_string())
+        ]
+    );
+
+    llang.funcs[0].name = Some("sub".to_string());
+    assert_eq!(
+        llang.try_convert(),
+        Err(SymbolError::DuplicateName("sub".to_string()))
+    );
+    llang.funcs.remove(0);
+    llang.funcs[1].name = Some("subtract".to_string());
+    assert_eq!(
+        llang.try_convert().map_err(|e| e.to_string()),
+        Err("unresolved function `sub`".to_string())
+    );
+    assert_eq!(llang.callee(&llang.funcs[0].ops[2]), None);
+}
+
+#[test]
+fn test_remove_ops() {
+    let mut func = Func {
+        id: 0,
+        name: None,
+        local_count: 0,
+        ops: vec![
+            Op::JumpIf(4),
+            Op::Const(1),
+            Op::Const(2),
+            Op::Jump(0),
+            Op::Const(3),
+            Op::Jump(2),
+        ],
+    };
+    func.remove_ops(1, 3);
+    assert_eq!(
+        func.ops,
+        vec![Op::JumpIf(2), Op::Jump(0), Op::Const(3), Op::Jump(1)]
+    );
+    func.replace_ops(1, 2, vec![Op::Const(4), Op::Const(5)]);
+    assert_eq!(
+        func.ops,
+        vec![
+            Op::JumpIf(3),
+            Op::Const(4),
+            Op::Const(5),
+            Op::Const(3),
+            Op::Jump(1)
+        ]
+    );
+}
+
+#[test]
+fn test_arithmetic() {
+    use crate::vm::VM;
+
+    // (7 - 2) * 6 / 3
+    assert_eq!(
+        VM::new(
+            LLang {
+                entry: 0,
+                funcs: vec![Func {
+                    id: 0,
+                    name: None,
+                    local_count: 0,
+                    ops: vec![
+                        Op::Const(3),
+                        Op::Const(6),
+                        Op::Const(2),
+                        Op::Const(7),
+                        Op::Sub,
+                        Op::Mul,
+                        Op::Div,
+                    ],
+                }],
+            }
+            .convert()
+        )
+        .run(),
+        Ok(10)
+    );
+}
+
+#[test]
+fn test_compare() {
+    use crate::vm::VM;
+
+    // abs(-5)
+    assert_eq!(
+        VM::new(
+            LLang {
+                entry: 0,
+                funcs: vec![Func {
+                    id: 0,
+                    name: None,
+                    local_count: 0,
+                    ops: vec![
+                        Op::Const(0),  // 0
+                        Op::Const(-5), // 1
+                        Op::Lt,        // 2
+                        Op::JumpIf(6), // 3
+                        Op::Const(-5), // 4
+                        Op::Jump(9),   // 5
+                        Op::Const(-5), // 6
+                        Op::Const(0),  // 7
+                        Op::Sub,       // 8
+                    ],
+                }],
+            }
+            .convert()
+        )
+        .run(),
+        Ok(5)
     );
 }
+
+#[test]
+fn test_to_program() {
+    use crate::vm::{VmConfig, VM};
+
+    let big = 1 << 40;
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![
+                Op::Const(big),
+                Op::Const(-7),
+                Op::Add,
+                Op::Const(big),
+                Op::Sub,
+                Op::Const(70000),
+                Op::Add,
+            ],
+        }],
+    };
+    let program = llang.to_program();
+    assert_eq!(program.consts, vec![big, 70000]);
+    assert_eq!(
+        program.code[2..5],
+        [Cmd::ConstLoad(0), Cmd::Const(-7), Cmd::Add]
+    );
+    assert_eq!(
+        VM::from_program(program, VmConfig::new()).run(),
+        VM::new(llang.convert()).run()
+    );
+}
+
+#[test]
+fn test_relocate() {
+    use crate::genprog::{generate, ALL_WORKLOADS};
+    use crate::vm::VM;
+
+    // 先頭に置いたJumpで、後ろへ移したプログラムのEntryへ飛ぶ
+    let moved = |cmds: Vec<Cmd>| {
+        let mut program = vec![Cmd::Jump(3), Cmd::Const(0), Cmd::Const(0)];
+        program.extend(relocate(&cmds, 3));
+        VM::new(program).run()
+    };
+    for &workload in &ALL_WORKLOADS {
+        let llang = generate(workload, 5);
+        let fragments = llang.funcs.iter().map(Func::compile).collect::<Vec<_>>();
+        let expected = VM::new(llang.convert()).run();
+        assert_eq!(moved(llang.convert()), expected);
+
+        let relative = link_relative(llang.entry, &fragments);
+        assert!(!relative
+            .iter()
+            .any(|cmd| matches!(cmd, Cmd::Call(_) | Cmd::Jump(_) | Cmd::JumpIf(_))));
+        assert_eq!(VM::new(relative.clone()).run(), expected);
+        assert_eq!(relocate(&relative, 3)[1..], relative[1..]);
+        assert_eq!(moved(relative), expected);
+    }
+}
+
+#[test]
+fn test_tail_call() {
+    use crate::vm::{VmError, VM};
+
+    // sum(n, acc) = if n == 0 { acc } else { sum(n - 1, acc + n) }
+    let sum = |ops_after_call: Vec<Op>| {
+        let mut ops = vec![
+            Op::ArgLoad(0),
+            Op::Const(0),
+            Op::Eq,
+            Op::JumpIf(0),
+            Op::ArgLoad(1),
+            Op::ArgLoad(0),
+            Op::Add,
+            Op::Const(1),
+            Op::ArgLoad(0),
+            Op::Sub,
+            Op::Call(1),
+            Op::PopR(4),
+        ];
+        ops.extend(ops_after_call);
+        ops.push(Op::Jump(ops.len() + 2));
+        ops[3] = Op::JumpIf(ops.len());
+        ops.push(Op::ArgLoad(1));
+        Func {
+            id: 1,
+            name: None,
+            local_count: 0,
+            ops,
+        }
+    };
+    let main = Func {
+        id: 0,
+        name: None,
+        local_count: 0,
+        ops: vec![Op::Const(0), Op::Const(10000), Op::Call(1), Op::PopR(4)],
+    };
+
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![main.clone(), sum(vec![])],
+    };
+    let program = llang.convert();
+    assert_eq!(
+        program
+            .iter()
+            .filter(|cmd| matches!(cmd, Cmd::TailCall(_, 2)))
+            .count(),
+        1
+    );
+    assert_eq!(VM::new(program).run(), Ok(50005000));
+
+    // 呼び出しの後に計算が残るなら末尾呼び出しではない
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![main, sum(vec![Op::Const(0), Op::Add])],
+    };
+    let program = llang.convert();
+    assert!(!program.iter().any(|cmd| matches!(cmd, Cmd::TailCall(_, _))));
+    assert_eq!(VM::new(program).run(), Err(VmError::StackOverflow));
+}
+
+#[test]
+fn test_func_ref() {
+    use crate::vm::VM;
+
+    let func = |id, ops| Func {
+        id,
+        name: None,
+        local_count: 0,
+        ops,
+    };
+    // apply(f, x) = f(x)
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            func(
+                0,
+                vec![Op::Const(20), Op::FuncRef(2), Op::Call(1), Op::PopR(4)],
+            ),
+            func(
+                1,
+                vec![
+                    Op::ArgLoad(1),
+                    Op::ArgLoad(0),
+                    Op::CallIndirect,
+                    Op::PopR(3),
+                ],
+            ),
+            func(2, vec![Op::ArgLoad(0), Op::ArgLoad(0), Op::Add]),
+        ],
+    };
+    assert_eq!(llang.funcs[1].max_stack(), Some(3));
+    let program = llang.convert();
+    assert!(program.contains(&Cmd::FuncRef(13)));
+    assert_eq!(VM::new(program).run(), Ok(40));
+}
+
+#[test]
+fn test_closure() {
+    use crate::vm::VM;
+
+    let func = |id, ops| Func {
+        id,
+        name: None,
+        local_count: 0,
+        ops,
+    };
+    // make_adder(n) = |x| x + n
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            func(
+                0,
+                vec![
+                    Op::Const(5),
+                    Op::Const(10),
+                    Op::Call(1),
+                    Op::PopR(3),
+                    Op::CallClosure,
+                    Op::PopR(4),
+                ],
+            ),
+            func(1, vec![Op::ArgLoad(0), Op::MakeClosure(2, 1)]),
+            func(2, vec![Op::ArgLoad(1), Op::CaptureLoad(0), Op::Add]),
+        ],
+    };
+    assert_eq!(llang.funcs[0].max_stack(), Some(4));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(15));
+}
@@ -0,0 +1,328 @@
+use crate::llang::{Func, LLang, Op};
+use crate::program::DecodeError;
+use crate::varint::{read_varint, unzigzag, write_varint, zigzag};
+use std::io::{self, Read, Write};
+
+// LLangをそのまま書き出す形式。Cmdの形式とは別に版を管理する
+// 関数名や関数単位のジャンプ先を残すので、Cmdへの変換は読み込んだ側でできる
+const MAGIC: &[u8; 4] = b"SVL\0";
+const VERSION: u8 = 1;
+
+// 1バイトのオペコードに続けてオペランドをLEB128で並べる。Constの値はzigzag符号化し、文字列は長さを前置する
+// オペコードはCmdのものとは独立に振る
+impl Op {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        let (opcode, operands): (u8, &[u64]) = match *self {
+            Op::Call(x) => (0, &[x as u64]),
+            Op::CallName(ref name) => {
+                bytes.push(1);
+                write_str(bytes, name);
+                return;
+            }
+            Op::LocalLoad(x) => (2, &[x as u64]),
+            Op::LocalStore(x) => (3, &[x as u64]),
+            Op::ArgLoad(x) => (4, &[x as u64]),
+            Op::ArgStore(x) => (5, &[x as u64]),
+            Op::Const(x) => (6, &[zigzag(x)]),
+            Op::Add => (7, &[]),
+            Op::Sub => (8, &[]),
+            Op::Mul => (9, &[]),
+            Op::Div => (10, &[]),
+            Op::Mod => (11, &[]),
+            Op::Eq => (12, &[]),
+            Op::Ne => (13, &[]),
+            Op::Lt => (14, &[]),
+            Op::Le => (15, &[]),
+            Op::Gt => (16, &[]),
+            Op::Ge => (17, &[]),
+            Op::JumpIf(x) => (18, &[x as u64]),
+            Op::Jump(x) => (19, &[x as u64]),
+            Op::PopR(x) => (20, &[x as u64]),
+            Op::Alloc(x) => (21, &[x as u64]),
+            Op::HeapLoad => (22, &[]),
+            Op::HeapStore => (23, &[]),
+            Op::StrConst(x) => (24, &[x as u64]),
+            Op::StrConcat => (25, &[]),
+            Op::StrLen => (26, &[]),
+            Op::StrEq => (27, &[]),
+            Op::NativeCall(x, y) => (28, &[x as u64, y as u64]),
+            Op::Print => (29, &[]),
+            Op::ReadInt => (30, &[]),
+            Op::And => (31, &[]),
+            Op::Or => (32, &[]),
+            Op::Not => (33, &[]),
+            Op::Xor => (34, &[]),
+            Op::Shl => (35, &[]),
+            Op::Shr => (36, &[]),
+            Op::Dup => (37, &[]),
+            Op::Swap => (38, &[]),
+            Op::Pop => (39, &[]),
+            Op::FuncRef(x) => (40, &[x as u64]),
+            Op::CallIndirect => (41, &[]),
+            Op::MakeClosure(x, y) => (42, &[x as u64, y as u64]),
+            Op::CallClosure => (43, &[]),
+            Op::CaptureLoad(x) => (44, &[x as u64]),
+        };
+        bytes.push(opcode);
+        for x in operands {
+            write_varint(bytes, *x);
+        }
+    }
+
+    fn decode(bytes: &mut &[u8]) -> Result<Op, DecodeError> {
+        let (&opcode, rest) = bytes.split_first().ok_or(DecodeError::UnexpectedEof)?;
+        *bytes = rest;
+        let mut operand = || read_len(bytes).map(|x| x as usize);
+        Ok(match opcode {
+            0 => Op::Call(operand()?),
+            1 => Op::CallName(read_str(bytes)?),
+            2 => Op::LocalLoad(operand()?),
+            3 => Op::LocalStore(operand()?),
+            4 => Op::ArgLoad(operand()?),
+            5 => Op::ArgStore(operand()?),
+            6 => Op::Const(unzigzag(read_len(bytes)?)),
+            7 => Op::Add,
+            8 => Op::Sub,
+            9 => Op::Mul,
+            10 => Op::Div,
+            11 => Op::Mod,
+            12 => Op::Eq,
+            13 => Op::Ne,
+            14 => Op::Lt,
+            15 => Op::Le,
+            16 => Op::Gt,
+            17 => Op::Ge,
+            18 => Op::JumpIf(operand()?),
+            19 => Op::Jump(operand()?),
+            20 => Op::PopR(operand()?),
+            21 => Op::Alloc(operand()?),
+            22 => Op::HeapLoad,
+            23 => Op::HeapStore,
+            24 => Op::StrConst(operand()?),
+            25 => Op::StrConcat,
+            26 => Op::StrLen,
+            27 => Op::StrEq,
+            28 => Op::NativeCall(operand()?, operand()?),
+            29 => Op::Print,
+            30 => Op::ReadInt,
+            31 => Op::And,
+            32 => Op::Or,
+            33 => Op::Not,
+            34 => Op::Xor,
+            35 => Op::Shl,
+            36 => Op::Shr,
+            37 => Op::Dup,
+            38 => Op::Swap,
+            39 => Op::Pop,
+            40 => Op::FuncRef(operand()?),
+            41 => Op::CallIndirect,
+            42 => Op::MakeClosure(operand()?, operand()?),
+            43 => Op::CallClosure,
+            44 => Op::CaptureLoad(operand()?),
+            _ => return Err(DecodeError::InvalidOpcode(opcode)),
+        })
+    }
+}
+
+fn read_len(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+    read_varint(bytes).ok_or(DecodeError::UnexpectedEof)
+}
+
+fn write_str(bytes: &mut Vec<u8>, s: &str) {
+    write_varint(bytes, s.len() as u64);
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn read_str(bytes: &mut &[u8]) -> Result<String, DecodeError> {
+    let len = read_len(bytes)? as usize;
+    if bytes.len() < len {
+        return Err(DecodeError::UnexpectedEof);
+    }
+    let (s, rest) = bytes.split_at(len);
+    *bytes = rest;
+    String::from_utf8(s.to_vec()).map_err(|_| DecodeError::InvalidString)
+}
+
+// マジック、バージョン、エントリの関数ID、関数の数に続けて、関数ごとに
+// ID、名前の有無(0か1)と名前、ローカル変数の数、命令数、命令列を並べる
+impl LLang {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_varint(&mut bytes, self.entry as u64);
+        write_varint(&mut bytes, self.funcs.len() as u64);
+        for func in &self.funcs {
+            write_varint(&mut bytes, func.id as u64);
+            match &func.name {
+                Some(name) => {
+                    bytes.push(1);
+                    write_str(&mut bytes, name);
+                }
+                None => bytes.push(0),
+            }
+            write_varint(&mut bytes, func.local_count as u64);
+            write_varint(&mut bytes, func.ops.len() as u64);
+            for op in &func.ops {
+                op.encode(&mut bytes);
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<LLang, DecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(DecodeError::BadMagic);
+        }
+        let mut bytes = &bytes[MAGIC.len()..];
+        match bytes.split_first() {
+            Some((&VERSION, rest)) => bytes = rest,
+            Some((&version, _)) => return Err(DecodeError::UnsupportedVersion(version)),
+            None => return Err(DecodeError::UnexpectedEof),
+        }
+        let entry = read_len(&mut bytes)? as usize;
+        let mut funcs = Vec::new();
+        for _ in 0..read_len(&mut bytes)? {
+            let id = read_len(&mut bytes)? as usize;
+            let name = match bytes.split_first() {
+                Some((&0, rest)) => {
+                    bytes = rest;
+                    None
+                }
+                Some((&1, rest)) => {
+                    bytes = rest;
+                    Some(read_str(&mut bytes)?)
+                }
+                Some((&tag, _)) => return Err(DecodeError::InvalidOpcode(tag)),
+                None => return Err(DecodeError::UnexpectedEof),
+            };
+            let local_count = read_len(&mut bytes)? as usize;
+            let mut ops = Vec::new();
+            for _ in 0..read_len(&mut bytes)? {
+                ops.push(Op::decode(&mut bytes)?);
+            }
+            // 飛び先は関数内の命令か、末尾のRet(ops.len())
+            for op in &ops {
+                if let Op::Jump(x) | Op::JumpIf(x) = *op {
+                    if x > ops.len() {
+                        return Err(DecodeError::InvalidJump(x));
+                    }
+                }
+            }
+            funcs.push(Func {
+                id,
+                name,
+                local_count,
+                ops,
+            });
+        }
+        Ok(LLang { entry, funcs })
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<LLang, DecodeError> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| DecodeError::Io(e.kind()))?;
+        LLang::from_bytes(&bytes)
+    }
+}
+
+#[test]
+fn test() {
+    use crate::genprog::{generate, ALL_WORKLOADS};
+    use crate::vm::VM;
+
+    for &workload in &ALL_WORKLOADS {
+        let llang = generate(workload, 5);
+        let mut bytes = Vec::new();
+        llang.write(&mut bytes).unwrap();
+        assert_eq!(&bytes[..5], b"SVL\0\x01");
+        let loaded = LLang::read(&bytes[..]).unwrap();
+        assert_eq!(loaded, llang);
+        assert_eq!(
+            VM::new(loaded.convert()).run(),
+            VM::new(llang.convert()).run()
+        );
+    }
+
+    let llang = LLang {
+        entry: 3,
+        funcs: vec![
+            Func {
+                id: 3,
+                name: Some("main".to_string()),
+                local_count: 2,
+                ops: vec![
+                    Op::Const(i64::MIN),
+                    Op::CallName("関数".to_string()),
+                    Op::NativeCall(1, 300),
+                    Op::JumpIf(0),
+                ],
+            },
+            Func {
+                id: 1 << 40,
+                name: None,
+                local_count: 0,
+                ops: vec![
+                    Op::StrConst(2),
+                    Op::ReadInt,
+                    Op::Print,
+                    Op::HeapStore,
+                    Op::And,
+                    Op::Or,
+                    Op::Not,
+                    Op::Xor,
+                    Op::Shl,
+                    Op::Shr,
+                    Op::Dup,
+                    Op::Swap,
+                    Op::Pop,
+                    Op::FuncRef(3),
+                    Op::CallIndirect,
+                    Op::MakeClosure(3, 2),
+                    Op::CallClosure,
+                    Op::CaptureLoad(1),
+                ],
+            },
+        ],
+    };
+    assert_eq!(LLang::from_bytes(&llang.to_bytes()), Ok(llang));
+
+    // Cmdの形式とは混ざらない
+    assert_eq!(
+        LLang::from_bytes(b"SVM\0\x01\x00"),
+        Err(DecodeError::BadMagic)
+    );
+    assert_eq!(
+        LLang::from_bytes(b"SVL\0\x02"),
+        Err(DecodeError::UnsupportedVersion(2))
+    );
+    assert_eq!(
+        LLang::from_bytes(b"SVL\0\x01\x00\x01\x00\x00\x00\x01\xff"),
+        Err(DecodeError::InvalidOpcode(0xff))
+    );
+    assert_eq!(
+        LLang::from_bytes(b"SVL\0\x01\x00\x01\x00\x01\x02\xff\xfe"),
+        Err(DecodeError::InvalidString)
+    );
+    assert_eq!(
+        LLang::from_bytes(b"SVL\0\x01\x00\x01\x00\x00\x00\x02\x06"),
+        Err(DecodeError::UnexpectedEof)
+    );
+    assert_eq!(
+        LLang::from_bytes(b"SVL\0\x01\x00\x01\x00\x00\x00\x01\x13\x02"),
+        Err(DecodeError::InvalidJump(2))
+    );
+    assert_eq!(
+        LLang::from_bytes(
+            b"SVL\0\x01\x00\x01\x00\x00\x00\x01\x13\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"
+        ),
+        Err(DecodeError::InvalidJump(usize::MAX))
+    );
+    // 末尾への飛び先は正しい
+    assert!(LLang::from_bytes(b"SVL\0\x01\x00\x01\x00\x00\x00\x01\x13\x01").is_ok());
+}
@@ -1,6 +1,145 @@
-mod llang;
-mod vm;
+use stack_vm_rs::asm::assemble_with_symbols;
+use stack_vm_rs::explain::ExplainTracer;
+use stack_vm_rs::program::Program;
+use stack_vm_rs::vm::PrintTracer;
+use stack_vm_rs::{VmConfig, VM};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: stack-vm-rs [--trace | --explain] [--stack-size N] FILE";
+
+#[derive(Debug, PartialEq)]
+struct Options {
+    path: String,
+    trace: bool,
+    // 一命令ごとに何をしたかを文で書き出す
+    explain: bool,
+    stack_size: Option<usize>,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut path = None;
+    let mut trace = false;
+    let mut explain = false;
+    let mut stack_size = None;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => trace = true,
+            "--explain" => explain = true,
+            "--stack-size" => {
+                let n = args.next().ok_or("--stack-size needs a value")?;
+                stack_size = Some(
+                    n.parse()
+                        .map_err(|_| format!("invalid stack size `{}`", n))?,
+                );
+            }
+            _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+            _ if path.is_none() => path = Some(arg),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+    if trace && explain {
+        return Err("--trace and --explain cannot be used together".to_string());
+    }
+    Ok(Options {
+        path: path.ok_or("no input file")?,
+        trace,
+        explain,
+        stack_size,
+    })
+}
+
+// バイナリ形式ならそのまま、そうでなければアセンブリとして読む。.includeはpathのあるディレクトリから探す
+// アセンブリならラベルの名前も返す
+fn load(path: &str) -> Result<(Program, BTreeMap<usize, String>), String> {
+    let bytes = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if bytes.starts_with(b"SVM\0") {
+        return Program::from_bytes(&bytes)
+            .map(|program| (program, BTreeMap::new()))
+            .map_err(|e| format!("{}: {}", path, e));
+    }
+    let source = String::from_utf8(bytes).map_err(|_| format!("{}: not utf-8", path))?;
+    let dir = Path::new(path).parent().unwrap_or_else(|| Path::new(""));
+    let loader =
+        |name: &str| fs::read_to_string(dir.join(name)).map_err(|e| format!("{}: {}", name, e));
+    assemble_with_symbols(&source, loader)
+        .map(|(cmds, symbols)| (Program::new(cmds), symbols.labels))
+        .map_err(|e| format!("{}:{}", path, e))
+}
 
 fn main() {
-    println!("Hello, world!");
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    let (program, labels) = match load(&options.path) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    let mut config = VmConfig::new();
+    if let Some(size) = options.stack_size {
+        config = config.max_stack_size(size);
+    }
+    let mut vm = VM::from_program(program, config);
+    if options.trace {
+        vm.set_tracer(Box::new(PrintTracer));
+    }
+    if options.explain {
+        vm.set_tracer(Box::new(ExplainTracer::new(labels, |pc, text| {
+            println!("{:>5}  {}", pc, text)
+        })));
+    }
+    match vm.run() {
+        Ok(result) => println!("{}", result),
+        Err(e) => {
+            eprintln!("{}", vm.error_report(&e));
+            process::exit(1);
+        }
+    }
+}
+
+#[test]
+fn test_parse_args() {
+    let parse = |args: &[&str]| parse_args(args.iter().map(|s| s.to_string()));
+    assert_eq!(
+        parse(&["--trace", "a.s", "--stack-size", "4096"]),
+        Ok(Options {
+            path: "a.s".to_string(),
+            trace: true,
+            explain: false,
+            stack_size: Some(4096),
+        })
+    );
+    assert_eq!(
+        parse(&["--explain", "a.s"]).map(|options| options.explain),
+        Ok(true)
+    );
+    assert_eq!(
+        parse(&["--explain", "--trace", "a.s"]),
+        Err("--trace and --explain cannot be used together".to_string())
+    );
+    assert_eq!(parse(&[]), Err("no input file".to_string()));
+    assert_eq!(
+        parse(&["--stack-size", "x", "a.s"]),
+        Err("invalid stack size `x`".to_string())
+    );
+    assert_eq!(
+        parse(&["--fast"]),
+        Err("unknown option `--fast`".to_string())
+    );
+    assert_eq!(
+        parse(&["a.s", "b.s"]),
+        Err("unexpected argument `b.s`".to_string())
+    );
 }
@@ -0,0 +1,92 @@
+use crate::vm::Value;
+use std::collections::HashMap;
+
+// 関数の先頭アドレスと引数をキーに戻り値を覚える。capacityを超えたら最も長く使われていないものから捨てる
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoCache {
+    capacity: usize,
+    // 値と最後に使った時刻
+    entries: HashMap<(usize, Vec<Value>), (Value, u64)>,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl MemoCache {
+    pub fn new(capacity: usize) -> MemoCache {
+        MemoCache {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, func: usize, args: &[Value]) -> Option<Value> {
+        self.clock += 1;
+        match self.entries.get_mut(&(func, args.to_vec())) {
+            Some((value, used)) => {
+                *used = self.clock;
+                self.hits += 1;
+                Some(*value)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn insert(&mut self, func: usize, args: Vec<Value>, value: Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&(func, args.clone()))
+        {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, used))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.clock += 1;
+        self.entries.insert((func, args), (value, self.clock));
+    }
+
+    // 覚えている引数と戻り値。ヒープのアドレスなら回収されては困る
+    pub fn values(&self) -> impl Iterator<Item = Value> + '_ {
+        self.entries
+            .iter()
+            .flat_map(|((_, args), (value, _))| args.iter().cloned().chain(Some(*value)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[test]
+fn test() {
+    let mut cache = MemoCache::new(2);
+    cache.insert(1, vec![1], 10);
+    cache.insert(1, vec![2], 20);
+    assert_eq!(cache.get(1, &[1]), Some(10));
+    cache.insert(1, vec![3], 30);
+    // 最近使っていない[2]が捨てられる
+    assert_eq!(cache.get(1, &[2]), None);
+    assert_eq!(cache.get(1, &[1]), Some(10));
+    assert_eq!(cache.get(1, &[3]), Some(30));
+    assert_eq!(cache.get(2, &[3]), None);
+    assert_eq!((cache.hits, cache.misses, cache.len()), (3, 2, 2));
+    let mut values = cache.values().collect::<Vec<_>>();
+    values.sort();
+    assert_eq!(values, vec![1, 3, 10, 30]);
+}
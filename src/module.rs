@@ -0,0 +1,362 @@
+use crate::llang::{Func, LLang, Op};
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
+
+// 別々にコンパイルする単位。関数IDと関数名はモジュールの中だけで通じる
+// 他のモジュールの関数は、名前で取り込んでこのモジュールの関数IDを割り当ててから呼ぶ
+// Op::CallNameはこのモジュールの関数名か取り込んだ名前で解決する
+#[derive(Clone, Debug, PartialEq)]
+pub struct Module {
+    pub name: String,
+    pub funcs: Vec<Func>,
+    // 公開する名前から関数IDへ
+    pub exports: BTreeMap<String, usize>,
+    // 取り込む名前から、このモジュール内でその関数を指す関数IDへ
+    pub imports: BTreeMap<String, usize>,
+}
+
+impl Module {
+    pub fn new(name: &str, funcs: Vec<Func>) -> Module {
+        Module {
+            name: name.to_string(),
+            funcs,
+            exports: BTreeMap::new(),
+            imports: BTreeMap::new(),
+        }
+    }
+
+    pub fn export(mut self, name: &str, id: usize) -> Module {
+        self.exports.insert(name.to_string(), id);
+        self
+    }
+
+    pub fn import(mut self, name: &str, id: usize) -> Module {
+        self.imports.insert(name.to_string(), id);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LinkError {
+    // 同じ名前を複数のモジュールが公開した
+    DuplicateExport(String),
+    UnresolvedImport { module: String, name: String },
+    // モジュール内に定義も取り込みもない関数IDを参照した
+    UndefinedFunction { module: String, id: usize },
+    // Op::CallNameの名前がモジュール内の関数名にも取り込みにもない
+    UndefinedName { module: String, name: String },
+    // 関数IDが関数と取り込みで重なった
+    DuplicateFunction { module: String, id: usize },
+    UndefinedEntry(String),
+}
+
+impl fmt::Display for LinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LinkError::DuplicateExport(name) => write!(f, "`{}` is exported twice", name),
+            LinkError::UnresolvedImport { module, name } => {
+                write!(f, "{}: unresolved import `{}`", module, name)
+            }
+            LinkError::UndefinedFunction { module, id } => {
+                write!(f, "{}: undefined function id {}", module, id)
+            }
+            LinkError::UndefinedName { module, name } => {
+                write!(f, "{}: undefined function `{}`", module, name)
+            }
+            LinkError::DuplicateFunction { module, id } => {
+                write!(f, "{}: duplicate function id {}", module, id)
+            }
+            LinkError::UndefinedEntry(name) => write!(f, "entry `{}` is not exported", name),
+        }
+    }
+}
+
+impl error::Error for LinkError {}
+
+// 複数のモジュールを一つのLLangにまとめる
+// 関数IDは追加した順に0から振り直し、取り込みを公開元の関数に結びつける
+// 関数名はモジュール名::関数名にするので、別のモジュールの同じ名前とはぶつからない
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Linker {
+    modules: Vec<Module>,
+}
+
+impl Linker {
+    pub fn new() -> Linker {
+        Linker::default()
+    }
+
+    pub fn add(&mut self, module: Module) -> &mut Linker {
+        self.modules.push(module);
+        self
+    }
+
+    // 公開名がentryの関数をエントリにする
+    pub fn link(&self, entry: &str) -> Result<LLang, LinkError> {
+        // モジュールごとの、元の関数IDから振り直した関数IDへの対応
+        let mut ids = Vec::new();
+        let mut next = 0;
+        for module in &self.modules {
+            let mut map = HashMap::new();
+            for func in &module.funcs {
+                if map.insert(func.id, next).is_some() {
+                    return Err(LinkError::DuplicateFunction {
+                        module: module.name.clone(),
+                        id: func.id,
+                    });
+                }
+                next += 1;
+            }
+            ids.push(map);
+        }
+
+        let mut exports = HashMap::new();
+        for (module, map) in self.modules.iter().zip(&ids) {
+            for (name, id) in &module.exports {
+                let id = *map.get(id).ok_or_else(|| LinkError::UndefinedFunction {
+                    module: module.name.clone(),
+                    id: *id,
+                })?;
+                if exports.insert(name.as_str(), id).is_some() {
+                    return Err(LinkError::DuplicateExport(name.clone()));
+                }
+            }
+        }
+
+        for (module, map) in self.modules.iter().zip(&mut ids) {
+            for (name, id) in &module.imports {
+                let target =
+                    *exports
+                        .get(name.as_str())
+                        .ok_or_else(|| LinkError::UnresolvedImport {
+                            module: module.name.clone(),
+                            name: name.clone(),
+                        })?;
+                if map.insert(*id, target).is_some() {
+                    return Err(LinkError::DuplicateFunction {
+                        module: module.name.clone(),
+                        id: *id,
+                    });
+                }
+            }
+        }
+
+        let mut funcs = Vec::new();
+        for (module, map) in self.modules.iter().zip(&ids) {
+            let resolve = |id: &usize| {
+                map.get(id)
+                    .cloned()
+                    .ok_or_else(|| LinkError::UndefinedFunction {
+                        module: module.name.clone(),
+                        id: *id,
+                    })
+            };
+            let resolve_name = |name: &String| {
+                let id = match module.funcs.iter().find(|f| f.name.as_ref() == Some(name)) {
+                    Some(func) => func.id,
+                    None => *module
+                        .imports
+                        .get(name)
+                        .ok_or_else(|| LinkError::UndefinedName {
+                            module: module.name.clone(),
+                            name: name.clone(),
+                        })?,
+                };
+                resolve(&id)
+            };
+            for func in &module.funcs {
+                let mut ops = Vec::new();
+                for op in &func.ops {
+                    ops.push(match op {
+                        Op::Call(id) => Op::Call(resolve(id)?),
+                        Op::CallName(name) => Op::Call(resolve_name(name)?),
+                        Op::FuncRef(id) => Op::FuncRef(resolve(id)?),
+                        Op::MakeClosure(id, n) => Op::MakeClosure(resolve(id)?, *n),
+                        op => op.clone(),
+                    });
+                }
+                funcs.push(Func {
+                    id: map[&func.id],
+                    name: func
+                        .name
+                        .as_ref()
+                        .map(|name| format!("{}::{}", module.name, name)),
+                    local_count: func.local_count,
+                    ops,
+                });
+            }
+        }
+
+        Ok(LLang {
+            entry: *exports
+                .get(entry)
+                .ok_or_else(|| LinkError::UndefinedEntry(entry.to_string()))?,
+            funcs,
+        })
+    }
+}
+
+#[test]
+fn test() {
+    use crate::vm::VM;
+
+    let func = |id, ops| Func {
+        id,
+        name: None,
+        local_count: 0,
+        ops,
+    };
+    // 標準ライブラリ: square(x) = x * x, sum_sq(x, y) = square(x) + square(y)
+    let std = Module::new(
+        "std",
+        vec![
+            func(0, vec![Op::ArgLoad(0), Op::ArgLoad(0), Op::Mul]),
+            func(
+                1,
+                vec![
+                    Op::ArgLoad(0),
+                    Op::Call(0),
+                    Op::PopR(3),
+                    Op::ArgLoad(1),
+                    Op::Call(0),
+                    Op::PopR(3),
+                    Op::Add,
+                ],
+            ),
+        ],
+    )
+    .export("square", 0)
+    .export("sum_sq", 1);
+    // main() = sum_sq(3, 4) - square(2)
+    let user = Module::new(
+        "user",
+        vec![func(
+            0,
+            vec![
+                Op::Const(2),
+                Op::Call(5),
+                Op::PopR(3),
+                Op::Const(4),
+                Op::Const(3),
+                Op::Call(7),
+                Op::PopR(4),
+                Op::Sub,
+            ],
+        )],
+    )
+    .export("main", 0)
+    .import("square", 5)
+    .import("sum_sq", 7);
+
+    let llang = Linker::new()
+        .add(user.clone())
+        .add(std.clone())
+        .link("main")
+        .unwrap();
+    assert_eq!(llang.entry, 0);
+    assert_eq!(llang.funcs[0].ops[1], Op::Call(1));
+    assert_eq!(llang.funcs[0].ops[5], Op::Call(2));
+    assert_eq!(llang.funcs[2].ops[1], Op::Call(1));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(21));
+    let llang = Linker::new()
+        .add(std.clone())
+        .add(user.clone())
+        .link("main");
+    assert_eq!(VM::new(llang.unwrap().convert()).run(), Ok(21));
+
+    assert_eq!(
+        Linker::new().add(user.clone()).link("main"),
+        Err(LinkError::UnresolvedImport {
+            module: "user".to_string(),
+            name: "square".to_string()
+        })
+    );
+    assert_eq!(
+        Linker::new()
+            .add(std.clone())
+            .add(std.clone())
+            .link("square"),
+        Err(LinkError::DuplicateExport("square".to_string()))
+    );
+    assert_eq!(
+        Linker::new().add(std.clone()).link("main"),
+        Err(LinkError::UndefinedEntry("main".to_string()))
+    );
+    let broken = Module::new("broken", vec![func(0, vec![Op::Call(9)])]).export("f", 0);
+    assert_eq!(
+        Linker::new()
+            .add(broken)
+            .link("f")
+            .map_err(|e| e.to_string()),
+        Err("broken: undefined function id 9".to_string())
+    );
+    let clash = Module::new("clash", vec![func(0, vec![])])
+        .export("f", 0)
+        .import("square", 0);
+    assert_eq!(
+        Linker::new().add(std.clone()).add(clash).link("f"),
+        Err(LinkError::DuplicateFunction {
+            module: "clash".to_string(),
+            id: 0
+        })
+    );
+
+    // 名前で呼ぶと、同じモジュールの関数か取り込んだ関数になる
+    let named = |id, name: &str, ops| Func {
+        id,
+        name: Some(name.to_string()),
+        local_count: 0,
+        ops,
+    };
+    let call_helper = vec![Op::CallName("helper".to_string()), Op::PopR(2)];
+    let a = Module::new(
+        "a",
+        vec![
+            named(0, "helper", vec![Op::Const(10)]),
+            func(1, call_helper.clone()),
+        ],
+    )
+    .export("a", 1);
+    // main() = a() + b() + square(2)
+    let b = Module::new(
+        "b",
+        vec![
+            named(0, "helper", vec![Op::Const(20)]),
+            func(
+                1,
+                vec![
+                    Op::CallName("helper".to_string()),
+                    Op::PopR(2),
+                    Op::CallName("a".to_string()),
+                    Op::PopR(2),
+                    Op::Add,
+                    Op::Const(2),
+                    Op::CallName("square".to_string()),
+                    Op::PopR(3),
+                    Op::Add,
+                ],
+            ),
+        ],
+    )
+    .export("main", 1)
+    .import("a", 5)
+    .import("square", 6);
+    let llang = Linker::new()
+        .add(a.clone())
+        .add(b)
+        .add(std.clone())
+        .link("main")
+        .unwrap();
+    assert_eq!(llang.funcs[2].name, Some("b::helper".to_string()));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(34));
+
+    let stray = Module::new("stray", vec![func(0, call_helper)]).export("f", 0);
+    assert_eq!(
+        Linker::new().add(a).add(stray).link("f"),
+        Err(LinkError::UndefinedName {
+            module: "stray".to_string(),
+            name: "helper".to_string()
+        })
+    );
+}
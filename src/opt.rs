@@ -0,0 +1,684 @@
+use crate::llang::{Func, LLang, Op};
+use crate::pass::Pass;
+use crate::vm::Value;
+
+// 各命令の実行直前に生きている(後で読まれうる)ローカル変数
+pub fn live_locals(func: &Func) -> Vec<Vec<bool>> {
+    let mut live_in = vec![vec![false; func.local_count]; func.ops.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (i, op) in func.ops.iter().enumerate().rev() {
+            // 関数外へのジャンプはRetなので何も生きていない
+            let mut live = vec![false; func.local_count];
+            for succ in op.successors(i) {
+                if let Some(succ_live) = live_in.get(succ) {
+                    for (x, y) in live.iter_mut().zip(succ_live) {
+                        *x |= *y;
+                    }
+                }
+            }
+            match op {
+                Op::LocalStore(x) if *x < func.local_count => live[*x] = false,
+                Op::LocalLoad(x) if *x < func.local_count => live[*x] = true,
+                _ => {}
+            }
+            if live != live_in[i] {
+                live_in[i] = live;
+                changed = true;
+            }
+        }
+    }
+    live_in
+}
+
+// 使われていないローカル変数のスロットを詰め、減らしたスロット数を返す
+pub fn compact_locals(func: &mut Func) -> usize {
+    let mut used = vec![false; func.local_count];
+    for op in &func.ops {
+        match op {
+            Op::LocalLoad(x) | Op::LocalStore(x) if *x < func.local_count => used[*x] = true,
+            _ => {}
+        }
+    }
+    let mut slots = Vec::with_capacity(func.local_count);
+    let mut count = 0;
+    for used in used {
+        slots.push(count);
+        if used {
+            count += 1;
+        }
+    }
+    for op in &mut func.ops {
+        match op {
+            Op::LocalLoad(x) | Op::LocalStore(x) if *x < func.local_count => *x = slots[*x],
+            _ => {}
+        }
+    }
+    let saved = func.local_count - count;
+    func.local_count = count;
+    saved
+}
+
+// 後で読まれないLocalStoreを消し、使われなくなったローカル変数のスロットを詰める
+// 値を捨てる命令がないので、格納する値が直前のConst/LocalLoad/ArgLoadで積まれている場合だけその命令ごと消す
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeadStoreElimination {
+    pub removed_stores: usize,
+    pub saved_slots: usize,
+}
+
+impl DeadStoreElimination {
+    pub fn new() -> DeadStoreElimination {
+        DeadStoreElimination::default()
+    }
+
+    fn run_func(&mut self, func: &mut Func) -> bool {
+        let mut changed = false;
+        while let Some(i) = find_dead_store(func) {
+            func.remove_ops(i - 1, i + 1);
+            self.removed_stores += 1;
+            changed = true;
+        }
+        let saved = compact_locals(func);
+        self.saved_slots += saved;
+        changed || saved != 0
+    }
+}
+
+fn find_dead_store(func: &Func) -> Option<usize> {
+    let live = live_locals(func);
+    (1..func.ops.len()).find(|&i| {
+        let dead = match func.ops[i] {
+            Op::LocalStore(x) => live
+                .get(i + 1)
+                .map(|live| !live.get(x).cloned().unwrap_or(false))
+                .unwrap_or(true),
+            _ => false,
+        };
+        let pure_push = matches!(
+            func.ops[i - 1],
+            Op::Const(_) | Op::LocalLoad(_) | Op::ArgLoad(_)
+        );
+        let is_jump_target = func
+            .ops
+            .iter()
+            .any(|op| matches!(op, Op::Jump(x) | Op::JumpIf(x) if *x == i));
+        dead && pure_push && !is_jump_target
+    })
+}
+
+impl Pass for DeadStoreElimination {
+    fn name(&self) -> &'static str {
+        "dead-store-elimination"
+    }
+
+    fn run(&mut self, llang: &mut LLang) -> bool {
+        let mut changed = false;
+        for func in &mut llang.funcs {
+            changed |= self.run_func(func);
+        }
+        changed
+    }
+}
+
+// 同時に生きることのないローカル変数を同じスロットにまとめ、減らしたスロット数を返す
+pub fn coalesce_locals(func: &mut Func) -> usize {
+    let n = func.local_count;
+    if n == 0 {
+        return 0;
+    }
+    let live = live_locals(func);
+    let mut interfere = vec![vec![false; n]; n];
+    let mut add = |x: usize, y: usize| {
+        if x != y {
+            interfere[x][y] = true;
+            interfere[y][x] = true;
+        }
+    };
+    for (i, op) in func.ops.iter().enumerate() {
+        let mut live_out = vec![false; n];
+        for succ in op.successors(i) {
+            if let Some(succ_live) = live.get(succ) {
+                for (x, y) in live_out.iter_mut().zip(succ_live) {
+                    *x |= *y;
+                }
+            }
+        }
+        for x in 0..n {
+            for y in 0..n {
+                if live[i][x] && live[i][y] {
+                    add(x, y);
+                }
+            }
+        }
+        if let Op::LocalStore(x) = op {
+            if *x < n {
+                for (y, live) in live_out.iter().enumerate() {
+                    if *live {
+                        add(*x, y);
+                    }
+                }
+            }
+        }
+    }
+    // 書き込まれる前に読まれるローカル変数は、他の変数の値が見えてしまわないよう何とも共有しない
+    if let Some(entry_live) = live.first() {
+        for (x, live) in entry_live.iter().enumerate() {
+            if *live {
+                for y in 0..n {
+                    add(x, y);
+                }
+            }
+        }
+    }
+
+    let mut colors: Vec<usize> = Vec::with_capacity(n);
+    for row in &interfere {
+        let color = (0..)
+            .find(|c| colors.iter().zip(row).all(|(color, i)| !i || color != c))
+            .unwrap();
+        colors.push(color);
+    }
+    for op in &mut func.ops {
+        match op {
+            Op::LocalLoad(x) | Op::LocalStore(x) if *x < n => *x = colors[*x],
+            _ => {}
+        }
+    }
+    let count = colors.iter().max().map(|c| c + 1).unwrap_or(0);
+    func.local_count = count;
+    n - count
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocalCoalescing {
+    pub saved_slots: usize,
+}
+
+impl LocalCoalescing {
+    pub fn new() -> LocalCoalescing {
+        LocalCoalescing::default()
+    }
+}
+
+impl Pass for LocalCoalescing {
+    fn name(&self) -> &'static str {
+        "local-coalescing"
+    }
+
+    fn run(&mut self, llang: &mut LLang) -> bool {
+        let mut saved = 0;
+        for func in &mut llang.funcs {
+            saved += coalesce_locals(func);
+        }
+        self.saved_slots += saved;
+        saved != 0
+    }
+}
+
+// 関数の末尾での自分自身の呼び出しを、引数を書き換えて先頭へ戻るループにする
+// 呼び出し直前のオペランドスタックに引数だけが積まれている場合に限る
+// 呼び出し結果にAddかMulをひとつ適用して返す形(n * f(n - 1)など)は、その演算の累積用のローカル変数を足してループにし、
+// 戻るときに累積値を適用する。並べ替えるのでchecked演算ではオーバーフローを検出する位置が変わりうる
+// 新しいフレームを作らないので、書き込む前に読むローカル変数には前の周回の値が残る
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TailCallElimination {
+    // 書き換えた関数のID
+    pub rewritten: Vec<usize>,
+    skip: Vec<usize>,
+}
+
+impl TailCallElimination {
+    pub fn new() -> TailCallElimination {
+        TailCallElimination::default()
+    }
+
+    // idの関数は書き換えない
+    pub fn skip(mut self, id: usize) -> TailCallElimination {
+        self.skip.push(id);
+        self
+    }
+}
+
+// 自己再帰呼び出しの形
+#[derive(Clone, Debug, PartialEq)]
+enum Recursion {
+    // f(..)をそのまま返す
+    Tail,
+    // x OP f(..)。xは引数の下に積まれている
+    Before(Op),
+    // f(..) OP x。xは値をひとつ積む命令
+    After(Op, Op),
+}
+
+impl Recursion {
+    fn op(&self) -> Option<&Op> {
+        match self {
+            Recursion::Tail => None,
+            Recursion::Before(op) | Recursion::After(_, op) => Some(op),
+        }
+    }
+
+    // 置き換える命令数
+    fn len(&self) -> usize {
+        match self {
+            Recursion::Tail => 2,
+            Recursion::Before(_) => 3,
+            Recursion::After(_, _) => 4,
+        }
+    }
+}
+
+// 累積の演算の単位元
+fn identity(op: &Op) -> Value {
+    match op {
+        Op::Mul => 1,
+        _ => 0,
+    }
+}
+
+// 自己再帰呼び出しのCallのインデックスと形、引数の数
+fn find_recursion(func: &Func) -> Option<(usize, Recursion, usize)> {
+    let arity = func
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::ArgLoad(x) | Op::ArgStore(x) => Some(x + 1),
+            Op::CaptureLoad(_) => Some(1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let depths = func.stack_depths()?;
+    let len = func.ops.len();
+    let returns = |i: usize| i == len || func.ops.get(i) == Some(&Op::Jump(len));
+    let calls_self = |op: &Op| match op {
+        Op::Call(id) => *id == func.id,
+        Op::CallName(name) => func.name.as_ref() == Some(name),
+        _ => false,
+    };
+    let accumulates = |op: Option<&Op>| matches!(op, Some(Op::Add) | Some(Op::Mul));
+    let loads = |op: Option<&Op>| {
+        matches!(
+            op,
+            Some(Op::Const(_)) | Some(Op::ArgLoad(_)) | Some(Op::LocalLoad(_))
+        )
+    };
+    let recursion = |i: usize| {
+        if !calls_self(&func.ops[i]) || !matches!(func.ops[i + 1], Op::PopR(_)) {
+            return None;
+        }
+        if returns(i + 2) && depths[i] == Some(arity) {
+            Some(Recursion::Tail)
+        } else if accumulates(func.ops.get(i + 2)) && returns(i + 3) && depths[i] == Some(arity + 1)
+        {
+            Some(Recursion::Before(func.ops[i + 2].clone()))
+        } else if loads(func.ops.get(i + 2))
+            && accumulates(func.ops.get(i + 3))
+            && returns(i + 4)
+            && depths[i] == Some(arity)
+        {
+            Some(Recursion::After(
+                func.ops[i + 2].clone(),
+                func.ops[i + 3].clone(),
+            ))
+        } else {
+            None
+        }
+    };
+    (0..len.saturating_sub(1))
+        .filter_map(|i| recursion(i).map(|r| (i, r, arity)))
+        .find(|(i, r, _)| {
+            !func
+                .ops
+                .iter()
+                .any(|op| matches!(op, Op::Jump(x) | Op::JumpIf(x) if *x > *i && *x < *i + r.len()))
+        })
+}
+
+// 書き換えられる自己再帰呼び出しをすべて書き換える。累積の演算が混ざる場合は書き換えない
+fn eliminate_recursion(func: &mut Func) -> bool {
+    let mut acc_op = None;
+    let mut copy = func.clone();
+    while let Some((i, recursion, _)) = find_recursion(&copy) {
+        if let Some(op) = recursion.op() {
+            if acc_op.get_or_insert_with(|| op.clone()) != op {
+                return false;
+            }
+        }
+        copy.replace_ops(i, i + recursion.len(), vec![Op::Jump(usize::MAX)]);
+    }
+    if copy.ops == func.ops {
+        return false;
+    }
+    // 関数外へ抜けるジャンプは末尾へのものだけにしておく
+    let len = func.ops.len();
+    if func
+        .ops
+        .iter()
+        .any(|op| matches!(op, Op::Jump(x) | Op::JumpIf(x) if *x > len))
+    {
+        return false;
+    }
+
+    let acc = func.local_count;
+    while let Some((i, recursion, arity)) = find_recursion(func) {
+        // スタックトップがArgLoad(0)になる
+        let stores = (0..arity).map(Op::ArgStore);
+        let fold = |op: &Op| vec![Op::LocalLoad(acc), op.clone(), Op::LocalStore(acc)];
+        let ops = match &recursion {
+            Recursion::Tail => stores.collect(),
+            Recursion::Before(op) => stores.chain(fold(op)).collect(),
+            Recursion::After(x, op) => {
+                let mut ops = vec![x.clone()];
+                ops.extend(fold(op));
+                ops.extend(stores);
+                ops
+            }
+        };
+        let mut ops = ops;
+        ops.push(Op::Jump(0));
+        func.replace_ops(i, i + recursion.len(), ops);
+    }
+    if let Some(op) = &acc_op {
+        // 戻るときに累積値を適用し、先頭で単位元に初期化する。Jump(0)は初期化の後を指すようになる
+        func.ops.push(Op::LocalLoad(acc));
+        func.ops.push(op.clone());
+        func.replace_ops(0, 0, vec![Op::Const(identity(op)), Op::LocalStore(acc)]);
+        func.local_count += 1;
+    }
+    true
+}
+
+impl Pass for TailCallElimination {
+    fn name(&self) -> &'static str {
+        "tail-call-elimination"
+    }
+
+    fn run(&mut self, llang: &mut LLang) -> bool {
+        let mut changed = false;
+        for func in &mut llang.funcs {
+            if self.skip.contains(&func.id) {
+                continue;
+            }
+            if eliminate_recursion(func) {
+                self.rewritten.push(func.id);
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[test]
+fn test_dead_store_elimination() {
+    use crate::vm::VM;
+
+    let mut llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 4,
+            ops: vec![
+                Op::Const(1),
+                Op::LocalStore(1),
+                Op::Const(2),
+                Op::LocalStore(3),
+                Op::LocalLoad(1),
+                Op::LocalStore(0),
+                Op::Const(5),
+                Op::LocalStore(3),
+                Op::LocalLoad(3),
+            ],
+        }],
+    };
+    let expected = VM::new(llang.convert()).run();
+
+    let mut pass = DeadStoreElimination::new();
+    assert!(pass.run(&mut llang));
+    assert_eq!(
+        llang.funcs[0],
+        Func {
+            id: 0,
+            name: None,
+            local_count: 1,
+            ops: vec![Op::Const(5), Op::LocalStore(0), Op::LocalLoad(0)],
+        }
+    );
+    assert_eq!(pass.removed_stores, 3);
+    assert_eq!(pass.saved_slots, 3);
+    assert_eq!(VM::new(llang.convert()).run(), expected);
+    assert!(!pass.run(&mut llang));
+}
+
+#[test]
+fn test_live_locals() {
+    let func = Func {
+        id: 0,
+        name: None,
+        local_count: 2,
+        ops: vec![
+            Op::Const(1),      // 0
+            Op::LocalStore(0), // 1
+            Op::LocalLoad(1),  // 2
+            Op::JumpIf(0),     // 3
+            Op::LocalLoad(0),  // 4
+        ],
+    };
+    assert_eq!(
+        live_locals(&func),
+        vec![
+            vec![false, true],
+            vec![false, true],
+            vec![true, true],
+            vec![true, true],
+            vec![true, false],
+        ]
+    );
+}
+
+#[test]
+fn test_local_coalescing() {
+    use crate::vm::VM;
+
+    let mut llang = LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: None,
+                local_count: 3,
+                ops: vec![
+                    Op::Const(1),
+                    Op::LocalStore(0),
+                    Op::LocalLoad(0),
+                    Op::Const(2),
+                    Op::LocalStore(1),
+                    Op::LocalLoad(1),
+                    Op::Add,
+                    Op::LocalStore(2),
+                    Op::LocalLoad(2),
+                    Op::Call(1),
+                    Op::PopR(3),
+                ],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 2,
+                ops: vec![
+                    Op::Const(1),
+                    Op::LocalStore(0),
+                    Op::Const(2),
+                    Op::LocalStore(1),
+                    Op::LocalLoad(0),
+                    Op::LocalLoad(1),
+                    Op::Add,
+                    Op::ArgLoad(0),
+                    Op::Add,
+                ],
+            },
+        ],
+    };
+    let expected = VM::new(llang.convert()).run();
+
+    let mut pass = LocalCoalescing::new();
+    assert!(pass.run(&mut llang));
+    assert_eq!(pass.saved_slots, 2);
+    assert_eq!(llang.funcs[0].local_count, 1);
+    assert_eq!(llang.funcs[1].local_count, 2);
+    assert_eq!(VM::new(llang.convert()).run(), expected);
+    assert!(!pass.run(&mut llang));
+}
+
+#[test]
+fn test_tail_call_elimination() {
+    use crate::equiv::check_equivalent;
+    use crate::genprog::{expected, generate, Workload};
+    use crate::vm::VM;
+
+    let gcd = LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::Const(182), Op::Const(1029), Op::Call(1), Op::PopR(2)],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 0,
+                ops: vec![
+                    Op::ArgLoad(0),
+                    Op::Const(0),
+                    Op::Eq,
+                    Op::JumpIf(5),
+                    Op::Jump(7),
+                    Op::ArgLoad(1),
+                    Op::Jump(13),
+                    Op::ArgLoad(0),
+                    Op::ArgLoad(0),
+                    Op::ArgLoad(1),
+                    Op::Mod,
+                    Op::Call(1),
+                    Op::PopR(2),
+                ],
+            },
+        ],
+    };
+    let mut llang = gcd.clone();
+    let mut pass = TailCallElimination::new();
+    assert!(pass.run(&mut llang));
+    assert_eq!(pass.rewritten, vec![1]);
+    assert_eq!(
+        llang.funcs[1].ops[6..],
+        [
+            Op::Jump(14),
+            Op::ArgLoad(0),
+            Op::ArgLoad(0),
+            Op::ArgLoad(1),
+            Op::Mod,
+            Op::ArgStore(0),
+            Op::ArgStore(1),
+            Op::Jump(0),
+        ]
+    );
+    assert_eq!(check_equivalent(&gcd, &llang, 1, 2, 100), Ok(()));
+    assert!(!pass.run(&mut llang));
+
+    let mut llang = gcd.clone();
+    assert!(!TailCallElimination::new().skip(1).run(&mut llang));
+
+    // f(k + 1) + 1は累積値に1を足しながらループする
+    let mut llang = generate(Workload::DeepRecursion, 10);
+    assert!(TailCallElimination::new().run(&mut llang));
+    assert_eq!(llang.funcs[1].local_count, 1);
+    assert!(!llang.funcs[1].ops.contains(&Op::Call(1)));
+    assert_eq!(
+        VM::new(llang.convert()).run(),
+        Ok(expected(Workload::DeepRecursion, 10))
+    );
+
+    // fact(n) = if n == 0 { 1 } else { n * fact(n - 1) }
+    let fact = |n| LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::Const(n), Op::Call(1), Op::PopR(3)],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 0,
+                ops: vec![
+                    Op::ArgLoad(0),
+                    Op::Const(0),
+                    Op::Eq,
+                    Op::JumpIf(12),
+                    Op::ArgLoad(0),
+                    Op::Const(1),
+                    Op::ArgLoad(0),
+                    Op::Sub,
+                    Op::Call(1),
+                    Op::PopR(3),
+                    Op::Mul,
+                    Op::Jump(13),
+                    Op::Const(1),
+                ],
+            },
+        ],
+    };
+    for n in 0..6 {
+        let mut llang = fact(n);
+        let mut pass = TailCallElimination::new();
+        assert!(pass.run(&mut llang));
+        assert_eq!(pass.rewritten, vec![1]);
+        assert_eq!(
+            VM::new(llang.convert()).run(),
+            VM::new(fact(n).convert()).run()
+        );
+    }
+    let mut llang = fact(5);
+    TailCallElimination::new().run(&mut llang);
+    assert_eq!(llang.funcs[1].ops[..2], [Op::Const(1), Op::LocalStore(0)]);
+    assert_eq!(
+        llang.funcs[1].ops[10..],
+        [
+            Op::ArgStore(0),
+            Op::LocalLoad(0),
+            Op::Mul,
+            Op::LocalStore(0),
+            Op::Jump(2),
+            Op::Jump(17),
+            Op::Const(1),
+            Op::LocalLoad(0),
+            Op::Mul,
+        ]
+    );
+
+    // 累積の演算が混ざる場合は書き換えない
+    let mut llang = fact(5);
+    llang.funcs[1].ops[11] = Op::Jump(20);
+    llang.funcs[1].ops.splice(
+        12..,
+        vec![
+            Op::ArgLoad(0),
+            Op::Const(1),
+            Op::ArgLoad(0),
+            Op::Sub,
+            Op::Call(1),
+            Op::PopR(3),
+            Op::Add,
+            Op::Jump(20),
+        ],
+    );
+    assert!(find_recursion(&llang.funcs[1]).is_some());
+    assert!(!TailCallElimination::new().run(&mut llang));
+}
@@ -0,0 +1,159 @@
+use crate::llang::LLang;
+
+pub trait Pass {
+    fn name(&self) -> &'static str;
+
+    // このパスより前に実行されている必要のあるパスの名前
+    fn requires(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    // 変更があればtrueを返す
+    fn run(&mut self, llang: &mut LLang) -> bool;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PipelineError {
+    MissingRequirement {
+        pass: &'static str,
+        requires: &'static str,
+    },
+}
+
+pub struct PipelineBuilder {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl PipelineBuilder {
+    pub fn new() -> PipelineBuilder {
+        PipelineBuilder { passes: Vec::new() }
+    }
+
+    // 演算子のAddではなく、パスを末尾に足す
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(mut self, pass: impl Pass + 'static) -> PipelineBuilder {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    pub fn build(self) -> Result<Pipeline, PipelineError> {
+        for (i, pass) in self.passes.iter().enumerate() {
+            for requires in pass.requires() {
+                if !self.passes[..i].iter().any(|p| p.name() == requires) {
+                    return Err(PipelineError::MissingRequirement {
+                        pass: pass.name(),
+                        requires,
+                    });
+                }
+            }
+        }
+        Ok(Pipeline {
+            passes: self.passes,
+        })
+    }
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> PipelineBuilder {
+        PipelineBuilder::new()
+    }
+}
+
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Pipeline {
+    // 全パスを一度ずつ実行し、変更のあったパスの名前を返す
+    pub fn run(&mut self, llang: &mut LLang) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        for pass in &mut self.passes {
+            if pass.run(llang) {
+                changed.push(pass.name());
+            }
+        }
+        changed
+    }
+
+    // 変更がなくなるまで繰り返し、実行した回数を返す。max_iterations回で収束しなければNone
+    pub fn run_to_fixpoint(&mut self, llang: &mut LLang, max_iterations: usize) -> Option<usize> {
+        (1..=max_iterations).find(|_| self.run(llang).is_empty())
+    }
+}
+
+#[cfg(test)]
+struct CountDown {
+    name: &'static str,
+    requires: Vec<&'static str>,
+    remaining: usize,
+}
+
+#[cfg(test)]
+impl Pass for CountDown {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn requires(&self) -> Vec<&'static str> {
+        self.requires.clone()
+    }
+
+    fn run(&mut self, _: &mut LLang) -> bool {
+        if self.remaining == 0 {
+            false
+        } else {
+            self.remaining -= 1;
+            true
+        }
+    }
+}
+
+#[test]
+fn test() {
+    let mut llang = LLang {
+        entry: 0,
+        funcs: Vec::new(),
+    };
+
+    let mut pipeline = PipelineBuilder::new()
+        .add(CountDown {
+            name: "a",
+            requires: vec![],
+            remaining: 1,
+        })
+        .add(CountDown {
+            name: "b",
+            requires: vec!["a"],
+            remaining: 3,
+        })
+        .build()
+        .unwrap();
+    assert_eq!(pipeline.run(&mut llang), vec!["a", "b"]);
+    assert_eq!(pipeline.run(&mut llang), vec!["b"]);
+    assert_eq!(pipeline.run_to_fixpoint(&mut llang, 10), Some(2));
+
+    let mut pipeline = PipelineBuilder::new()
+        .add(CountDown {
+            name: "a",
+            requires: vec![],
+            remaining: 100,
+        })
+        .build()
+        .unwrap();
+    assert_eq!(pipeline.run_to_fixpoint(&mut llang, 10), None);
+
+    assert_eq!(
+        PipelineBuilder::new()
+            .add(CountDown {
+                name: "b",
+                requires: vec!["a"],
+                remaining: 0,
+            })
+            .build()
+            .err(),
+        Some(PipelineError::MissingRequirement {
+            pass: "b",
+            requires: "a"
+        })
+    );
+}
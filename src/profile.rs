@@ -0,0 +1,367 @@
+use crate::heap::Heap;
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FuncStats {
+    // その関数自身で実行した命令数
+    pub exclusive: u64,
+    // その関数から呼んだ関数の分も含めた命令数
+    pub inclusive: u64,
+}
+
+// ヒープ確保の回数とバイト数
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AllocStats {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl AllocStats {
+    fn add(&mut self, bytes: u64) {
+        self.count += 1;
+        self.bytes += bytes;
+    }
+}
+
+// 関数の先頭アドレスごとの実行命令数と、確保した命令・関数ごとのヒープ確保
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    funcs: BTreeMap<usize, FuncStats>,
+    allocs_by_pc: BTreeMap<usize, AllocStats>,
+    allocs_by_func: BTreeMap<usize, AllocStats>,
+    // ブロックの先頭アドレスから、それを確保した命令のアドレスとバイト数
+    sites: HashMap<usize, (usize, u64)>,
+    // recordした命令の数と、関数ごとに最後にinclusiveを数えた時のclock
+    clock: u64,
+    stamps: HashMap<usize, u64>,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    pub fn stats(&self, func: usize) -> Option<&FuncStats> {
+        self.funcs.get(&func)
+    }
+
+    pub fn funcs(&self) -> impl Iterator<Item = (usize, &FuncStats)> {
+        self.funcs.iter().map(|(func, stats)| (*func, stats))
+    }
+
+    // call_stackの末尾の関数で命令を一つ実行したことを記録する
+    pub(crate) fn record(&mut self, call_stack: &[usize]) {
+        self.clock += 1;
+        if let Some(top) = call_stack.last() {
+            self.funcs.entry(*top).or_default().exclusive += 1;
+        }
+        // 再帰していても一回の命令は一回だけ数える
+        for func in call_stack {
+            let stamp = self.stamps.entry(*func).or_insert(0);
+            if *stamp != self.clock {
+                *stamp = self.clock;
+                self.funcs.entry(*func).or_default().inclusive += 1;
+            }
+        }
+    }
+
+    // pcの命令でaddrのブロックをbytesバイト確保したことを記録する
+    pub(crate) fn record_alloc(
+        &mut self,
+        call_stack: &[usize],
+        pc: usize,
+        addr: usize,
+        bytes: u64,
+    ) {
+        self.allocs_by_pc.entry(pc).or_default().add(bytes);
+        if let Some(top) = call_stack.last() {
+            self.allocs_by_func.entry(*top).or_default().add(bytes);
+        }
+        self.sites.insert(addr, (pc, bytes));
+    }
+
+    // 確保した命令のアドレスごとの累計
+    pub fn allocs_by_pc(&self) -> impl Iterator<Item = (usize, &AllocStats)> {
+        self.allocs_by_pc.iter().map(|(pc, stats)| (*pc, stats))
+    }
+
+    // 確保した関数の先頭アドレスごとの累計
+    pub fn allocs_by_func(&self) -> impl Iterator<Item = (usize, &AllocStats)> {
+        self.allocs_by_func
+            .iter()
+            .map(|(func, stats)| (*func, stats))
+    }
+
+    // allocs_by_funcを関数名ごとにまとめる。symbolsはreportと同じ
+    pub fn allocs_by_name(
+        &self,
+        symbols: &BTreeMap<usize, String>,
+    ) -> BTreeMap<String, AllocStats> {
+        let mut allocs = BTreeMap::<String, AllocStats>::new();
+        for (func, stats) in self.allocs_by_func() {
+            let entry = allocs.entry(name(symbols, func)).or_default();
+            entry.count += stats.count;
+            entry.bytes += stats.bytes;
+        }
+        allocs
+    }
+
+    // 今heapに残っているブロックを、確保した命令のアドレスごとに数える
+    pub fn live_allocs(&self, heap: &Heap) -> BTreeMap<usize, AllocStats> {
+        let mut live = BTreeMap::<usize, AllocStats>::new();
+        for (addr, _) in heap.blocks() {
+            if let Some(&(pc, bytes)) = self.sites.get(&addr) {
+                live.entry(pc).or_default().add(bytes);
+            }
+        }
+        live
+    }
+
+    // 確保した命令ごとの累計と、そのうちheapに残っている分
+    pub fn alloc_report(&self, heap: &Heap) -> String {
+        let live = self.live_allocs(heap);
+        let mut report = format!(
+            "{:>8} {:>8} {:>12} {:>8} {:>12}\n",
+            "pc", "allocs", "bytes", "live", "live bytes"
+        );
+        for (pc, stats) in self.allocs_by_pc() {
+            let live = live.get(&pc).cloned().unwrap_or_default();
+            report += &format!(
+                "{:>8} {:>8} {:>12} {:>8} {:>12}\n",
+                pc, stats.count, stats.bytes, live.count, live.bytes
+            );
+        }
+        report
+    }
+
+    fn sorted(&self) -> Vec<(usize, &FuncStats)> {
+        let mut funcs = self.funcs().collect::<Vec<_>>();
+        funcs.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+        funcs
+    }
+
+    // symbolsは関数の先頭アドレスから名前へ。llang::try_linkのシンボルマップをそのまま渡せる
+    // 名前のない関数はアドレスで書く
+    pub fn report(&self, symbols: &BTreeMap<usize, String>) -> String {
+        let mut report = format!("{:>8} {:>12} {:>12}\n", "func", "self", "total");
+        for (func, stats) in self.sorted() {
+            report += &format!(
+                "{:>8} {:>12} {:>12}\n",
+                name(symbols, func),
+                stats.exclusive,
+                stats.inclusive
+            );
+        }
+        report
+    }
+
+    pub fn to_json(&self, symbols: &BTreeMap<usize, String>) -> String {
+        let funcs = self
+            .sorted()
+            .into_iter()
+            .map(|(func, stats)| {
+                let name = match symbols.get(&func) {
+                    Some(name) => format!(r#","name":"{}""#, escape(name)),
+                    None => String::new(),
+                };
+                format!(
+                    r#"{{"func":{}{},"exclusive":{},"inclusive":{}}}"#,
+                    func, name, stats.exclusive, stats.inclusive
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(r#"{{"functions":[{}]}}"#, funcs.join(","))
+    }
+}
+
+// JSONの文字列に入れられるよう、引用符とバックスラッシュ、制御文字をエスケープする
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            '\r' => escaped += "\\r",
+            '\t' => escaped += "\\t",
+            c if c.is_control() => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn name(symbols: &BTreeMap<usize, String>, func: usize) -> String {
+    symbols
+        .get(&func)
+        .cloned()
+        .unwrap_or_else(|| func.to_string())
+}
+
+#[test]
+fn test() {
+    use crate::vm::{Cmd, VM};
+
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(1),
+        Cmd::Const(2),
+        Cmd::Call(7),
+        Cmd::PopR(2),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    vm.enable_profiler();
+    assert_eq!(vm.run(), Ok(3));
+    let profile = vm.profile().unwrap();
+    assert_eq!(
+        profile.stats(1),
+        Some(&FuncStats {
+            exclusive: 6,
+            inclusive: 11
+        })
+    );
+    assert_eq!(
+        profile.stats(7),
+        Some(&FuncStats {
+            exclusive: 5,
+            inclusive: 5
+        })
+    );
+    assert_eq!(
+        profile.report(&BTreeMap::new()),
+        "    func         self        total\n       1            6           11\n       7            5            5\n"
+    );
+    let symbols = vec![(7, "add".to_string())].into_iter().collect();
+    assert_eq!(
+        profile.report(&symbols),
+        "    func         self        total\n       1            6           11\n     add            5            5\n"
+    );
+    assert_eq!(
+        profile.to_json(&symbols),
+        r#"{"functions":[{"func":1,"exclusive":6,"inclusive":11},{"func":7,"name":"add","exclusive":5,"inclusive":5}]}"#
+    );
+    let symbols = vec![(7, "a\"b\\c\n\u{1}".to_string())]
+        .into_iter()
+        .collect();
+    assert_eq!(
+        profile.to_json(&symbols),
+        r#"{"functions":[{"func":1,"exclusive":6,"inclusive":11},{"func":7,"name":"a\"b\\c\n\u0001","exclusive":5,"inclusive":5}]}"#
+    );
+}
+
+#[test]
+#[cfg(feature = "frontend")]
+fn test_llang() {
+    use crate::llang::{Func, LLang, Op};
+    use crate::vm::VM;
+
+    // main() = twice(3)、twice(x) = x + x
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: Some("main".to_string()),
+                local_count: 0,
+                ops: vec![
+                    Op::Const(3),
+                    Op::Call(1),
+                    Op::PopR(3),
+                    Op::Const(1),
+                    Op::Add,
+                ],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::ArgLoad(0), Op::ArgLoad(0), Op::Add],
+            },
+        ],
+    };
+    let (cmds, symbols) = llang.convert_with_symbols().unwrap();
+    let mut vm = VM::new(cmds);
+    vm.enable_profiler();
+    assert_eq!(vm.run(), Ok(7));
+    assert_eq!(
+        vm.profile().unwrap().report(&symbols),
+        "    func         self        total\n    main            7           12\n      #1            5            5\n"
+    );
+}
+
+#[test]
+fn test_recursion() {
+    let mut profile = Profile::new();
+    profile.record(&[1, 7, 7, 7]);
+    assert_eq!(
+        profile.stats(7),
+        Some(&FuncStats {
+            exclusive: 1,
+            inclusive: 1
+        })
+    );
+    assert_eq!(
+        profile.stats(1),
+        Some(&FuncStats {
+            exclusive: 0,
+            inclusive: 1
+        })
+    );
+}
+
+#[test]
+fn test_allocs() {
+    use crate::vm::{Cmd, VM};
+
+    // 2セルのブロックを3つ確保する。GCの後には戻り値にした最後のブロックだけが残る
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(1, 2),
+        Cmd::Call(7),
+        Cmd::LocalStore(0),
+        Cmd::Call(7),
+        Cmd::Call(7),
+        Cmd::Ret,
+        Cmd::Frame(0, 1), // 7
+        Cmd::Alloc(2),
+        Cmd::Ret,
+    ]);
+    vm.enable_profiler();
+    vm.run().unwrap();
+    vm.collect_garbage();
+    let profile = vm.profile().unwrap();
+    let stats = |count, bytes| AllocStats { count, bytes };
+    assert_eq!(
+        profile.allocs_by_pc().collect::<Vec<_>>(),
+        vec![(8, &stats(3, 48))]
+    );
+    assert_eq!(
+        profile.allocs_by_func().collect::<Vec<_>>(),
+        vec![(7, &stats(3, 48))]
+    );
+    assert_eq!(
+        profile
+            .live_allocs(vm.heap())
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![(8, stats(1, 16))]
+    );
+    let symbols = vec![(7, "make".to_string())].into_iter().collect();
+    assert_eq!(
+        profile
+            .allocs_by_name(&symbols)
+            .into_iter()
+            .collect::<Vec<_>>(),
+        vec![("make".to_string(), stats(3, 48))]
+    );
+    assert_eq!(
+        profile.alloc_report(vm.heap()),
+        "      pc   allocs        bytes     live   live bytes\n       8        3           48        1           16\n"
+    );
+}
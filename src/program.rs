@@ -0,0 +1,338 @@
+use crate::varint::{read_varint, unzigzag, write_varint, zigzag};
+use crate::vm::{Cmd, Value};
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"SVM\0";
+// 1は命令列だけ、2から定数プールを持つ
+const VERSION: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DecodeError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    // 命令の途中でデータが終わった
+    UnexpectedEof,
+    InvalidOpcode(u8),
+    // 文字列定数がUTF-8でない
+    InvalidString,
+    // 飛び先が関数の外を指している
+    InvalidJump(usize),
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::BadMagic => write!(f, "not a program file"),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported version {}", v),
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of data"),
+            DecodeError::InvalidOpcode(op) => write!(f, "invalid opcode {}", op),
+            DecodeError::InvalidString => write!(f, "invalid string constant"),
+            DecodeError::InvalidJump(x) => write!(f, "invalid jump target {}", x),
+            DecodeError::Io(kind) => write!(f, "io error: {:?}", kind),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+// 1バイトのオペコードに続けて、オペランドをLEB128で並べる。Constの値と相対アドレスはzigzag符号化する
+impl Cmd {
+    pub fn encode(&self, bytes: &mut Vec<u8>) {
+        let (opcode, operands): (u8, &[u64]) = match *self {
+            Cmd::Frame(x, y) => (0, &[x as u64, y as u64]),
+            Cmd::Ret => (1, &[]),
+            Cmd::Call(x) => (2, &[x as u64]),
+            Cmd::LocalLoad(x) => (3, &[x as u64]),
+            Cmd::LocalStore(x) => (4, &[x as u64]),
+            Cmd::ArgLoad(x) => (5, &[x as u64]),
+            Cmd::ArgStore(x) => (6, &[x as u64]),
+            Cmd::PopR(x) => (7, &[x as u64]),
+            Cmd::Const(x) => (8, &[zigzag(x)]),
+            Cmd::Add => (9, &[]),
+            Cmd::Sub => (10, &[]),
+            Cmd::Mul => (11, &[]),
+            Cmd::Div => (12, &[]),
+            Cmd::Mod => (13, &[]),
+            Cmd::Entry(x) => (14, &[x as u64]),
+            Cmd::Eq => (15, &[]),
+            Cmd::Ne => (16, &[]),
+            Cmd::Lt => (17, &[]),
+            Cmd::Le => (18, &[]),
+            Cmd::Gt => (19, &[]),
+            Cmd::Ge => (20, &[]),
+            Cmd::JumpIf(x) => (21, &[x as u64]),
+            Cmd::Jump(x) => (22, &[x as u64]),
+            Cmd::Alloc(x) => (23, &[x as u64]),
+            Cmd::HeapLoad => (24, &[]),
+            Cmd::HeapStore => (25, &[]),
+            Cmd::StrConst(x) => (26, &[x as u64]),
+            Cmd::StrConcat => (27, &[]),
+            Cmd::StrLen => (28, &[]),
+            Cmd::StrEq => (29, &[]),
+            Cmd::ConstLoad(x) => (30, &[x as u64]),
+            Cmd::Print => (31, &[]),
+            Cmd::ReadInt => (32, &[]),
+            Cmd::NativeCall(x) => (33, &[x as u64]),
+            Cmd::JumpRel(x) => (34, &[zigzag(x as i64)]),
+            Cmd::JumpIfRel(x) => (35, &[zigzag(x as i64)]),
+            Cmd::CallRel(x) => (36, &[zigzag(x as i64)]),
+            Cmd::And => (37, &[]),
+            Cmd::Or => (38, &[]),
+            Cmd::Not => (39, &[]),
+            Cmd::Xor => (40, &[]),
+            Cmd::Shl => (41, &[]),
+            Cmd::Shr => (42, &[]),
+            Cmd::Dup => (43, &[]),
+            Cmd::Swap => (44, &[]),
+            Cmd::Pop => (45, &[]),
+            Cmd::TailCall(x, y) => (46, &[x as u64, y as u64]),
+            Cmd::FuncRef(x) => (47, &[x as u64]),
+            Cmd::CallIndirect => (48, &[]),
+            Cmd::MakeClosure(x, y) => (49, &[x as u64, y as u64]),
+            Cmd::CallClosure => (50, &[]),
+            Cmd::CaptureLoad(x) => (51, &[x as u64]),
+        };
+        bytes.push(opcode);
+        for x in operands {
+            write_varint(bytes, *x);
+        }
+    }
+
+    // 一命令読んで、読んだ分だけbytesを進める
+    pub fn decode(bytes: &mut &[u8]) -> Result<Cmd, DecodeError> {
+        let (&opcode, rest) = bytes.split_first().ok_or(DecodeError::UnexpectedEof)?;
+        *bytes = rest;
+        let mut operand = || read_varint(bytes).ok_or(DecodeError::UnexpectedEof);
+        Ok(match opcode {
+            0 => Cmd::Frame(operand()? as usize, operand()? as usize),
+            1 => Cmd::Ret,
+            2 => Cmd::Call(operand()? as usize),
+            3 => Cmd::LocalLoad(operand()? as usize),
+            4 => Cmd::LocalStore(operand()? as usize),
+            5 => Cmd::ArgLoad(operand()? as usize),
+            6 => Cmd::ArgStore(operand()? as usize),
+            7 => Cmd::PopR(operand()? as usize),
+            8 => Cmd::Const(unzigzag(operand()?)),
+            9 => Cmd::Add,
+            10 => Cmd::Sub,
+            11 => Cmd::Mul,
+            12 => Cmd::Div,
+            13 => Cmd::Mod,
+            14 => Cmd::Entry(operand()? as usize),
+            15 => Cmd::Eq,
+            16 => Cmd::Ne,
+            17 => Cmd::Lt,
+            18 => Cmd::Le,
+            19 => Cmd::Gt,
+            20 => Cmd::Ge,
+            21 => Cmd::JumpIf(operand()? as usize),
+            22 => Cmd::Jump(operand()? as usize),
+            23 => Cmd::Alloc(operand()? as usize),
+            24 => Cmd::HeapLoad,
+            25 => Cmd::HeapStore,
+            26 => Cmd::StrConst(operand()? as usize),
+            27 => Cmd::StrConcat,
+            28 => Cmd::StrLen,
+            29 => Cmd::StrEq,
+            30 => Cmd::ConstLoad(operand()? as usize),
+            31 => Cmd::Print,
+            32 => Cmd::ReadInt,
+            33 => Cmd::NativeCall(operand()? as usize),
+            34 => Cmd::JumpRel(unzigzag(operand()?) as isize),
+            35 => Cmd::JumpIfRel(unzigzag(operand()?) as isize),
+            36 => Cmd::CallRel(unzigzag(operand()?) as isize),
+            37 => Cmd::And,
+            38 => Cmd::Or,
+            39 => Cmd::Not,
+            40 => Cmd::Xor,
+            41 => Cmd::Shl,
+            42 => Cmd::Shr,
+            43 => Cmd::Dup,
+            44 => Cmd::Swap,
+            45 => Cmd::Pop,
+            46 => Cmd::TailCall(operand()? as usize, operand()? as usize),
+            47 => Cmd::FuncRef(operand()? as usize),
+            48 => Cmd::CallIndirect,
+            49 => Cmd::MakeClosure(operand()? as usize, operand()? as usize),
+            50 => Cmd::CallClosure,
+            51 => Cmd::CaptureLoad(operand()? as usize),
+            _ => return Err(DecodeError::InvalidOpcode(opcode)),
+        })
+    }
+}
+
+fn read_len(bytes: &mut &[u8]) -> Result<u64, DecodeError> {
+    read_varint(bytes).ok_or(DecodeError::UnexpectedEof)
+}
+
+// コンパイル済みのプログラム
+// マジック、バージョン、定数プール(数値、文字列)、命令列の順に、それぞれ要素数を前置して書き出す
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Program {
+    // ConstLoadで参照する定数
+    pub consts: Vec<Value>,
+    // StrConstで参照する文字列
+    pub strings: Vec<String>,
+    pub code: Vec<Cmd>,
+}
+
+impl Program {
+    pub fn new(code: Vec<Cmd>) -> Program {
+        Program {
+            code,
+            ..Program::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        write_varint(&mut bytes, self.consts.len() as u64);
+        for x in &self.consts {
+            write_varint(&mut bytes, zigzag(*x));
+        }
+        write_varint(&mut bytes, self.strings.len() as u64);
+        for s in &self.strings {
+            write_varint(&mut bytes, s.len() as u64);
+            bytes.extend_from_slice(s.as_bytes());
+        }
+        write_varint(&mut bytes, self.code.len() as u64);
+        for cmd in &self.code {
+            cmd.encode(&mut bytes);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Program, DecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(DecodeError::BadMagic);
+        }
+        let mut bytes = &bytes[MAGIC.len()..];
+        let version = match bytes.split_first() {
+            Some((&version, rest)) if version == 1 || version == VERSION => {
+                bytes = rest;
+                version
+            }
+            Some((&version, _)) => return Err(DecodeError::UnsupportedVersion(version)),
+            None => return Err(DecodeError::UnexpectedEof),
+        };
+        let mut program = Program::default();
+        if version >= 2 {
+            for _ in 0..read_len(&mut bytes)? {
+                program.consts.push(unzigzag(read_len(&mut bytes)?));
+            }
+            for _ in 0..read_len(&mut bytes)? {
+                let len = read_len(&mut bytes)? as usize;
+                if bytes.len() < len {
+                    return Err(DecodeError::UnexpectedEof);
+                }
+                let (s, rest) = bytes.split_at(len);
+                program
+                    .strings
+                    .push(String::from_utf8(s.to_vec()).map_err(|_| DecodeError::InvalidString)?);
+                bytes = rest;
+            }
+        }
+        for _ in 0..read_len(&mut bytes)? {
+            program.code.push(Cmd::decode(&mut bytes)?);
+        }
+        Ok(program)
+    }
+
+    pub fn write(&self, mut writer: impl Write) -> io::Result<()> {
+        writer.write_all(&self.to_bytes())
+    }
+
+    pub fn read(mut reader: impl Read) -> Result<Program, DecodeError> {
+        let mut bytes = Vec::new();
+        reader
+            .read_to_end(&mut bytes)
+            .map_err(|e| DecodeError::Io(e.kind()))?;
+        Program::from_bytes(&bytes)
+    }
+}
+
+#[test]
+#[cfg(feature = "frontend")]
+fn test() {
+    use crate::genprog::{generate, Workload};
+    use crate::vm::VM;
+
+    let cmds = generate(Workload::BranchHeavy, 10).convert();
+    let program = Program::new(cmds.clone());
+    let mut bytes = Vec::new();
+    program.write(&mut bytes).unwrap();
+    assert_eq!(&bytes[..5], b"SVM\0\x02");
+    let loaded = Program::read(&bytes[..]).unwrap();
+    assert_eq!(loaded, program);
+    assert_eq!(VM::new(loaded.code).run(), VM::new(cmds).run());
+
+    let program = Program::new(vec![
+        Cmd::Const(-1),
+        Cmd::Frame(300, 2),
+        Cmd::Ge,
+        Cmd::Alloc(4),
+        Cmd::HeapStore,
+        Cmd::StrConst(2),
+        Cmd::StrEq,
+        Cmd::ConstLoad(1),
+        Cmd::ReadInt,
+        Cmd::Print,
+        Cmd::NativeCall(3),
+        Cmd::JumpRel(-3),
+        Cmd::CallRel(300),
+        Cmd::And,
+        Cmd::Or,
+        Cmd::Not,
+        Cmd::Xor,
+        Cmd::Shl,
+        Cmd::Shr,
+        Cmd::Dup,
+        Cmd::Swap,
+        Cmd::Pop,
+        Cmd::TailCall(5, 2),
+        Cmd::FuncRef(5),
+        Cmd::CallIndirect,
+        Cmd::MakeClosure(5, 2),
+        Cmd::CallClosure,
+        Cmd::CaptureLoad(1),
+    ]);
+    assert_eq!(Program::from_bytes(&program.to_bytes()), Ok(program));
+
+    let program = Program {
+        consts: vec![i64::MIN, 1 << 40],
+        strings: vec!["".to_string(), "あ".to_string()],
+        code: vec![Cmd::ConstLoad(1)],
+    };
+    assert_eq!(Program::from_bytes(&program.to_bytes()), Ok(program));
+    // 定数プールのないバージョン1も読める
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x01\x01\x01"),
+        Ok(Program::new(vec![Cmd::Ret]))
+    );
+
+    assert_eq!(Program::from_bytes(b"ELF"), Err(DecodeError::BadMagic));
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x03"),
+        Err(DecodeError::UnsupportedVersion(3))
+    );
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x01\x01\x00\x05"),
+        Err(DecodeError::UnexpectedEof)
+    );
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x01\x01\xff"),
+        Err(DecodeError::InvalidOpcode(0xff))
+    );
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x02\x00\x01\x01\xff\x00"),
+        Err(DecodeError::InvalidString)
+    );
+    assert_eq!(
+        Program::from_bytes(b"SVM\0\x02\x00\x01\x05ab"),
+        Err(DecodeError::UnexpectedEof)
+    );
+}
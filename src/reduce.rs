@@ -0,0 +1,127 @@
+use crate::llang::{LLang, Op};
+use crate::slice::slice;
+use crate::verifier::{verify, Severity};
+
+// predicateを満たしたまま、関数と命令を削れるだけ削ったプログラムを返す
+// 候補はverifierでエラーが出ないものだけに絞ってからpredicateに渡す
+pub fn reduce(llang: &LLang, predicate: impl Fn(&LLang) -> bool) -> LLang {
+    let mut current = llang.clone();
+    if !is_valid(&current) || !predicate(&current) {
+        return current;
+    }
+
+    let sliced = slice(&current, current.entry);
+    if is_valid(&sliced) && predicate(&sliced) {
+        current = sliced;
+    }
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        let mut i = 0;
+        while i < current.funcs.len() {
+            if current.funcs[i].id != current.entry {
+                let mut candidate = current.clone();
+                candidate.funcs.remove(i);
+                if is_valid(&candidate) && predicate(&candidate) {
+                    current = candidate;
+                    changed = true;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+
+        for f in 0..current.funcs.len() {
+            let mut size = current.funcs[f].ops.len();
+            while size > 0 {
+                let mut start = 0;
+                while start < current.funcs[f].ops.len() {
+                    let end = (start + size).min(current.funcs[f].ops.len());
+                    let mut candidate = current.clone();
+                    candidate.funcs[f].remove_ops(start, end);
+                    if is_valid(&candidate) && predicate(&candidate) {
+                        current = candidate;
+                        changed = true;
+                    } else {
+                        start += size;
+                    }
+                }
+                size /= 2;
+            }
+        }
+    }
+    current
+}
+
+fn is_valid(llang: &LLang) -> bool {
+    let ids = llang.funcs.iter().map(|f| f.id).collect::<Vec<_>>();
+    let resolved = ids.contains(&llang.entry)
+        && llang.funcs.iter().all(|f| {
+            f.ops.iter().all(|op| match op {
+                Op::Call(_) | Op::CallName(_) => {
+                    matches!(llang.callee(op), Some(id) if ids.contains(&id))
+                }
+                Op::FuncRef(id) | Op::MakeClosure(id, _) => ids.contains(id),
+                _ => true,
+            })
+        });
+    resolved
+        && llang.funcs.iter().all(|f| f.max_stack().is_some())
+        && verify(&llang.convert())
+            .iter()
+            .all(|d| d.severity != Severity::Error)
+}
+
+#[test]
+fn test() {
+    use crate::llang::Func;
+    use crate::vm::VM;
+
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: None,
+                local_count: 1,
+                ops: vec![
+                    Op::Const(5),
+                    Op::Const(6),
+                    Op::Add,
+                    Op::LocalStore(0),
+                    Op::Const(3),
+                    Op::Call(1),
+                    Op::PopR(3),
+                ],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::ArgLoad(0)],
+            },
+            Func {
+                id: 2,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::Const(1)],
+            },
+        ],
+    };
+
+    let reduced = reduce(&llang, |llang| VM::new(llang.convert()).run() == Ok(3));
+    assert_eq!(
+        reduced,
+        LLang {
+            entry: 0,
+            funcs: vec![Func {
+                id: 0,
+                name: None,
+                local_count: 1,
+                ops: vec![Op::Const(3)],
+            }],
+        }
+    );
+}
@@ -0,0 +1,284 @@
+use crate::llang::{Func, LLang, Op};
+use std::collections::HashSet;
+
+// 関数IDがcriterionの関数の戻り値に影響しうる関数と命令だけを残し、criterionをエントリとするプログラムを返す
+// 関数の中では、読まれないローカル変数への代入とPopで捨てる値を、それを計算する命令ごと取り除く
+pub fn slice(llang: &LLang, criterion: usize) -> LLang {
+    let mut reachable = HashSet::new();
+    let mut work = vec![criterion];
+    while let Some(id) = work.pop() {
+        if !reachable.insert(id) {
+            continue;
+        }
+        if let Some(func) = llang.funcs.iter().find(|f| f.id == id) {
+            for op in &func.ops {
+                if let Some(callee) = llang.callee(op) {
+                    work.push(callee);
+                }
+                // 間接呼び出しで呼ばれうる
+                if let Op::FuncRef(id) | Op::MakeClosure(id, _) = op {
+                    work.push(*id);
+                }
+            }
+        }
+    }
+
+    LLang {
+        entry: criterion,
+        funcs: llang
+            .funcs
+            .iter()
+            .filter(|f| reachable.contains(&f.id))
+            .map(slice_func)
+            .collect(),
+    }
+}
+
+fn slice_func(func: &Func) -> Func {
+    let mut func = func.clone();
+    // 一つ取り除くと、その値だけを使っていた代入も取り除けるようになる
+    while let Some((start, end)) = irrelevant_statement(&func) {
+        func.remove_ops(start, end + 1);
+    }
+    func
+}
+
+// 結果が使われない文ops[start..=end]をひとつ探す
+fn irrelevant_statement(func: &Func) -> Option<(usize, usize)> {
+    let live = live_locals(func);
+    let targets = func
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::Jump(x) | Op::JumpIf(x) => Some(*x),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    (0..func.ops.len()).find_map(|end| {
+        let dead = match func.ops[end] {
+            Op::Pop => true,
+            Op::LocalStore(i) => !live[end + 1].contains(&i),
+            _ => false,
+        };
+        if !dead {
+            return None;
+        }
+        statement_start(&func.ops, end, &targets).map(|start| (start, end))
+    })
+}
+
+// ops[end]が取り出す値だけを計算している区間の先頭
+// 途中に副作用のある命令があるか、途中へ飛び込まれるならNone
+fn statement_start(ops: &[Op], end: usize, targets: &HashSet<usize>) -> Option<usize> {
+    // ops[start..=end]が区間の前から取り出す値の数
+    let mut need = ops[end].pop_count();
+    for start in (0..end).rev() {
+        let op = &ops[start];
+        if !is_pure(op) || targets.contains(&(start + 1)) || op.push_count() > need {
+            return None;
+        }
+        need = need - op.push_count() + op.pop_count();
+        if need == 0 {
+            return Some(start);
+        }
+    }
+    None
+}
+
+// 取り除いても結果が変わらない命令。トラップしうるDiv, Modやヒープに触る命令は含めない
+fn is_pure(op: &Op) -> bool {
+    matches!(
+        op,
+        Op::LocalLoad(_)
+            | Op::ArgLoad(_)
+            | Op::Const(_)
+            | Op::Add
+            | Op::Sub
+            | Op::Mul
+            | Op::Eq
+            | Op::Ne
+            | Op::Lt
+            | Op::Le
+            | Op::Gt
+            | Op::Ge
+            | Op::And
+            | Op::Or
+            | Op::Not
+            | Op::Xor
+            | Op::Shl
+            | Op::Shr
+            | Op::Dup
+            | Op::Swap
+            | Op::FuncRef(_)
+            | Op::CaptureLoad(_)
+    )
+}
+
+// live[i]はops[i]を実行する直前に、あとで読まれうるローカル変数。live[ops.len()]は関数の末尾
+fn live_locals(func: &Func) -> Vec<HashSet<usize>> {
+    let len = func.ops.len();
+    let mut live = vec![HashSet::new(); len + 1];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..len).rev() {
+            let op = &func.ops[i];
+            let mut set = HashSet::new();
+            for succ in op.successors(i) {
+                // 関数外へのジャンプはRetとみなす
+                if let Some(s) = live.get(succ) {
+                    set.extend(s.iter().cloned());
+                }
+            }
+            match op {
+                Op::LocalStore(x) => {
+                    set.remove(x);
+                }
+                Op::LocalLoad(x) => {
+                    set.insert(*x);
+                }
+                _ => {}
+            }
+            if set != live[i] {
+                live[i] = set;
+                changed = true;
+            }
+        }
+    }
+    live
+}
+
+#[test]
+fn test() {
+    use crate::llang::{Func, Op};
+    use crate::vm::VM;
+
+    let func = |id, ops| Func {
+        id,
+        name: None,
+        local_count: 0,
+        ops,
+    };
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            func(
+                0,
+                vec![
+                    Op::Const(2),
+                    Op::Call(1),
+                    Op::PopR(3),
+                    Op::Call(3),
+                    Op::PopR(2),
+                ],
+            ),
+            // 1(x) = 2(x) + x
+            func(
+                1,
+                vec![
+                    Op::ArgLoad(0),
+                    Op::Call(2),
+                    Op::PopR(3),
+                    Op::ArgLoad(0),
+                    Op::Add,
+                ],
+            ),
+            // 2(x) = x + x
+            func(2, vec![Op::ArgLoad(0), Op::ArgLoad(0), Op::Add]),
+            func(3, vec![Op::Const(100)]),
+            func(4, vec![Op::Const(200)]),
+        ],
+    };
+
+    let sliced = slice(&llang, 1);
+    assert_eq!(
+        sliced.funcs.iter().map(|f| f.id).collect::<Vec<_>>(),
+        vec![1, 2]
+    );
+
+    let sliced = slice(&llang, 0);
+    assert_eq!(
+        sliced.funcs.iter().map(|f| f.id).collect::<Vec<_>>(),
+        vec![0, 1, 2, 3]
+    );
+    assert_eq!(
+        VM::new(sliced.convert()).run(),
+        VM::new(llang.convert()).run()
+    );
+}
+
+#[test]
+fn test_statements() {
+    use crate::llang::{Func, Op};
+    use crate::vm::VM;
+
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![
+            Func {
+                id: 0,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::Const(5), Op::Call(1), Op::PopR(3)],
+            },
+            Func {
+                id: 1,
+                name: None,
+                local_count: 3,
+                ops: vec![
+                    // a = x * 2
+                    Op::ArgLoad(0),
+                    Op::Const(2),
+                    Op::Mul,
+                    Op::LocalStore(0),
+                    // b = x + 100
+                    Op::ArgLoad(0),
+                    Op::Const(100),
+                    Op::Add,
+                    Op::LocalStore(1),
+                    // c = b * 3
+                    Op::LocalLoad(1),
+                    Op::Const(3),
+                    Op::Mul,
+                    Op::LocalStore(2),
+                    // 捨てる値
+                    Op::ArgLoad(0),
+                    Op::Dup,
+                    Op::Add,
+                    Op::Pop,
+                    // 0でなければ a を表示する
+                    Op::ArgLoad(0),
+                    Op::JumpIf(19),
+                    Op::Jump(21),
+                    Op::LocalLoad(0),
+                    Op::Print,
+                    // a + x
+                    Op::LocalLoad(0),
+                    Op::ArgLoad(0),
+                    Op::Add,
+                ],
+            },
+        ],
+    };
+
+    let sliced = slice(&llang, 0);
+    assert_eq!(
+        sliced.funcs[1].ops,
+        vec![
+            Op::ArgLoad(0),
+            Op::Const(2),
+            Op::Mul,
+            Op::LocalStore(0),
+            Op::ArgLoad(0),
+            Op::JumpIf(7),
+            Op::Jump(9),
+            Op::LocalLoad(0),
+            Op::Print,
+            Op::LocalLoad(0),
+            Op::ArgLoad(0),
+            Op::Add,
+        ]
+    );
+    assert_eq!(VM::new(sliced.convert()).run(), Ok(15));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(15));
+}
@@ -0,0 +1,255 @@
+use crate::llang::{Func, LLang, Op};
+use crate::slice::slice;
+use crate::vm::Value;
+use std::collections::HashSet;
+
+// 関数IDがentryの関数の引数を一部固定した複製を作り、それをエントリとする残余プログラムを返す
+// known_args[i]がSomeならArgLoad(i)をその定数に置き換え、定数畳み込みと到達しない命令の削除をする
+// 呼び出し規約は変えないので、固定した引数も積んでから呼ぶ。ArgStoreで書き換えられる引数は固定しない
+// 元の関数は再帰呼び出しなどから呼ばれていれば残る
+pub fn specialize(llang: &LLang, entry: usize, known_args: &[Option<Value>]) -> LLang {
+    let mut func = match llang.funcs.iter().find(|f| f.id == entry) {
+        Some(func) => func.clone(),
+        None => return llang.clone(),
+    };
+    func.id = llang.funcs.iter().map(|f| f.id).max().unwrap() + 1;
+    // 名前は元の関数のもの
+    func.name = None;
+
+    let stored = func
+        .ops
+        .iter()
+        .filter_map(|op| match op {
+            Op::ArgStore(i) => Some(*i),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    for op in &mut func.ops {
+        if let Op::ArgLoad(i) = *op {
+            if let Some(Some(x)) = known_args.get(i) {
+                if !stored.contains(&i) {
+                    *op = Op::Const(*x);
+                }
+            }
+        }
+    }
+    while fold(&mut func) || remove_unreachable(&mut func) {}
+
+    let id = func.id;
+    let mut residual = llang.clone();
+    residual.funcs.push(func);
+    slice(&residual, id)
+}
+
+// 一箇所だけ畳み込み、畳み込んだらtrueを返す
+fn fold(func: &mut Func) -> bool {
+    let targets = func
+        .ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, op)| match op {
+            Op::JumpIf(_) | Op::Jump(_) => op.successors(i),
+            _ => vec![],
+        })
+        .collect::<HashSet<_>>();
+    // 途中に飛び込まれる命令列は畳み込めない
+    let straight =
+        |start: usize, len: usize| (start + 1..start + len).all(|i| !targets.contains(&i));
+
+    for i in 0..func.ops.len() {
+        match func.ops[i..] {
+            [Op::Const(y), Op::Const(x), ref op, ..] if straight(i, 3) => {
+                if let Some(z) = eval(op, x, y) {
+                    func.replace_ops(i, i + 3, vec![Op::Const(z)]);
+                    return true;
+                }
+            }
+            [Op::Const(x), Op::Dup, ..] if straight(i, 2) => {
+                func.ops[i + 1] = Op::Const(x);
+                return true;
+            }
+            [Op::Const(_), Op::Pop, ..] if straight(i, 2) => {
+                func.remove_ops(i, i + 2);
+                return true;
+            }
+            [Op::Const(x), Op::Not, ..] if straight(i, 2) => {
+                func.replace_ops(i, i + 2, vec![Op::Const((x == 0) as Value)]);
+                return true;
+            }
+            [Op::Const(x), Op::JumpIf(to), ..] if straight(i, 2) => {
+                if x != 0 {
+                    func.ops[i + 1] = Op::Jump(to);
+                    func.remove_ops(i, i + 1);
+                } else {
+                    func.remove_ops(i, i + 2);
+                }
+                return true;
+            }
+            [Op::Jump(to), ..] if to == i + 1 => {
+                func.remove_ops(i, i + 1);
+                return true;
+            }
+            _ => {}
+        }
+    }
+    false
+}
+
+// xがスタックの一番上の値。実行時にエラーになったり、オーバーフローの扱いで結果が変わったりするものは畳み込まない
+fn eval(op: &Op, x: Value, y: Value) -> Option<Value> {
+    let bool = |b| Some(b as Value);
+    match op {
+        Op::Add => x.checked_add(y),
+        Op::Sub => x.checked_sub(y),
+        Op::Mul => x.checked_mul(y),
+        Op::Div => x.checked_div(y),
+        Op::Mod => x.checked_rem(y),
+        Op::Eq => bool(x == y),
+        Op::Ne => bool(x != y),
+        Op::Lt => bool(x < y),
+        Op::Le => bool(x <= y),
+        Op::Gt => bool(x > y),
+        Op::Ge => bool(x >= y),
+        Op::And => Some(x & y),
+        Op::Or => Some(x | y),
+        Op::Xor => Some(x ^ y),
+        Op::Shl if (0..64).contains(&y) => Some(x << y),
+        Op::Shr if (0..64).contains(&y) => Some(x >> y),
+        _ => None,
+    }
+}
+
+// 先頭から到達しない命令を消し、消したらtrueを返す
+fn remove_unreachable(func: &mut Func) -> bool {
+    let mut reachable = vec![false; func.ops.len()];
+    let mut work = vec![0];
+    while let Some(i) = work.pop() {
+        if i >= func.ops.len() || reachable[i] {
+            continue;
+        }
+        reachable[i] = true;
+        work.extend(func.ops[i].successors(i));
+    }
+    match reachable.iter().rposition(|r| !r) {
+        Some(end) => {
+            let start = reachable[..end]
+                .iter()
+                .rposition(|r| *r)
+                .map_or(0, |i| i + 1);
+            func.remove_ops(start, end + 1);
+            true
+        }
+        None => false,
+    }
+}
+
+#[test]
+fn test() {
+    use crate::vm::VM;
+
+    // rule(x:2, scale:1, mode:0) = if mode == 1 { x * scale } else { x + scale }
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![
+                Op::Const(1),
+                Op::ArgLoad(0),
+                Op::Eq,
+                Op::JumpIf(8),
+                Op::ArgLoad(1),
+                Op::ArgLoad(2),
+                Op::Add,
+                Op::Jump(11),
+                Op::ArgLoad(1),
+                Op::ArgLoad(2),
+                Op::Mul,
+            ],
+        }],
+    };
+    let run = |llang: &LLang, args: &[Value]| {
+        let mut vm = VM::new(llang.convert());
+        for x in args {
+            vm.push_int(*x).unwrap();
+        }
+        vm.run()
+    };
+
+    let residual = specialize(&llang, 0, &[Some(1), Some(3), None]);
+    assert_eq!(
+        residual,
+        LLang {
+            entry: 1,
+            funcs: vec![Func {
+                id: 1,
+                name: None,
+                local_count: 0,
+                ops: vec![Op::Const(3), Op::ArgLoad(2), Op::Mul],
+            }],
+        }
+    );
+    for x in -3..3 {
+        assert_eq!(run(&residual, &[x, 3, 1]), run(&llang, &[x, 3, 1]));
+    }
+
+    let residual = specialize(&llang, 0, &[Some(0), None, None]);
+    assert_eq!(
+        residual.funcs[0].ops,
+        vec![Op::ArgLoad(1), Op::ArgLoad(2), Op::Add]
+    );
+    assert_eq!(run(&residual, &[4, 5, 0]), Ok(9));
+
+    // 0除算は実行時に任せる
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![Op::ArgLoad(0), Op::Const(1), Op::Div],
+        }],
+    };
+    assert_eq!(
+        specialize(&llang, 0, &[Some(0)]).funcs[0].ops,
+        vec![Op::Const(0), Op::Const(1), Op::Div]
+    );
+
+    // 論理演算とシフトも畳み込む。範囲外のシフトは実行時に任せる
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![
+                Op::ArgLoad(0),
+                Op::Not,
+                Op::Const(6),
+                Op::Xor,
+                Op::ArgLoad(1),
+                Op::Const(1),
+                Op::Shl,
+            ],
+        }],
+    };
+    assert_eq!(
+        specialize(&llang, 0, &[Some(0), Some(70)]).funcs[0].ops,
+        vec![Op::Const(7), Op::Const(70), Op::Const(1), Op::Shl]
+    );
+
+    // x * x + (捨てる値)
+    let llang = LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![Op::ArgLoad(0), Op::Dup, Op::Mul, Op::ArgLoad(1), Op::Pop],
+        }],
+    };
+    let residual = specialize(&llang, 0, &[Some(5), Some(1)]);
+    assert_eq!(residual.funcs[0].ops, vec![Op::Const(25)]);
+    assert_eq!(run(&residual, &[0, 5]), Ok(25));
+}
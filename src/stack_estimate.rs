@@ -0,0 +1,116 @@
+use crate::llang::{LLang, Op};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StackBound {
+    Slots(usize),
+    // 再帰やループでスタックが伸び続けるため上限がない
+    Unbounded,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackEstimate {
+    // エントリから実行したときに使うスタックのスロット数
+    pub entry: StackBound,
+    // 関数IDごとの、呼び出されてから戻るまでに戻りアドレスより上で使うスロット数
+    pub funcs: BTreeMap<usize, StackBound>,
+}
+
+// 各関数のフレームサイズとコールグラフから、最悪の場合のスタック使用量を見積もる
+pub fn max_stack_estimate(llang: &LLang) -> StackEstimate {
+    let mut funcs = BTreeMap::new();
+    for func in &llang.funcs {
+        let mut visiting = Vec::new();
+        let bound = estimate(llang, func.id, &mut funcs, &mut visiting);
+        funcs.insert(func.id, bound);
+    }
+    let entry = match funcs.get(&llang.entry) {
+        // Entryが積む戻りアドレスの分
+        Some(StackBound::Slots(x)) => StackBound::Slots(x + 1),
+        _ => StackBound::Unbounded,
+    };
+    StackEstimate { entry, funcs }
+}
+
+fn estimate(
+    llang: &LLang,
+    id: usize,
+    memo: &mut BTreeMap<usize, StackBound>,
+    visiting: &mut Vec<usize>,
+) -> StackBound {
+    if let Some(bound) = memo.get(&id) {
+        return *bound;
+    }
+    if visiting.contains(&id) {
+        return StackBound::Unbounded;
+    }
+    let func = match llang.funcs.iter().find(|f| f.id == id) {
+        Some(func) => func,
+        None => return StackBound::Unbounded,
+    };
+    let (depths, max_stack) = match (func.stack_depths(), func.max_stack()) {
+        (Some(depths), Some(max_stack)) => (depths, max_stack),
+        _ => return StackBound::Unbounded,
+    };
+
+    visiting.push(id);
+    let mut peak = max_stack;
+    let mut callees = HashMap::new();
+    for (op, depth) in func.ops.iter().zip(depths) {
+        // 呼び先がわからない
+        if let Op::CallIndirect | Op::CallClosure = op {
+            visiting.pop();
+            return StackBound::Unbounded;
+        }
+        if let (Some(callee), Some(depth)) = (llang.callee(op), depth) {
+            let bound = *callees
+                .entry(callee)
+                .or_insert_with(|| estimate(llang, callee, memo, visiting));
+            match bound {
+                // 戻りアドレスの分
+                StackBound::Slots(x) => peak = peak.max(depth + 1 + x),
+                StackBound::Unbounded => {
+                    visiting.pop();
+                    return StackBound::Unbounded;
+                }
+            }
+        }
+    }
+    visiting.pop();
+
+    // 旧フレームポインタ、ローカル変数、オペランドスタック
+    let bound = StackBound::Slots(1 + func.local_count + peak);
+    memo.insert(id, bound);
+    bound
+}
+
+#[test]
+fn test() {
+    use crate::genprog::{generate, Workload};
+    use crate::llang::{Func, Op};
+    use crate::vm::VM;
+
+    let llang = generate(Workload::CallHeavy, 3);
+    let estimate = max_stack_estimate(&llang);
+    // add: 旧fp + ArgLoad 2つ
+    assert_eq!(estimate.funcs[&1], StackBound::Slots(3));
+    // main: 旧fp + ローカル2つ + (LocalLoad 2つ + 戻りアドレス + add)
+    assert_eq!(estimate.funcs[&0], StackBound::Slots(9));
+    assert_eq!(estimate.entry, StackBound::Slots(10));
+    assert_eq!(VM::new(llang.convert()).run(), Ok(3));
+
+    let estimate = max_stack_estimate(&generate(Workload::DeepRecursion, 3));
+    assert_eq!(estimate.funcs[&1], StackBound::Unbounded);
+    assert_eq!(estimate.entry, StackBound::Unbounded);
+
+    let estimate = max_stack_estimate(&LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops: vec![Op::Const(1), Op::Jump(0)],
+        }],
+    });
+    assert_eq!(estimate.entry, StackBound::Unbounded);
+}
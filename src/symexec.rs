@@ -0,0 +1,736 @@
+use crate::equiv::with_entry;
+use crate::llang::{LLang, Op};
+use crate::vm::{Value, VmError, VM};
+
+// 記録する経路の数の上限
+const MAX_PATHS: usize = 256;
+// 解を探す範囲。引数はこの絶対値以下の値から選ぶ
+const BOUND: i128 = 1 << 20;
+// 解を探すときに試す割り当ての数の上限
+const MAX_NODES: usize = 10_000;
+// 見つけた入力で本当にトラップするか実行して確かめるときの命令数の上限
+const CONFIRM_FUEL: u64 = 1_000_000;
+
+// 引数の一次式 coeffs[0] * ArgLoad(0) + coeffs[1] * ArgLoad(1) + ... + constant
+#[derive(Clone, Debug, PartialEq)]
+pub struct Linear {
+    pub coeffs: Vec<Value>,
+    pub constant: Value,
+}
+
+impl Linear {
+    fn constant(arity: usize, x: Value) -> Linear {
+        Linear {
+            coeffs: vec![0; arity],
+            constant: x,
+        }
+    }
+
+    fn arg(arity: usize, i: usize) -> Linear {
+        let mut lin = Linear::constant(arity, 0);
+        lin.coeffs[i] = 1;
+        lin
+    }
+
+    fn as_const(&self) -> Option<Value> {
+        if self.coeffs.iter().all(|&c| c == 0) {
+            Some(self.constant)
+        } else {
+            None
+        }
+    }
+
+    // 係数が溢れればNone
+    fn add(&self, other: &Linear) -> Option<Linear> {
+        Some(Linear {
+            coeffs: self
+                .coeffs
+                .iter()
+                .zip(&other.coeffs)
+                .map(|(a, b)| a.checked_add(*b))
+                .collect::<Option<_>>()?,
+            constant: self.constant.checked_add(other.constant)?,
+        })
+    }
+
+    fn scale(&self, k: Value) -> Option<Linear> {
+        Some(Linear {
+            coeffs: self
+                .coeffs
+                .iter()
+                .map(|c| c.checked_mul(k))
+                .collect::<Option<_>>()?,
+            constant: self.constant.checked_mul(k)?,
+        })
+    }
+
+    fn sub(&self, other: &Linear) -> Option<Linear> {
+        self.add(&other.scale(-1)?)
+    }
+}
+
+// 一次式と0との関係
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Rel {
+    Eq,
+    Ne,
+    Ge,
+}
+
+// lin REL 0
+#[derive(Clone, Debug, PartialEq)]
+pub struct Constraint {
+    pub lin: Linear,
+    pub rel: Rel,
+}
+
+impl Constraint {
+    fn new(lin: Linear, rel: Rel) -> Constraint {
+        Constraint { lin, rel }
+    }
+
+    // 成り立たないときの条件
+    fn negate(&self) -> Option<Constraint> {
+        Some(match self.rel {
+            Rel::Eq => Constraint::new(self.lin.clone(), Rel::Ne),
+            Rel::Ne => Constraint::new(self.lin.clone(), Rel::Eq),
+            // lin < 0 は -lin - 1 >= 0
+            Rel::Ge => {
+                let arity = self.lin.coeffs.len();
+                let lin = self.lin.scale(-1)?.add(&Linear::constant(arity, -1))?;
+                Constraint::new(lin, Rel::Ge)
+            }
+        })
+    }
+
+    fn holds(&self, value: i128) -> bool {
+        match self.rel {
+            Rel::Eq => value == 0,
+            Rel::Ne => value != 0,
+            Rel::Ge => value >= 0,
+        }
+    }
+}
+
+// constraintsをすべて満たす引数を、BOUNDの範囲で0に近いものから探す
+// 変数の範囲を制約から狭めながら、引数を先頭から一つずつ決めていく。見つからないか諦めればNone
+pub fn solve(constraints: &[Constraint], arity: usize) -> Option<Vec<Value>> {
+    let mut nodes = 0;
+    search(constraints, vec![(-BOUND, BOUND); arity], 0, &mut nodes)
+}
+
+fn search(
+    constraints: &[Constraint],
+    mut bounds: Vec<(i128, i128)>,
+    var: usize,
+    nodes: &mut usize,
+) -> Option<Vec<Value>> {
+    *nodes += 1;
+    if *nodes > MAX_NODES || !propagate(constraints, &mut bounds) {
+        return None;
+    }
+    if var == bounds.len() {
+        return Some(bounds.iter().map(|&(x, _)| x as Value).collect());
+    }
+    let (lo, hi) = bounds[var];
+    let start = 0.max(lo).min(hi);
+    // startから両側に広げていく
+    for i in 0..=2 * (hi - lo) {
+        let x = if i % 2 == 0 {
+            start + i / 2
+        } else {
+            start - (i + 1) / 2
+        };
+        if x < lo || hi < x {
+            continue;
+        }
+        let mut next = bounds.clone();
+        next[var] = (x, x);
+        if let Some(args) = search(constraints, next, var + 1, nodes) {
+            return Some(args);
+        }
+        if *nodes > MAX_NODES {
+            return None;
+        }
+    }
+    None
+}
+
+// 各変数の範囲を制約から狭める。矛盾すればfalse
+fn propagate(constraints: &[Constraint], bounds: &mut [(i128, i128)]) -> bool {
+    for _ in 0..64 {
+        let mut changed = false;
+        for c in constraints {
+            let ok = match c.rel {
+                Rel::Ge => tighten(&c.lin, 1, bounds, &mut changed),
+                Rel::Eq => {
+                    tighten(&c.lin, 1, bounds, &mut changed)
+                        && tighten(&c.lin, -1, bounds, &mut changed)
+                }
+                // 範囲では表せないので、式の変数がすべて決まってから確かめる
+                Rel::Ne => fixed_value(&c.lin, bounds).is_none_or(|x| c.holds(x)),
+            };
+            if !ok {
+                return false;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    true
+}
+
+// sign * lin >= 0 になるよう範囲を狭める。満たせなければfalse
+fn tighten(lin: &Linear, sign: i128, bounds: &mut [(i128, i128)], changed: &mut bool) -> bool {
+    let coeff = |i: usize| sign * lin.coeffs[i] as i128;
+    // 項の取りうる最大値
+    let max_term = |i: usize, bounds: &[(i128, i128)]| {
+        let c = coeff(i);
+        if c > 0 {
+            c * bounds[i].1
+        } else {
+            c * bounds[i].0
+        }
+    };
+    let total =
+        sign * lin.constant as i128 + (0..bounds.len()).map(|i| max_term(i, bounds)).sum::<i128>();
+    if total < 0 {
+        return false;
+    }
+    for i in 0..bounds.len() {
+        let c = coeff(i);
+        if c == 0 {
+            continue;
+        }
+        // c * x >= -(ほかの項の最大値 + 定数)。先に狭めた分はtotalに反映しないが、緩いだけで正しい
+        let rest = max_term(i, bounds) - total;
+        let (lo, hi) = bounds[i];
+        let (lo2, hi2) = if c > 0 {
+            (lo.max(-floor_div(-rest, c)), hi)
+        } else {
+            (lo, hi.min(floor_div(rest, c)))
+        };
+        if lo2 > hi2 {
+            return false;
+        }
+        if (lo2, hi2) != (lo, hi) {
+            bounds[i] = (lo2, hi2);
+            *changed = true;
+        }
+    }
+    true
+}
+
+fn fixed_value(lin: &Linear, bounds: &[(i128, i128)]) -> Option<i128> {
+    let mut value = lin.constant as i128;
+    for (&c, &(lo, hi)) in lin.coeffs.iter().zip(bounds) {
+        if c != 0 {
+            if lo != hi {
+                return None;
+            }
+            value += c as i128 * lo;
+        }
+    }
+    Some(value)
+}
+
+fn floor_div(a: i128, b: i128) -> i128 {
+    let q = a / b;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        q - 1
+    } else {
+        q
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Sym {
+    Lin(Linear),
+    // 条件が成り立てば1、そうでなければ0
+    Cond(Constraint),
+    // 引数の一次式で表せない値。これで分岐しても制約は増えない
+    Unknown,
+}
+
+impl Sym {
+    // 式が定数なら成り立つかどうかを値にする
+    fn cond(c: Constraint) -> Sym {
+        match c.lin.as_const() {
+            Some(x) => Sym::Lin(Linear::constant(
+                c.lin.coeffs.len(),
+                if c.holds(x as i128) { 1 } else { 0 },
+            )),
+            None => Sym::Cond(c),
+        }
+    }
+
+    fn linear(&self) -> Option<&Linear> {
+        match self {
+            Sym::Lin(lin) => Some(lin),
+            _ => None,
+        }
+    }
+
+    // 0でない条件と0である条件
+    fn truth(&self) -> Option<(Constraint, Constraint)> {
+        match self {
+            Sym::Lin(lin) => Some((
+                Constraint::new(lin.clone(), Rel::Ne),
+                Constraint::new(lin.clone(), Rel::Eq),
+            )),
+            Sym::Cond(c) => Some((c.clone(), c.negate()?)),
+            Sym::Unknown => None,
+        }
+    }
+}
+
+// x = pop, y = pop として x OP y
+fn arith(op: &Op, x: &Sym, y: &Sym, arity: usize) -> Sym {
+    let (x, y) = match (x.linear(), y.linear()) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Sym::Unknown,
+    };
+    let res = match (op, x.as_const(), y.as_const()) {
+        (Op::Add, _, _) => x.add(y),
+        (Op::Sub, _, _) => x.sub(y),
+        (Op::Mul, Some(k), _) => y.scale(k),
+        (Op::Mul, _, Some(k)) => x.scale(k),
+        (_, Some(a), Some(b)) => concrete(op, a, b).map(|v| Linear::constant(arity, v)),
+        _ => None,
+    };
+    res.map_or(Sym::Unknown, Sym::Lin)
+}
+
+// VMの既定と同じく溢れたら折り返す
+fn concrete(op: &Op, x: Value, y: Value) -> Option<Value> {
+    Some(match op {
+        Op::Div if y != 0 => x.wrapping_div(y),
+        Op::Mod if y != 0 => x.wrapping_rem(y),
+        Op::And => x & y,
+        Op::Or => x | y,
+        Op::Xor => x ^ y,
+        Op::Shl => x.wrapping_shl(y as u32),
+        Op::Shr => x.wrapping_shr(y as u32),
+        _ => return None,
+    })
+}
+
+fn compare(op: &Op, x: &Sym, y: &Sym) -> Sym {
+    let d = match (x.linear(), y.linear()) {
+        (Some(x), Some(y)) => x.sub(y),
+        _ => None,
+    };
+    let d = match d {
+        Some(d) => d,
+        None => return Sym::Unknown,
+    };
+    let ge = |lin: Linear| Constraint::new(lin, Rel::Ge);
+    let c = match op {
+        Op::Eq => Some(Constraint::new(d, Rel::Eq)),
+        Op::Ne => Some(Constraint::new(d, Rel::Ne)),
+        Op::Ge => Some(ge(d)),
+        Op::Lt => ge(d).negate(),
+        Op::Le => d.scale(-1).map(ge),
+        Op::Gt => d.scale(-1).and_then(|d| ge(d).negate()),
+        _ => unreachable!(),
+    };
+    c.map_or(Sym::Unknown, Sym::cond)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum End {
+    // 関数の末尾まで実行した
+    Return,
+    // ops[pc]のDiv, Modで0で割る。Unknownで割る経路は0になるとは限らない
+    DivByZero(usize),
+    // 命令数の上限に達したか、扱えない命令に出会った
+    Cut,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path {
+    pub constraints: Vec<Constraint>,
+    pub end: End,
+    // constraintsを満たす引数のひとつ
+    pub args: Vec<Value>,
+}
+
+#[derive(Clone)]
+struct State {
+    pc: usize,
+    stack: Vec<Sym>,
+    locals: Vec<Sym>,
+    args: Vec<Sym>,
+    constraints: Vec<Constraint>,
+    steps: usize,
+}
+
+impl State {
+    // 関数の中で積んだ値より多くは取り出せない
+    fn pop(&mut self) -> Result<Sym, Option<End>> {
+        self.stack.pop().ok_or(Some(End::Cut))
+    }
+
+    // cを経路の条件に足す。満たせなくなればfalse
+    fn assume(&mut self, c: Constraint) -> bool {
+        if let Some(x) = c.lin.as_const() {
+            return c.holds(x as i128);
+        }
+        let arity = c.lin.coeffs.len();
+        self.constraints.push(c);
+        solve(&self.constraints, arity).is_some()
+    }
+}
+
+struct Explorer<'a> {
+    ops: &'a [Op],
+    arity: usize,
+    max_steps: usize,
+    work: Vec<State>,
+    paths: Vec<Path>,
+}
+
+impl<'a> Explorer<'a> {
+    fn finish(&mut self, state: State, end: End) {
+        if let Some(args) = solve(&state.constraints, self.arity) {
+            self.paths.push(Path {
+                constraints: state.constraints,
+                end,
+                args,
+            });
+        }
+    }
+
+    fn run(&mut self, mut state: State) {
+        let end = loop {
+            if state.pc == self.ops.len() {
+                break End::Return;
+            }
+            if state.steps == self.max_steps {
+                break End::Cut;
+            }
+            state.steps += 1;
+            match self.step(&mut state) {
+                Ok(()) => {}
+                Err(Some(end)) => break end,
+                // 条件を満たせない経路
+                Err(None) => return,
+            }
+        };
+        self.finish(state, end);
+    }
+
+    // 一命令進める。経路が終わればErr(Some(終わり方))、ありえない経路ならErr(None)
+    fn step(&mut self, state: &mut State) -> Result<(), Option<End>> {
+        let pc = state.pc;
+        let cut = || Some(End::Cut);
+        let op = self.ops.get(pc).ok_or_else(cut)?;
+        let value = match op {
+            Op::ArgLoad(i) => state.args.get(*i).cloned().ok_or_else(cut)?,
+            Op::ArgStore(i) => {
+                let x = state.pop()?;
+                *state.args.get_mut(*i).ok_or_else(cut)? = x;
+                state.pc += 1;
+                return Ok(());
+            }
+            Op::LocalLoad(i) => state.locals.get(*i).cloned().ok_or_else(cut)?,
+            Op::LocalStore(i) => {
+                let x = state.pop()?;
+                *state.locals.get_mut(*i).ok_or_else(cut)? = x;
+                state.pc += 1;
+                return Ok(());
+            }
+            Op::Const(x) => Sym::Lin(Linear::constant(self.arity, *x)),
+            Op::Add | Op::Sub | Op::Mul | Op::And | Op::Or | Op::Xor | Op::Shl | Op::Shr => {
+                let x = state.pop()?;
+                let y = state.pop()?;
+                arith(op, &x, &y, self.arity)
+            }
+            Op::Div | Op::Mod => {
+                let x = state.pop()?;
+                let y = state.pop()?;
+                match y.truth() {
+                    Some((nonzero, zero)) => {
+                        let mut trapped = state.clone();
+                        if trapped.assume(zero) {
+                            self.finish(trapped, End::DivByZero(pc));
+                        }
+                        if !state.assume(nonzero) {
+                            return Err(None);
+                        }
+                    }
+                    None => self.finish(state.clone(), End::DivByZero(pc)),
+                }
+                arith(op, &x, &y, self.arity)
+            }
+            Op::Eq | Op::Ne | Op::Lt | Op::Le | Op::Gt | Op::Ge => {
+                let x = state.pop()?;
+                let y = state.pop()?;
+                compare(op, &x, &y)
+            }
+            Op::Not => match state.pop()? {
+                Sym::Lin(lin) => Sym::cond(Constraint::new(lin, Rel::Eq)),
+                Sym::Cond(c) => c.negate().map_or(Sym::Unknown, Sym::cond),
+                Sym::Unknown => Sym::Unknown,
+            },
+            Op::JumpIf(target) => {
+                let x = state.pop()?;
+                let mut taken = state.clone();
+                taken.pc = *target;
+                match x.truth() {
+                    Some((nonzero, zero)) => {
+                        if taken.assume(nonzero) {
+                            self.work.push(taken);
+                        }
+                        if !state.assume(zero) {
+                            return Err(None);
+                        }
+                    }
+                    None => self.work.push(taken),
+                }
+                state.pc += 1;
+                return Ok(());
+            }
+            Op::Jump(target) => {
+                state.pc = *target;
+                return Ok(());
+            }
+            Op::PopR(n) => {
+                let x = state.pop()?;
+                for _ in 1..*n {
+                    state.pop()?;
+                }
+                x
+            }
+            Op::Dup => {
+                let x = state.pop()?;
+                state.stack.push(x.clone());
+                x
+            }
+            Op::Swap => {
+                let x = state.pop()?;
+                let y = state.pop()?;
+                state.stack.push(x);
+                y
+            }
+            Op::Pop | Op::Print => {
+                state.pop()?;
+                state.pc += 1;
+                return Ok(());
+            }
+            // 呼んだ先は追わない。VMと同じく戻りアドレスと戻り値を積み、引数と一緒にPopRで片付く
+            Op::Call(_) | Op::CallName(_) | Op::CallClosure => {
+                state.stack.push(Sym::Unknown);
+                Sym::Unknown
+            }
+            Op::CallIndirect => {
+                state.pop()?;
+                state.stack.push(Sym::Unknown);
+                Sym::Unknown
+            }
+            Op::StrLen => {
+                state.pop()?;
+                Sym::Unknown
+            }
+            Op::HeapLoad | Op::StrConcat | Op::StrEq => {
+                state.pop()?;
+                state.pop()?;
+                Sym::Unknown
+            }
+            Op::MakeClosure(_, n) => {
+                for _ in 0..*n {
+                    state.pop()?;
+                }
+                Sym::Unknown
+            }
+            Op::FuncRef(_) | Op::CaptureLoad(_) | Op::ReadInt | Op::Alloc(_) | Op::StrConst(_) => {
+                Sym::Unknown
+            }
+            Op::HeapStore | Op::NativeCall(_, _) => return Err(cut()),
+        };
+        state.stack.push(value);
+        state.pc += 1;
+        Ok(())
+    }
+}
+
+// 関数IDがfuncの関数をarity個の記号的な引数で実行し、分岐ごとの経路を集める
+// 一つの経路で実行する命令はmax_steps個までなので、ループしていても止まる
+pub fn explore(llang: &LLang, func: usize, arity: usize, max_steps: usize) -> Vec<Path> {
+    let func = match llang.funcs.iter().find(|f| f.id == func) {
+        Some(func) => func,
+        None => return Vec::new(),
+    };
+    let mut explorer = Explorer {
+        ops: &func.ops,
+        arity,
+        max_steps,
+        work: vec![State {
+            pc: 0,
+            stack: Vec::new(),
+            locals: vec![Sym::Unknown; func.local_count],
+            args: (0..arity)
+                .map(|i| Sym::Lin(Linear::arg(arity, i)))
+                .collect(),
+            constraints: Vec::new(),
+            steps: 0,
+        }],
+        paths: Vec::new(),
+    };
+    while let Some(state) = explorer.work.pop() {
+        if explorer.paths.len() >= MAX_PATHS {
+            break;
+        }
+        explorer.run(state);
+    }
+    explorer.paths
+}
+
+// 0で割る入力。pcはDiv, Modの関数内の位置
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trap {
+    pub pc: usize,
+    pub args: Vec<Value>,
+}
+
+// funcを呼ぶと0で割る入力を、割る命令ごとに一つ探す。どれも実際に実行して確かめてある
+pub fn find_traps(llang: &LLang, func: usize, arity: usize, max_steps: usize) -> Vec<Trap> {
+    let mut traps: Vec<Trap> = Vec::new();
+    for path in explore(llang, func, arity, max_steps) {
+        if let End::DivByZero(pc) = path.end {
+            if traps.iter().all(|t| t.pc != pc) && divides_by_zero(llang, func, &path.args) {
+                traps.push(Trap {
+                    pc,
+                    args: path.args,
+                });
+            }
+        }
+    }
+    traps
+}
+
+fn divides_by_zero(llang: &LLang, func: usize, args: &[Value]) -> bool {
+    VM::new(with_entry(llang, func, args).convert()).run_with_fuel(CONFIRM_FUEL)
+        == Err(VmError::DivByZero)
+}
+
+#[test]
+fn test() {
+    use crate::llang::Func;
+
+    let program = |ops| LLang {
+        entry: 0,
+        funcs: vec![Func {
+            id: 0,
+            name: None,
+            local_count: 0,
+            ops,
+        }],
+    };
+
+    // a > 5 なら 100 / (a - 2 * b)、そうでなければ0
+    let unguarded = program(vec![
+        Op::Const(5),
+        Op::ArgLoad(0),
+        Op::Gt,
+        Op::JumpIf(6),
+        Op::Const(0),
+        Op::Jump(13),
+        Op::ArgLoad(1),
+        Op::Const(2),
+        Op::Mul,
+        Op::ArgLoad(0),
+        Op::Sub,
+        Op::Const(100),
+        Op::Div,
+    ]);
+    let paths = explore(&unguarded, 0, 2, 100);
+    assert_eq!(
+        paths.iter().map(|p| p.end.clone()).collect::<Vec<_>>(),
+        vec![End::Return, End::DivByZero(12), End::Return]
+    );
+    assert_eq!(
+        find_traps(&unguarded, 0, 2, 100),
+        vec![Trap {
+            pc: 12,
+            args: vec![6, 3]
+        }]
+    );
+
+    // b != 0 なら a / b、そうでなければ0
+    let guarded = program(vec![
+        Op::ArgLoad(1),
+        Op::JumpIf(4),
+        Op::Const(0),
+        Op::Jump(7),
+        Op::ArgLoad(1),
+        Op::ArgLoad(0),
+        Op::Div,
+    ]);
+    assert_eq!(explore(&guarded, 0, 2, 100).len(), 2);
+    assert_eq!(find_traps(&guarded, 0, 2, 100), vec![]);
+
+    // 呼び出しの結果を捨ててから 100 / (a - 7)
+    let mut llang = program(vec![
+        Op::Call(1),
+        Op::PopR(2),
+        Op::Pop,
+        Op::Const(7),
+        Op::ArgLoad(0),
+        Op::Sub,
+        Op::Const(100),
+        Op::Div,
+    ]);
+    llang.funcs.push(Func {
+        id: 1,
+        name: None,
+        local_count: 0,
+        ops: vec![Op::Const(7)],
+    });
+    assert_eq!(
+        find_traps(&llang, 0, 1, 100),
+        vec![Trap {
+            pc: 7,
+            args: vec![7]
+        }]
+    );
+    // 呼び出しの結果で割る場合も、途中で打ち切られずに最後まで進む
+    llang.funcs[0].ops.splice(2..4, vec![]);
+    assert_eq!(
+        explore(&llang, 0, 1, 100)
+            .iter()
+            .map(|p| p.end.clone())
+            .collect::<Vec<_>>(),
+        vec![End::DivByZero(5), End::Return]
+    );
+
+    // 終わらないループも命令数で打ち切る
+    let spin = program(vec![Op::Jump(0)]);
+    assert_eq!(
+        explore(&spin, 0, 0, 100)
+            .iter()
+            .map(|p| p.end.clone())
+            .collect::<Vec<_>>(),
+        vec![End::Cut]
+    );
+
+    let lin = |coeffs: Vec<Value>, constant| Linear { coeffs, constant };
+    // a + b == 10, a - b >= 4, b != 3
+    assert_eq!(
+        solve(
+            &[
+                Constraint::new(lin(vec![1, 1], -10), Rel::Eq),
+                Constraint::new(lin(vec![1, -1], -4), Rel::Ge),
+                Constraint::new(lin(vec![0, 1], -3), Rel::Ne),
+            ],
+            2
+        ),
+        Some(vec![8, 2])
+    );
+    // 2a == 1 に整数解はない
+    assert_eq!(
+        solve(&[Constraint::new(lin(vec![2], -1), Rel::Eq)], 1),
+        None
+    );
+}
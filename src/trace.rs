@@ -0,0 +1,344 @@
+use crate::varint::{read_varint, unzigzag, write_varint, zigzag};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter;
+
+// トレースの一要素。pcの差分で持つ
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Token {
+    // pcが1ずつn回進んだ
+    Steps(usize),
+    // pcがd進んだ(分岐・呼び出し・戻り)
+    Jump(isize),
+    // 直前の2要素(ループ1周分)をさらにn回繰り返した
+    Repeat(usize),
+}
+
+// トレース中で同じpcの範囲を繰り返した部分
+#[derive(Clone, Debug, PartialEq)]
+pub struct Loop {
+    pub start: usize,
+    pub end: usize,
+    // 最初の周回を含めた周回数
+    pub iterations: usize,
+}
+
+impl fmt::Display for Loop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "loop at {}..{} executed {} times",
+            self.start, self.end, self.iterations
+        )
+    }
+}
+
+// 実行した命令のpcの列を圧縮して持つ
+// 命令そのものはプログラムとpcから復元できるので記録しない
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Trace {
+    tokens: Vec<Token>,
+    last: Option<usize>,
+}
+
+impl Trace {
+    pub fn new() -> Trace {
+        Trace::default()
+    }
+
+    pub fn record(&mut self, pc: usize) {
+        match self.last {
+            Some(last) if pc == last + 1 => match self.tokens.last_mut() {
+                Some(Token::Steps(n)) => *n += 1,
+                _ => self.tokens.push(Token::Steps(1)),
+            },
+            last => self.push_jump(pc as isize - last.unwrap_or(0) as isize),
+        }
+        self.last = Some(pc);
+    }
+
+    fn push_jump(&mut self, d: isize) {
+        self.tokens.push(Token::Jump(d));
+        let n = self.tokens.len();
+        if n < 4 || matches!(self.tokens[n - 2], Token::Repeat(_)) {
+            return;
+        }
+        if let Token::Repeat(count) = self.tokens[n - 3] {
+            if n >= 5 && self.tokens[n - 5..n - 3] == self.tokens[n - 2..] {
+                self.tokens.truncate(n - 2);
+                self.tokens[n - 3] = Token::Repeat(count + 1);
+            }
+        } else if self.tokens[n - 4..n - 2] == self.tokens[n - 2..] {
+            self.tokens.truncate(n - 2);
+            self.tokens.push(Token::Repeat(1));
+        }
+    }
+
+    // 記録した命令数
+    pub fn len(&self) -> usize {
+        measure(&self.tokens).map_or(0, |(len, _)| len as usize)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    // 記録したpcを順に返す。pcが負になるか溢れたらそこで終わる
+    pub fn pcs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.units().flat_map(deltas).scan(0isize, |pc, d| {
+            *pc = pc.checked_add(d)?;
+            usize::try_from(*pc).ok()
+        })
+    }
+
+    // 1周分のpcの範囲と周回数を、現れた順に返す
+    pub fn loops(&self) -> Vec<Loop> {
+        let mut loops = Vec::new();
+        let mut pc = 0isize;
+        for (i, token) in self.tokens.iter().enumerate() {
+            let count = match token {
+                Token::Repeat(count) => *count,
+                token => {
+                    pc += deltas(token).sum::<isize>();
+                    continue;
+                }
+            };
+            let body = self.tokens[i - 2..i]
+                .iter()
+                .flat_map(deltas)
+                .scan(pc, |pc, d| {
+                    *pc += d;
+                    Some(*pc)
+                })
+                .collect::<Vec<_>>();
+            match body.last() {
+                // 1周で元のpcに戻るものだけをループとみなす
+                Some(&last) if last == pc => loops.push(Loop {
+                    start: *body.iter().min().unwrap() as usize,
+                    end: *body.iter().max().unwrap() as usize,
+                    iterations: count + 2,
+                }),
+                Some(&last) => pc += (last - pc) * count as isize,
+                None => {}
+            }
+        }
+        loops
+    }
+
+    // ループを1行ずつにまとめた要約
+    pub fn report(&self) -> String {
+        self.loops().iter().map(|l| format!("{}\n", l)).collect()
+    }
+
+    // Repeatを展開した要素列
+    fn units(&self) -> impl Iterator<Item = &Token> + '_ {
+        let tokens = &self.tokens;
+        tokens.iter().enumerate().flat_map(move |(i, token)| {
+            let (unit, count) = match token {
+                Token::Repeat(count) => (&tokens[i - 2..i], *count),
+                _ => (&tokens[i..=i], 1),
+            };
+            unit.iter().cycle().take(unit.len() * count)
+        })
+    }
+
+    // 先頭に命令数、続けて各要素を (値 << 2 | 種類) のLEB128で並べる。Jumpの値はzigzag符号化する
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, self.len() as u64);
+        for token in &self.tokens {
+            let x = match *token {
+                Token::Steps(n) => (n as u64) << 2,
+                Token::Jump(d) => (zigzag(d as i64) << 2) | 1,
+                Token::Repeat(n) => ((n as u64) << 2) | 2,
+            };
+            write_varint(&mut bytes, x);
+        }
+        bytes
+    }
+
+    // 命令数が先頭の値と合わないか、途中でpcが負になるか溢れるならNone
+    pub fn from_bytes(mut bytes: &[u8]) -> Option<Trace> {
+        let declared = read_varint(&mut bytes)?;
+        let mut tokens = Vec::new();
+        while !bytes.is_empty() {
+            let x = read_varint(&mut bytes)?;
+            let value = x >> 2;
+            tokens.push(match x & 3 {
+                0 => Token::Steps(value as usize),
+                1 => Token::Jump(unzigzag(value) as isize),
+                2 if tokens.len() >= 2
+                    && !tokens[tokens.len() - 2..]
+                        .iter()
+                        .any(|t| matches!(t, Token::Repeat(_))) =>
+                {
+                    Token::Repeat(value as usize)
+                }
+                _ => return None,
+            });
+        }
+        if !matches!(tokens.first(), None | Some(Token::Jump(_))) {
+            return None;
+        }
+        let (len, last) = measure(&tokens)?;
+        if len != declared {
+            return None;
+        }
+        Some(Trace {
+            last: if tokens.is_empty() { None } else { Some(last) },
+            tokens,
+        })
+    }
+}
+
+// Repeatを展開せずに、命令数と最後のpcを求める。pcが負になるか溢れるならNone
+fn measure(tokens: &[Token]) -> Option<(u64, usize)> {
+    let mut len = 0u64;
+    let mut pc = 0isize;
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            Token::Steps(n) => {
+                pc = pc.checked_add(isize::try_from(n).ok()?)?;
+                len = len.checked_add(n as u64)?;
+            }
+            Token::Jump(d) => {
+                pc = pc.checked_add(d)?;
+                if pc < 0 {
+                    return None;
+                }
+                len = len.checked_add(1)?;
+            }
+            Token::Repeat(count) => {
+                // 1周分のpcの増分と命令数、周の始まりから見たpcの最小と最大
+                let (mut d, mut n, mut lo, mut hi) = (0isize, 0u64, 0isize, 0isize);
+                for token in &tokens[i - 2..i] {
+                    d = d.checked_add(deltas(token).sum())?;
+                    n += deltas(token).count() as u64;
+                    lo = lo.min(d);
+                    hi = hi.max(d);
+                }
+                let count = isize::try_from(count).ok()?;
+                let end = pc.checked_add(d.checked_mul(count)?)?;
+                // 各周の始まりはpcから最後の周の始まりまで等間隔に並ぶ
+                let last_start = end - d;
+                if pc.min(last_start) + lo < 0 {
+                    return None;
+                }
+                pc.max(last_start).checked_add(hi)?;
+                len = len.checked_add(n.checked_mul(count as u64)?)?;
+                pc = end;
+            }
+        }
+    }
+    Some((len, pc as usize))
+}
+
+// 要素を実行したときのpcの増分の列
+fn deltas(token: &Token) -> iter::Take<iter::Repeat<isize>> {
+    match token {
+        Token::Steps(n) => iter::repeat(1).take(*n),
+        Token::Jump(d) => iter::repeat(*d).take(1),
+        Token::Repeat(_) => iter::repeat(0).take(0),
+    }
+}
+
+#[test]
+fn test() {
+    // 0..3を実行してから、5..8のループを3周して戻る
+    let pcs = vec![0, 1, 2, 5, 6, 7, 5, 6, 7, 5, 6, 7, 3, 2];
+    let mut trace = Trace::new();
+    for &pc in &pcs {
+        trace.record(pc);
+    }
+    assert_eq!(
+        trace.tokens,
+        vec![
+            Token::Jump(0),
+            Token::Steps(2),
+            Token::Jump(3),
+            Token::Steps(2),
+            Token::Jump(-2),
+            Token::Repeat(1),
+            Token::Steps(2),
+            Token::Jump(-4),
+            Token::Jump(-1),
+        ]
+    );
+    assert_eq!(trace.len(), pcs.len());
+    assert_eq!(trace.pcs().collect::<Vec<_>>(), pcs);
+    assert_eq!(
+        trace.loops(),
+        vec![Loop {
+            start: 5,
+            end: 7,
+            iterations: 3
+        }]
+    );
+    assert_eq!(trace.report(), "loop at 5..7 executed 3 times\n");
+
+    let bytes = trace.to_bytes();
+    assert_eq!(Trace::from_bytes(&bytes), Some(trace));
+    assert_eq!(Trace::from_bytes(&[0x80]), None);
+    assert_eq!(Trace::from_bytes(&[3]), None);
+    // 命令数が合わない
+    let mut wrong = bytes.clone();
+    wrong[0] += 1;
+    assert_eq!(Trace::from_bytes(&wrong), None);
+
+    let encode = |len: u64, tokens: &[Token]| {
+        let trace = Trace {
+            tokens: tokens.to_vec(),
+            last: None,
+        };
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, len);
+        bytes.extend_from_slice(&trace.to_bytes()[1..]);
+        bytes
+    };
+    // 途中でpcが負になる
+    let negative = [
+        Token::Jump(5),
+        Token::Steps(1),
+        Token::Jump(-3),
+        Token::Repeat(2),
+    ];
+    assert_eq!(Trace::from_bytes(&encode(7, &negative)), None);
+    // 周回数で溢れる
+    let huge = [
+        Token::Jump(0),
+        Token::Steps(1),
+        Token::Jump(1 << 40),
+        Token::Repeat(1 << 40),
+    ];
+    assert_eq!(Trace::from_bytes(&encode(u64::MAX, &huge)), None);
+    let far = Trace {
+        tokens: vec![Token::Jump(isize::MAX), Token::Steps(1)],
+        last: None,
+    };
+    assert_eq!(far.pcs().collect::<Vec<_>>(), vec![isize::MAX as usize]);
+}
+
+#[test]
+#[cfg(feature = "frontend")]
+fn test_vm() {
+    use crate::genprog::{generate, Workload};
+    use crate::vm::VM;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let count = Rc::new(Cell::new(0));
+    let mut vm = VM::new(generate(Workload::ArithmeticHeavy, 1000).convert());
+    vm.enable_trace();
+    let counter = count.clone();
+    vm.every_n_instructions(1, move |_| counter.set(counter.get() + 1));
+    vm.run().unwrap();
+    let trace = vm.trace().unwrap();
+    assert_eq!(trace.len(), count.get());
+    assert_eq!(trace.pcs().count(), count.get());
+    assert_eq!(trace.pcs().next(), Some(0));
+    // 1000周のループがRepeatにまとまる
+    assert!(trace.to_bytes().len() < 32);
+    let loops = trace.loops();
+    assert_eq!(loops.len(), 1);
+    assert_eq!(loops[0].iterations, 1000);
+}
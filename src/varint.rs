@@ -0,0 +1,45 @@
+// LEB128の可変長整数
+
+pub(crate) fn write_varint(bytes: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        bytes.push((x as u8) | 0x80);
+        x >>= 7;
+    }
+    bytes.push(x as u8);
+}
+
+// 読めた分だけbytesを進める。途中で終わっていたらNone
+pub(crate) fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut x = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = bytes.split_first()?;
+        *bytes = rest;
+        x |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(x);
+        }
+    }
+    None
+}
+
+// 絶対値の小さい負数も短くなるように符号ビットを最下位に移す
+pub(crate) fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+pub(crate) fn unzigzag(x: u64) -> i64 {
+    ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+#[test]
+fn test() {
+    for &x in &[0, 1, -1, 63, -64, 64, i64::MAX, i64::MIN] {
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, zigzag(x));
+        let mut rest = &bytes[..];
+        assert_eq!(read_varint(&mut rest).map(unzigzag), Some(x));
+        assert!(rest.is_empty());
+    }
+    assert_eq!(zigzag(-1), 1);
+    assert_eq!(read_varint(&mut &[0x80][..]), None);
+}
@@ -0,0 +1,746 @@
+use crate::vm::{Cmd, FuncFlags};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub pc: usize,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn error(pc: usize, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            pc,
+            message,
+        }
+    }
+
+    pub fn warning(pc: usize, message: String) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            pc,
+            message,
+        }
+    }
+}
+
+pub fn verify(program: &[Cmd]) -> Vec<Diagnostic> {
+    let program = &absolute(program);
+    let mut diagnostics = Vec::new();
+    match program.first() {
+        Some(Cmd::Entry(_)) => {}
+        _ => {
+            diagnostics.push(Diagnostic::error(
+                0,
+                "program must start with Entry".to_string(),
+            ));
+            return diagnostics;
+        }
+    }
+
+    let owners = owners(program);
+
+    let mut called = vec![false; program.len()];
+    let referenced = referenced_funcs(program);
+    for (pc, cmd) in program.iter().enumerate() {
+        match cmd {
+            Cmd::Entry(i) | Cmd::Call(i) | Cmd::TailCall(i, _) => match program.get(*i) {
+                Some(Cmd::Frame(_, _)) => called[*i] = true,
+                _ => diagnostics.push(Diagnostic::error(
+                    pc,
+                    format!("call target {} is not the start of a function", i),
+                )),
+            },
+            // 参照された関数は間接呼び出しで呼ばれうる
+            Cmd::FuncRef(i) | Cmd::MakeClosure(i, _) => match program.get(*i) {
+                Some(Cmd::Frame(_, _)) => called[*i] = true,
+                _ => diagnostics.push(Diagnostic::error(
+                    pc,
+                    format!("function reference {} is not the start of a function", i),
+                )),
+            },
+            Cmd::CallIndirect | Cmd::CallClosure if referenced.is_empty() => {
+                diagnostics.push(Diagnostic::warning(
+                    pc,
+                    "indirect call but no function is referenced".to_string(),
+                ))
+            }
+            Cmd::JumpIf(i) | Cmd::Jump(i) => {
+                if *i >= program.len() {
+                    diagnostics.push(Diagnostic::error(
+                        pc,
+                        format!("jump target {} is out of range", i),
+                    ));
+                } else if owners[*i] != owners[pc] {
+                    diagnostics.push(Diagnostic::warning(
+                        pc,
+                        format!("jump target {} is in another function", i),
+                    ));
+                }
+            }
+            Cmd::PopR(0) => diagnostics.push(Diagnostic::error(
+                pc,
+                "PopR(0) underflows the stack pointer".to_string(),
+            )),
+            Cmd::PopR(1) => {
+                diagnostics.push(Diagnostic::warning(pc, "PopR(1) has no effect".to_string()))
+            }
+            _ => {}
+        }
+    }
+
+    for (pc, cmd) in program.iter().enumerate() {
+        if let Cmd::Frame(_, _) = cmd {
+            if !called[pc] {
+                diagnostics.push(Diagnostic::warning(
+                    pc,
+                    "function is never called".to_string(),
+                ));
+            }
+        }
+    }
+
+    let reachable = reachable(program);
+    let mut pc = 0;
+    while pc < program.len() {
+        let in_called_fn = owners[pc].map(|f| called[f]).unwrap_or(true);
+        if reachable[pc] || !in_called_fn {
+            pc += 1;
+            continue;
+        }
+        let start = pc;
+        while pc < program.len() && !reachable[pc] && owners[pc] == owners[start] {
+            pc += 1;
+        }
+        diagnostics.push(Diagnostic::warning(
+            start,
+            format!("unreachable code at {}..{}", start, pc),
+        ));
+    }
+
+    if let Some(last) = program.len().checked_sub(1) {
+        match program[last] {
+            Cmd::Ret | Cmd::Jump(_) | Cmd::TailCall(_, _) => {}
+            _ if reachable[last] => diagnostics.push(Diagnostic::error(
+                last,
+                "execution can run past the end of the program".to_string(),
+            )),
+            _ => {}
+        }
+    }
+
+    for (pc, cmd) in program.iter().enumerate() {
+        if let Cmd::Frame(local_count, _) = cmd {
+            for (load_pc, i) in uninitialized_reads(program, &owners, pc, *local_count) {
+                diagnostics.push(Diagnostic::warning(
+                    load_pc,
+                    format!("local {} may be read before it is written", i),
+                ));
+            }
+        }
+    }
+
+    diagnostics.sort_by_key(|d| d.pc);
+    diagnostics
+}
+
+// 埋め込み側が追加する検査。verifyの結果に加えて返す
+pub trait Rule {
+    fn check(&self, cfg: &Cfg) -> Vec<Diagnostic>;
+}
+
+impl<F: Fn(&Cfg) -> Vec<Diagnostic>> Rule for F {
+    fn check(&self, cfg: &Cfg) -> Vec<Diagnostic> {
+        self(cfg)
+    }
+}
+
+// Ruleから見えるプログラムの情報。相対ジャンプ・呼び出しは絶対アドレスに直してある
+pub struct Cfg {
+    program: Vec<Cmd>,
+    owners: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl Cfg {
+    // labelsはアドレスから名前へ。アセンブラのSymbols::labelsをそのまま渡せる
+    pub fn new(program: &[Cmd], labels: BTreeMap<usize, String>) -> Cfg {
+        let program = absolute(program);
+        Cfg {
+            owners: owners(&program),
+            reachable: reachable(&program),
+            program,
+            labels,
+        }
+    }
+
+    pub fn program(&self) -> &[Cmd] {
+        &self.program
+    }
+
+    // pcの命令が属する関数の先頭アドレス
+    pub fn owner(&self, pc: usize) -> Option<usize> {
+        self.owners.get(pc).cloned().flatten()
+    }
+
+    // Entryから辿れるか
+    pub fn is_reachable(&self, pc: usize) -> bool {
+        self.reachable.get(pc).cloned().unwrap_or(false)
+    }
+
+    // 関数内で次に実行しうる命令。Callは戻ってきた後の命令に繋ぎ、RetとTailCallには後続がない
+    pub fn successors(&self, pc: usize) -> Vec<usize> {
+        match self.program.get(pc) {
+            Some(Cmd::Entry(i)) | Some(Cmd::Jump(i)) => vec![*i],
+            Some(Cmd::JumpIf(i)) => vec![pc + 1, *i],
+            Some(Cmd::Ret) | Some(Cmd::TailCall(_, _)) | None => vec![],
+            Some(_) => vec![pc + 1],
+        }
+    }
+
+    // 関数の先頭アドレスの一覧
+    pub fn funcs(&self) -> Vec<usize> {
+        (0..self.program.len())
+            .filter(|&pc| matches!(self.program[pc], Cmd::Frame(_, _)))
+            .collect()
+    }
+
+    // funcが直接呼ぶ関数。CallIndirect, CallClosureは参照されているどの関数も呼びうるとみなす
+    pub fn callees(&self, func: usize) -> BTreeSet<usize> {
+        let mut callees = BTreeSet::new();
+        for pc in (0..self.program.len()).filter(|&pc| self.owner(pc) == Some(func)) {
+            match self.program[pc] {
+                Cmd::Call(callee) | Cmd::TailCall(callee, _) => {
+                    callees.insert(callee);
+                }
+                Cmd::CallIndirect | Cmd::CallClosure => callees.extend(self.referenced_funcs()),
+                _ => {}
+            }
+        }
+        callees
+    }
+
+    // FuncRef, MakeClosureで参照されている関数
+    pub fn referenced_funcs(&self) -> BTreeSet<usize> {
+        referenced_funcs(&self.program)
+    }
+
+    // funcから呼び出しを辿って実行しうる関数。func自身を含む
+    pub fn callable_from(&self, func: usize) -> BTreeSet<usize> {
+        let mut funcs = BTreeSet::new();
+        let mut work = vec![func];
+        while let Some(func) = work.pop() {
+            if funcs.insert(func) {
+                work.extend(self.callees(func));
+            }
+        }
+        funcs
+    }
+
+    pub fn label(&self, pc: usize) -> Option<&str> {
+        self.labels.get(&pc).map(|s| s.as_str())
+    }
+
+    pub fn find_label(&self, name: &str) -> Option<usize> {
+        self.labels
+            .iter()
+            .find(|(_, label)| label.as_str() == name)
+            .map(|(pc, _)| *pc)
+    }
+}
+
+// verifyに追加の検査を足して実行する
+#[derive(Default)]
+pub struct Verifier {
+    rules: Vec<Box<dyn Rule>>,
+    labels: BTreeMap<usize, String>,
+}
+
+impl Verifier {
+    pub fn new() -> Verifier {
+        Verifier::default()
+    }
+
+    pub fn add_rule(&mut self, rule: Box<dyn Rule>) {
+        self.rules.push(rule);
+    }
+
+    // Cfg::labelで引ける名前
+    pub fn set_labels(&mut self, labels: BTreeMap<usize, String>) {
+        self.labels = labels;
+    }
+
+    // 組み込みの検査と追加の検査の結果を、pc順にまとめて返す
+    pub fn verify(&self, program: &[Cmd]) -> Vec<Diagnostic> {
+        let mut diagnostics = verify(program);
+        if !self.rules.is_empty() {
+            let cfg = Cfg::new(program, self.labels.clone());
+            for rule in &self.rules {
+                diagnostics.extend(rule.check(&cfg));
+            }
+        }
+        diagnostics.sort_by_key(|d| d.pc);
+        diagnostics
+    }
+}
+
+// 関数の先頭アドレスごとのFuncFlagsを守っているか調べる。flagsにない関数はFuncFlags::ALL
+pub fn verify_flags(program: &[Cmd], flags: &HashMap<usize, FuncFlags>) -> Vec<Diagnostic> {
+    let program = &absolute(program);
+    let flags_of = |func: usize| flags.get(&func).cloned().unwrap_or(FuncFlags::ALL);
+    let owners = owners(program);
+    let referenced = referenced_funcs(program);
+    let mut diagnostics = Vec::new();
+    for (pc, cmd) in program.iter().enumerate() {
+        let func = match owners[pc] {
+            Some(func) => func,
+            None => continue,
+        };
+        match cmd {
+            // 参照されている関数のうち、一つでも呼べないものがあれば誤りにする
+            Cmd::CallIndirect | Cmd::CallClosure => {
+                for callee in &referenced {
+                    if !flags_of(func).allows(flags_of(*callee)) {
+                        diagnostics.push(Diagnostic::error(
+                            pc,
+                            format!("function {} may not call function {}", func, callee),
+                        ))
+                    }
+                }
+            }
+            Cmd::Call(callee) | Cmd::TailCall(callee, _)
+                if !flags_of(func).allows(flags_of(*callee)) =>
+            {
+                diagnostics.push(Diagnostic::error(
+                    pc,
+                    format!("function {} may not call function {}", func, callee),
+                ))
+            }
+            Cmd::Alloc(_) | Cmd::StrConst(_) | Cmd::StrConcat | Cmd::MakeClosure(_, _)
+                if !flags_of(func).may_allocate =>
+            {
+                diagnostics.push(Diagnostic::error(
+                    pc,
+                    format!("function {} may not allocate", func),
+                ))
+            }
+            Cmd::NativeCall(_) | Cmd::Print | Cmd::ReadInt if !flags_of(func).may_call_host => {
+                diagnostics.push(Diagnostic::error(
+                    pc,
+                    format!("function {} may not call the host", func),
+                ))
+            }
+            _ => {}
+        }
+    }
+    diagnostics
+}
+
+// 相対ジャンプ・呼び出しを絶対アドレスに直したプログラム。以降の検査は絶対アドレスのものだけを扱う
+fn absolute(program: &[Cmd]) -> Vec<Cmd> {
+    program
+        .iter()
+        .enumerate()
+        .map(|(pc, cmd)| cmd.absolute(pc))
+        .collect()
+}
+
+fn referenced_funcs(program: &[Cmd]) -> BTreeSet<usize> {
+    program
+        .iter()
+        .filter_map(|cmd| match cmd {
+            Cmd::FuncRef(i) | Cmd::MakeClosure(i, _) => Some(*i),
+            _ => None,
+        })
+        .collect()
+}
+
+// 各命令が属する関数の先頭(Frame)のアドレス
+fn owners(program: &[Cmd]) -> Vec<Option<usize>> {
+    let mut owners = Vec::with_capacity(program.len());
+    let mut owner = None;
+    for (pc, cmd) in program.iter().enumerate() {
+        if let Cmd::Frame(_, _) = cmd {
+            owner = Some(pc);
+        }
+        owners.push(owner);
+    }
+    owners
+}
+
+// frameから始まる関数内で、初期化されていない可能性のあるローカル変数を読むLocalLoadを探す
+fn uninitialized_reads(
+    program: &[Cmd],
+    owners: &[Option<usize>],
+    frame: usize,
+    local_count: usize,
+) -> Vec<(usize, usize)> {
+    // 各命令の実行前に確実に初期化されているローカル変数
+    let mut states: Vec<Option<Vec<bool>>> = vec![None; program.len()];
+    let mut work = vec![(frame + 1, vec![false; local_count])];
+    while let Some((pc, state)) = work.pop() {
+        if pc >= program.len() || owners[pc] != Some(frame) {
+            continue;
+        }
+        let merged = match &states[pc] {
+            Some(old) => {
+                let merged = old
+                    .iter()
+                    .zip(&state)
+                    .map(|(a, b)| *a && *b)
+                    .collect::<Vec<_>>();
+                if &merged == old {
+                    continue;
+                }
+                merged
+            }
+            None => state,
+        };
+        states[pc] = Some(merged.clone());
+
+        let mut next = merged;
+        match &program[pc] {
+            Cmd::LocalStore(i) => {
+                if let Some(x) = next.get_mut(*i) {
+                    *x = true;
+                }
+            }
+            Cmd::Ret | Cmd::TailCall(_, _) => continue,
+            Cmd::Jump(i) => {
+                work.push((*i, next));
+                continue;
+            }
+            Cmd::JumpIf(i) => work.push((*i, next.clone())),
+            _ => {}
+        }
+        work.push((pc + 1, next));
+    }
+
+    let mut reads = Vec::new();
+    for (pc, state) in states.iter().enumerate() {
+        if let (Some(state), Cmd::LocalLoad(i)) = (state, &program[pc]) {
+            if !state.get(*i).cloned().unwrap_or(false) {
+                reads.push((pc, *i));
+            }
+        }
+    }
+    reads
+}
+
+fn reachable(program: &[Cmd]) -> Vec<bool> {
+    let mut reachable = vec![false; program.len()];
+    let mut work = vec![0];
+    while let Some(pc) = work.pop() {
+        if pc >= program.len() || reachable[pc] {
+            continue;
+        }
+        reachable[pc] = true;
+        match &program[pc] {
+            Cmd::Entry(i) | Cmd::Jump(i) | Cmd::TailCall(i, _) => work.push(*i),
+            Cmd::Call(i) | Cmd::FuncRef(i) | Cmd::MakeClosure(i, _) | Cmd::JumpIf(i) => {
+                work.push(*i);
+                work.push(pc + 1);
+            }
+            Cmd::Ret => {}
+            _ => work.push(pc + 1),
+        }
+    }
+    reachable
+}
+
+#[test]
+fn test() {
+    assert_eq!(
+        verify(&[
+            Cmd::Entry(1),
+            Cmd::Frame(0, 4),
+            Cmd::Const(1),
+            Cmd::Const(2),
+            Cmd::Call(7),
+            Cmd::PopR(2),
+            Cmd::Ret,
+            Cmd::Frame(0, 2),
+            Cmd::ArgLoad(0),
+            Cmd::ArgLoad(1),
+            Cmd::Add,
+            Cmd::Ret
+        ]),
+        vec![]
+    );
+
+    assert_eq!(
+        verify(&[
+            Cmd::Entry(1),    // 0
+            Cmd::Frame(0, 1), // 1
+            Cmd::Const(1),    // 2
+            Cmd::PopR(1),     // 3
+            Cmd::Jump(7),     // 4
+            Cmd::Const(2),    // 5
+            Cmd::Ret,         // 6
+            Cmd::Frame(0, 1), // 7
+            Cmd::Const(3),    // 8
+            Cmd::Call(2),     // 9
+            Cmd::JumpIf(100), // 10
+        ]),
+        vec![
+            Diagnostic::warning(3, "PopR(1) has no effect".to_string()),
+            Diagnostic::warning(4, "jump target 7 is in another function".to_string()),
+            Diagnostic::warning(5, "unreachable code at 5..7".to_string()),
+            Diagnostic::warning(7, "function is never called".to_string()),
+            Diagnostic::error(
+                9,
+                "call target 2 is not the start of a function".to_string()
+            ),
+            Diagnostic::error(10, "jump target 100 is out of range".to_string()),
+            Diagnostic::error(
+                10,
+                "execution can run past the end of the program".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_uninitialized_reads() {
+    assert_eq!(
+        verify(&[
+            Cmd::Entry(1),      // 0
+            Cmd::Frame(2, 2),   // 1
+            Cmd::Const(1),      // 2
+            Cmd::JumpIf(5),     // 3
+            Cmd::Jump(7),       // 4
+            Cmd::Const(2),      // 5
+            Cmd::LocalStore(1), // 6
+            Cmd::Const(3),      // 7
+            Cmd::LocalStore(0), // 8
+            Cmd::LocalLoad(0),  // 9
+            Cmd::LocalLoad(1),  // 10
+            Cmd::Add,           // 11
+            Cmd::Ret,           // 12
+        ]),
+        vec![Diagnostic::warning(
+            10,
+            "local 1 may be read before it is written".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_verify_flags() {
+    let program = [
+        Cmd::Entry(1),    // 0
+        Cmd::Frame(0, 2), // 1
+        Cmd::Call(5),     // 2
+        Cmd::PopR(2),     // 3
+        Cmd::Ret,         // 4
+        Cmd::Frame(0, 1), // 5
+        Cmd::Alloc(1),    // 6
+        Cmd::Ret,         // 7
+    ];
+    let flags =
+        |flags: Vec<(usize, FuncFlags)>| verify_flags(&program, &flags.into_iter().collect());
+    assert_eq!(flags(vec![]), vec![]);
+    assert_eq!(
+        flags(vec![(1, FuncFlags::PURE), (5, FuncFlags::PURE)]),
+        vec![Diagnostic::error(
+            6,
+            "function 5 may not allocate".to_string()
+        )]
+    );
+    assert_eq!(
+        flags(vec![(1, FuncFlags::PURE)]),
+        vec![Diagnostic::error(
+            2,
+            "function 1 may not call function 5".to_string()
+        )]
+    );
+    assert_eq!(
+        verify_flags(
+            &[Cmd::Entry(1), Cmd::Frame(0, 1), Cmd::ReadInt, Cmd::Ret],
+            &vec![(1, FuncFlags::PURE)].into_iter().collect()
+        ),
+        vec![Diagnostic::error(
+            2,
+            "function 1 may not call the host".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_rules() {
+    use crate::asm::assemble_with_symbols;
+
+    let (program, symbols) = assemble_with_symbols(
+        "
+        entry main
+    main:
+        frame 0 4
+        call pricing
+        pop_r 2
+        ret
+    pricing:
+        frame 0 4
+        call helper
+        pop_r 2
+        ret
+    helper:
+        frame 0 4
+        const 0
+        const 0
+        alloc 1
+        heap_store
+        const 1
+        ret
+    ",
+        |_| Err(String::new()),
+    )
+    .unwrap();
+
+    let no_heap_store = |cfg: &Cfg| {
+        let pricing = match cfg.find_label("pricing") {
+            Some(pricing) => pricing,
+            None => return vec![],
+        };
+        let funcs = cfg.callable_from(pricing);
+        (0..cfg.program().len())
+            .filter(|&pc| cfg.program()[pc] == Cmd::HeapStore && cfg.is_reachable(pc))
+            .filter(|&pc| matches!(cfg.owner(pc), Some(f) if funcs.contains(&f)))
+            .map(|pc| {
+                Diagnostic::error(
+                    pc,
+                    format!(
+                        "HeapStore in `{}` is reachable from pricing",
+                        cfg.label(cfg.owner(pc).unwrap()).unwrap_or("?")
+                    ),
+                )
+            })
+            .collect()
+    };
+    let mut verifier = Verifier::new();
+    verifier.add_rule(Box::new(no_heap_store));
+    // 名前がなければ規則は何も言わない
+    assert_eq!(verifier.verify(&program), verify(&program));
+    verifier.set_labels(symbols.labels);
+    assert_eq!(
+        verifier.verify(&program),
+        vec![Diagnostic::error(
+            13,
+            "HeapStore in `helper` is reachable from pricing".to_string()
+        )]
+    );
+
+    let cfg = Cfg::new(&program, BTreeMap::new());
+    assert_eq!(cfg.funcs(), vec![1, 5, 9]);
+    assert_eq!(cfg.callees(1), vec![5].into_iter().collect());
+    assert_eq!(cfg.callable_from(5), vec![5, 9].into_iter().collect());
+    assert_eq!(cfg.successors(2), vec![3]);
+    assert_eq!(cfg.successors(4), vec![]);
+}
+
+#[test]
+fn test_indirect_calls() {
+    use crate::asm::assemble;
+
+    let program = assemble(
+        "
+        entry main
+    main:
+        frame 0 3
+        const 5
+        func_ref double
+        call_indirect
+        pop_r 3
+        ret
+    double:
+        frame 0 2
+        arg_load 0
+        arg_load 0
+        add
+        ret
+    ",
+    )
+    .unwrap();
+    // 参照されている関数は呼ばれうるので、呼ばれない関数とは言わない
+    assert_eq!(verify(&program), vec![]);
+    let cfg = Cfg::new(&program, BTreeMap::new());
+    assert_eq!(cfg.referenced_funcs(), vec![7].into_iter().collect());
+    assert_eq!(cfg.callees(1), vec![7].into_iter().collect());
+    assert_eq!(
+        verify_flags(&program, &vec![(1, FuncFlags::PURE)].into_iter().collect()),
+        vec![Diagnostic::error(
+            4,
+            "function 1 may not call function 7".to_string()
+        )]
+    );
+
+    assert_eq!(
+        verify(&[
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::FuncRef(3),
+            Cmd::CallIndirect,
+            Cmd::Ret,
+        ]),
+        vec![Diagnostic::error(
+            2,
+            "function reference 3 is not the start of a function".to_string()
+        )]
+    );
+    assert_eq!(
+        verify(&[Cmd::Entry(1), Cmd::Frame(0, 1), Cmd::CallIndirect, Cmd::Ret]),
+        vec![Diagnostic::warning(
+            2,
+            "indirect call but no function is referenced".to_string()
+        )]
+    );
+}
+
+#[test]
+fn test_closures() {
+    use crate::asm::assemble;
+
+    let program = assemble(
+        "
+        entry main
+    main:
+        frame 0 4
+        const 5
+        const 10
+        make_closure adder 1
+        call_closure
+        pop_r 4
+        ret
+    adder:
+        frame 0 2
+        arg_load 1
+        capture_load 0
+        add
+        ret
+    ",
+    )
+    .unwrap();
+    assert_eq!(verify(&program), vec![]);
+    let cfg = Cfg::new(&program, BTreeMap::new());
+    assert_eq!(cfg.callees(1), vec![8].into_iter().collect());
+    assert_eq!(
+        verify_flags(
+            &program,
+            &vec![(1, FuncFlags::PURE), (8, FuncFlags::PURE)]
+                .into_iter()
+                .collect()
+        ),
+        vec![Diagnostic::error(
+            4,
+            "function 1 may not allocate".to_string()
+        )]
+    );
+}
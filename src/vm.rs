@@ -1,4 +1,395 @@
+use crate::billing::{Ledger, Usage};
+use crate::golden::{Divergence, GoldenTrace, Projection, Recorder};
+use crate::heap::{footprint, Heap, CELL_BYTES};
+use crate::memo::MemoCache;
+use crate::profile::Profile;
+use crate::program::Program;
+use crate::trace::Trace;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+
+// スタックに積む値。戻りアドレスや旧フレームポインタもこの型で積む
+pub type Value = i64;
+
+// resetで古い値を塗りつぶすときの値
+pub const POISON: Value = 0xDEAD_BEEF;
+
+// 算術演算が溢れたときの扱い
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Overflow {
+    // 2の補数で折り返す
+    Wrapping,
+    // VmError::Overflowにする
+    Checked,
+}
+
+// 関数の呼び出しと戻りを監視する
+pub trait CallObserver {
+    // funcは呼び出された関数の先頭アドレス。argsは呼び出し直前のスタックで、ArgLoad(i)はargs[args.len() - 1 - i]
+    fn on_call(&mut self, func: usize, args: &[Value]);
+    fn on_return(&mut self, func: usize, result: Value);
+}
+
+// 関数に許す操作。指定しなかった関数は何でもできる(FuncFlags::ALL)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FuncFlags {
+    pub may_call_host: bool,
+    pub may_allocate: bool,
+}
+
+impl FuncFlags {
+    pub const PURE: FuncFlags = FuncFlags {
+        may_call_host: false,
+        may_allocate: false,
+    };
+
+    pub const ALL: FuncFlags = FuncFlags {
+        may_call_host: true,
+        may_allocate: true,
+    };
+
+    pub fn is_pure(self) -> bool {
+        self == FuncFlags::PURE
+    }
+
+    // selfの関数からcalleeを呼んでよいか。呼び出し元に許されていないことをする関数は呼べない
+    pub fn allows(self, callee: FuncFlags) -> bool {
+        (self.may_call_host || !callee.may_call_host) && (self.may_allocate || !callee.may_allocate)
+    }
+}
+
+// 命令の実行前後に呼ばれる。デバッグ出力やトレースの記録向け
+pub trait Tracer {
+    fn on_before_cmd(&mut self, _view: &VmView, _cmd: &Cmd) {}
+    fn on_after_cmd(&mut self, _view: &VmView, _cmd: &Cmd) {}
+}
+
+// 実行の様子を標準出力に書き出す
+pub struct PrintTracer;
+
+impl PrintTracer {
+    fn state(view: &VmView) -> String {
+        format!("pc:{} fp:{} stack:{:?}", view.pc, view.fp, view.stack)
+    }
+}
+
+impl Tracer for PrintTracer {
+    fn on_before_cmd(&mut self, view: &VmView, cmd: &Cmd) {
+        println!("[run]{:?}", cmd);
+        println!("[state] {}", PrintTracer::state(view));
+    }
+
+    fn on_after_cmd(&mut self, view: &VmView, _cmd: &Cmd) {
+        println!("[result]{}", PrintTracer::state(view));
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum VmError {
+    // プログラムの範囲外の命令を実行しようとした
+    InvalidPc(usize),
+    StackUnderflow,
+    StackOverflow,
+    // 範囲外のローカル変数にアクセスした
+    InvalidLocal(usize),
+    // 範囲外の引数にアクセスした
+    InvalidArg(usize),
+    // ホストAPIで範囲外のインデックスを指定した
+    InvalidIndex(isize),
+    // フレームの外でRetした
+    InvalidFrame,
+    DivByZero,
+    // Overflow::Checkedで、算術演算の結果が溢れた
+    Overflow,
+    // サニタイザモードで、書き込まれていないローカル変数を読んだ
+    UninitializedLocal { local: usize, pc: usize },
+    // 実行を終えたVMでstepした
+    Halted,
+    // サンドボックスモードで、許されていない関数を呼んだ
+    PermissionDenied { caller: usize, callee: usize },
+    // ヒープのブロックの外にアクセスした
+    InvalidHeapAccess { addr: Value, offset: Value },
+    // サンドボックスモードで、確保を許されていない関数がAllocした
+    AllocNotAllowed { func: usize },
+    // 範囲外の定数を参照した
+    InvalidConst(usize),
+    // ヒープの上限を超えて確保しようとした。pcは確保した命令、bytesは確保しようとしたバイト数
+    HeapLimitExceeded { pc: usize, bytes: usize },
+    // サンドボックスモードで、ホストとのやりとりを許されていない関数が入出力した
+    HostNotAllowed { func: usize },
+    // 燃料を使い切った。燃料を足してrunすれば続きから実行できる
+    OutOfFuel,
+    // 登録されていないホスト関数を呼んだ
+    InvalidNative(usize),
+    // 入出力に失敗した。ReadIntで整数が読めなかったときはInvalidData
+    Io(io::ErrorKind),
+    // CallIndirect, CallClosureで呼ぼうとした値が関数の先頭アドレスではなかった
+    InvalidCallTarget(Value),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::InvalidPc(pc) => write!(f, "invalid pc {}", pc),
+            VmError::StackUnderflow => write!(f, "stack underflow"),
+            VmError::StackOverflow => write!(f, "stack overflow"),
+            VmError::InvalidLocal(i) => write!(f, "invalid local {}", i),
+            VmError::InvalidArg(i) => write!(f, "invalid arg {}", i),
+            VmError::InvalidIndex(i) => write!(f, "invalid index {}", i),
+            VmError::InvalidFrame => write!(f, "ret outside of a frame"),
+            VmError::DivByZero => write!(f, "division by zero"),
+            VmError::Overflow => write!(f, "arithmetic overflow"),
+            VmError::UninitializedLocal { local, pc } => {
+                write!(f, "read of uninitialized local {} at {}", local, pc)
+            }
+            VmError::Halted => write!(f, "vm has halted"),
+            VmError::PermissionDenied { caller, callee } => {
+                write!(f, "function {} may not call function {}", caller, callee)
+            }
+            VmError::InvalidHeapAccess { addr, offset } => {
+                write!(f, "invalid heap access {}+{}", addr, offset)
+            }
+            VmError::AllocNotAllowed { func } => {
+                write!(f, "function {} may not allocate", func)
+            }
+            VmError::InvalidConst(i) => write!(f, "invalid constant {}", i),
+            VmError::HeapLimitExceeded { pc, bytes } => {
+                write!(
+                    f,
+                    "heap limit exceeded allocating {} bytes at {}",
+                    bytes, pc
+                )
+            }
+            VmError::HostNotAllowed { func } => {
+                write!(f, "function {} may not call the host", func)
+            }
+            VmError::OutOfFuel => write!(f, "out of fuel"),
+            VmError::InvalidNative(i) => write!(f, "invalid native function {}", i),
+            VmError::Io(kind) => write!(f, "io error: {:?}", kind),
+            VmError::InvalidCallTarget(x) => write!(f, "invalid call target {}", x),
+        }
+    }
+}
+
+impl error::Error for VmError {}
+
+// VmErrorから詳細を落とした種類。大量に失敗させるときはこれとVM::pcだけを見ればよい
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum VmErrorKind {
+    InvalidPc = 1,
+    StackUnderflow,
+    StackOverflow,
+    InvalidLocal,
+    InvalidArg,
+    InvalidIndex,
+    InvalidFrame,
+    DivByZero,
+    Overflow,
+    UninitializedLocal,
+    Halted,
+    PermissionDenied,
+    InvalidHeapAccess,
+    AllocNotAllowed,
+    InvalidConst,
+    HeapLimitExceeded,
+    HostNotAllowed,
+    OutOfFuel,
+    InvalidNative,
+    Io,
+    InvalidCallTarget,
+}
+
+impl VmErrorKind {
+    // 外部に渡すための数値のエラーコード
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+impl VmError {
+    pub fn kind(&self) -> VmErrorKind {
+        match self {
+            VmError::InvalidPc(_) => VmErrorKind::InvalidPc,
+            VmError::StackUnderflow => VmErrorKind::StackUnderflow,
+            VmError::StackOverflow => VmErrorKind::StackOverflow,
+            VmError::InvalidLocal(_) => VmErrorKind::InvalidLocal,
+            VmError::InvalidArg(_) => VmErrorKind::InvalidArg,
+            VmError::InvalidIndex(_) => VmErrorKind::InvalidIndex,
+            VmError::InvalidFrame => VmErrorKind::InvalidFrame,
+            VmError::DivByZero => VmErrorKind::DivByZero,
+            VmError::Overflow => VmErrorKind::Overflow,
+            VmError::UninitializedLocal { .. } => VmErrorKind::UninitializedLocal,
+            VmError::Halted => VmErrorKind::Halted,
+            VmError::PermissionDenied { .. } => VmErrorKind::PermissionDenied,
+            VmError::InvalidHeapAccess { .. } => VmErrorKind::InvalidHeapAccess,
+            VmError::AllocNotAllowed { .. } => VmErrorKind::AllocNotAllowed,
+            VmError::InvalidConst(_) => VmErrorKind::InvalidConst,
+            VmError::HeapLimitExceeded { .. } => VmErrorKind::HeapLimitExceeded,
+            VmError::HostNotAllowed { .. } => VmErrorKind::HostNotAllowed,
+            VmError::OutOfFuel => VmErrorKind::OutOfFuel,
+            VmError::InvalidNative(_) => VmErrorKind::InvalidNative,
+            VmError::Io(_) => VmErrorKind::Io,
+            VmError::InvalidCallTarget(_) => VmErrorKind::InvalidCallTarget,
+        }
+    }
+}
+
+// コールバックに渡すVMの状態
+pub struct VmView<'a> {
+    pub pc: usize,
+    pub fp: usize,
+    pub sp: usize,
+    pub stack: &'a [Value],
+}
+
+// ホスト関数から触れるVMの部分。ヒープの確保はAllocと同じく上限やサンドボックス、計測を通す
+pub struct VmContext<'a> {
+    vm: &'a mut VM,
+}
+
+impl<'a> VmContext<'a> {
+    // 確保したブロックはホスト関数から戻るまでGCで回収しない
+    pub fn alloc(&mut self, size: usize) -> Result<Value, VmError> {
+        let vm = &mut *self.vm;
+        vm.before_alloc(size)?;
+        let addr = vm.heap.alloc(size).ok_or_else(|| vm.heap_full(size))?;
+        vm.record_alloc(addr, size);
+        vm.native_allocs.push(addr);
+        Ok(addr)
+    }
+
+    pub fn read(&self, addr: Value, offset: Value) -> Result<Value, VmError> {
+        self.vm.heap.load(addr, offset)
+    }
+
+    pub fn write(&mut self, addr: Value, offset: Value, x: Value) -> Result<(), VmError> {
+        self.vm.heap.store(addr, offset, x)
+    }
+
+    pub fn read_bytes(&self, addr: Value) -> Result<Vec<u8>, VmError> {
+        self.vm.heap.load_bytes(addr)
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.vm.output
+    }
+}
+
+pub type NativeFn = Box<dyn Fn(&mut VmContext, &[Value]) -> Result<Value, VmError>>;
+
+struct Native {
+    arity: usize,
+    f: NativeFn,
+}
+
+// stepで返すVMの状態
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmState {
+    pub pc: usize,
+    pub fp: usize,
+    pub sp: usize,
+    pub stack: Vec<Value>,
+}
+
+// n命令ごとに呼ばれるコールバックと、次に呼ぶまでの残り命令数
+struct Periodic {
+    n: usize,
+    remaining: usize,
+    callback: Box<dyn FnMut(&VmView)>,
+}
+
+// VMの設定。VmConfig::new().max_stack_size(1 << 16) のように組み立てる
+#[derive(Clone, Debug, PartialEq)]
+pub struct VmConfig {
+    // 最初に確保するスタックのスロット数
+    initial_stack_size: usize,
+    // スタックを伸ばせる上限のスロット数。超えるとStackOverflow
+    max_stack_size: usize,
+    // 同時に確保できるヒープのバイト数。超えるとHeapLimitExceeded
+    max_heap_bytes: Option<usize>,
+}
+
+impl VmConfig {
+    pub fn new() -> VmConfig {
+        VmConfig::default()
+    }
+
+    pub fn initial_stack_size(mut self, size: usize) -> VmConfig {
+        self.initial_stack_size = size;
+        self
+    }
+
+    pub fn max_stack_size(mut self, size: usize) -> VmConfig {
+        self.max_stack_size = size;
+        self
+    }
+
+    pub fn max_heap_bytes(mut self, bytes: usize) -> VmConfig {
+        self.max_heap_bytes = Some(bytes);
+        self
+    }
+}
+
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            initial_stack_size: 64,
+            max_stack_size: 1000,
+            max_heap_bytes: None,
+        }
+    }
+}
+
+// VM::heaplessで読み込めないプログラム
 #[derive(Clone, Debug, PartialEq)]
+pub enum HeaplessError {
+    ProgramTooLarge { len: usize, max: usize },
+    // ヒープやホスト関数を使う命令がある
+    UsesHeap(usize),
+}
+
+impl fmt::Display for HeaplessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaplessError::ProgramTooLarge { len, max } => {
+                write!(f, "program has {} instructions, limit is {}", len, max)
+            }
+            HeaplessError::UsesHeap(pc) => write!(f, "instruction at {} uses the heap", pc),
+        }
+    }
+}
+
+impl error::Error for HeaplessError {}
+
+// run_withに渡す、一回の実行ごとの設定
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RunOptions {
+    // 指定すると、この実行の使用量をVM::ledgerにこのテナントの分として記録する
+    tenant: Option<String>,
+    // 指定すると、run_with_fuelと同じく最大この命令数だけ実行する
+    fuel: Option<u64>,
+}
+
+impl RunOptions {
+    pub fn new() -> RunOptions {
+        RunOptions::default()
+    }
+
+    pub fn tenant(mut self, tenant: &str) -> RunOptions {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    pub fn fuel(mut self, limit: u64) -> RunOptions {
+        self.fuel = Some(limit);
+        self
+    }
+}
+
 pub struct VM {
     // 現在実行中の関数のフレームポインタ(旧フレームポインタが入ってるスタックのアドレス。最初のローカル変数の一個前のアドレス)
     fp: usize,
@@ -6,214 +397,1345 @@ pub struct VM {
     sp: usize,
     // 次に実行する命令のアドレス
     pc: usize,
-    stack: Vec<usize>,
+    // 必要になったらmax_stack_sizeまで伸ばす
+    stack: Vec<Value>,
+    max_stack_size: usize,
+    heap: Heap,
+    max_heap_bytes: Option<usize>,
+    // ConstLoadで参照する定数
+    consts: Vec<Value>,
+    // StrConstで参照する文字列定数
+    strings: Vec<String>,
+    // 前回のGCからこのセル数を超えて確保しようとしたらGCする
+    gc_threshold: Option<usize>,
+    // 前回のGCから確保したセル数
+    allocated: usize,
     program: Vec<Cmd>,
+    overflow: Overflow,
+    // 書き込み監査モードのとき、スタックの各スロットに最後に書き込んだ命令のアドレス
+    last_writes: Option<Vec<Option<usize>>>,
+    // サニタイザモードのとき、スタックの各スロットが初期化済みかどうか
+    initialized: Option<Vec<bool>>,
+    call_observer: Option<Box<dyn CallObserver>>,
+    tracer: Option<Box<dyn Tracer>>,
+    // call_observerかプロファイラがあるとき、実行中の関数の先頭アドレス
+    call_stack: Vec<usize>,
+    profile: Option<Profile>,
+    // カバレッジ計測中のとき、制御移動(辺)ごとのヒット数
+    coverage: Option<Vec<u8>>,
+    // トレース中のとき、実行した命令のpc
+    trace: Option<Trace>,
+    // 比較用の記録中のとき、実行した命令とスタックの変化
+    golden: Option<Recorder>,
+    periodic: Option<Periodic>,
+    // サンドボックスモードのとき、関数の先頭アドレスごとに許す操作
+    sandbox: Option<HashMap<usize, FuncFlags>>,
+    // メモ化する関数の先頭アドレスと引数の数
+    memoized: HashMap<usize, usize>,
+    memo: Option<MemoCache>,
+    // 結果をメモするために戻りを待っている呼び出し。呼び出し時のsp、関数、引数
+    memo_pending: Vec<(usize, usize, Vec<Value>)>,
+    // NativeCallで呼ぶホスト関数
+    natives: Vec<Native>,
+    // 実行中のホスト関数が確保したブロック。戻るまではGCのルート
+    native_allocs: Vec<Value>,
+    // 燃料制限をしているとき、あと実行できる命令数
+    fuel: Option<u64>,
+    // テナントを指定して実行中のとき、この実行の使用量
+    usage: Option<Usage>,
+    ledger: Ledger,
+    // Printの出力先とReadIntの入力元。標準では標準出力と標準入力
+    output: Box<dyn Write>,
+    input: Box<dyn Read>,
+    // エントリ関数から戻ったかどうか
+    halted: bool,
 }
 
 impl VM {
     pub fn new(program: Vec<Cmd>) -> VM {
+        VM::with_config(program, VmConfig::default())
+    }
+
+    pub fn with_config(program: Vec<Cmd>, config: VmConfig) -> VM {
         VM {
             fp: 0,
-            stack: {
-                let mut v = Vec::with_capacity(1000);
-                v.resize(1000, 0);
-                v
-            },
+            stack: vec![0; config.initial_stack_size.min(config.max_stack_size)],
+            max_stack_size: config.max_stack_size,
+            heap: Heap::new(),
+            max_heap_bytes: config.max_heap_bytes,
+            consts: Vec::new(),
+            strings: Vec::new(),
+            gc_threshold: None,
+            allocated: 0,
             sp: 0,
             program,
             pc: 0,
+            overflow: Overflow::Wrapping,
+            last_writes: None,
+            initialized: None,
+            call_observer: None,
+            tracer: None,
+            call_stack: Vec::new(),
+            profile: None,
+            coverage: None,
+            trace: None,
+            golden: None,
+            periodic: None,
+            sandbox: None,
+            memoized: HashMap::new(),
+            memo: None,
+            memo_pending: Vec::new(),
+            natives: Vec::new(),
+            native_allocs: Vec::new(),
+            fuel: None,
+            usage: None,
+            ledger: Ledger::new(),
+            output: Box::new(io::stdout()),
+            input: Box::new(io::stdin()),
+            halted: false,
         }
     }
 
-    pub fn run(&mut self) -> usize {
-        self.run_cmd();
-        while self.pc != 0 {
-            self.run_cmd();
+    // 命令列と定数プールを読み込む
+    pub fn from_program(program: Program, config: VmConfig) -> VM {
+        let mut vm = VM::with_config(program.code, config);
+        vm.consts = program.consts;
+        vm.strings = program.strings;
+        vm
+    }
+
+    // 命令数とスタックの上限を静的に決めたVM。スタックはここでSTACKスロットを確保し、実行中は伸ばさない
+    // ヒープを使う命令は読み込み時に拒否するので、計測などを有効にしなければ構築後に確保は起きない
+    pub fn heapless<const PROGRAM: usize, const STACK: usize>(
+        program: Vec<Cmd>,
+    ) -> Result<VM, HeaplessError> {
+        if program.len() > PROGRAM {
+            return Err(HeaplessError::ProgramTooLarge {
+                len: program.len(),
+                max: PROGRAM,
+            });
         }
-        self.peak()
+        if let Some(pc) = program.iter().position(Cmd::uses_heap) {
+            return Err(HeaplessError::UsesHeap(pc));
+        }
+        let config = VmConfig::new()
+            .initial_stack_size(STACK)
+            .max_stack_size(STACK)
+            .max_heap_bytes(0);
+        Ok(VM::with_config(program, config))
     }
 
-    fn push(&mut self, x: usize) {
-        self.stack[self.sp] = x;
-        self.sp += 1;
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
     }
 
-    fn peak(&self) -> usize {
-        self.stack[self.sp - 1]
+    // n命令実行するごとにcallbackを呼ぶ。サンプリングや進捗表示向け
+    pub fn every_n_instructions(&mut self, n: usize, callback: impl FnMut(&VmView) + 'static) {
+        assert!(n > 0);
+        self.periodic = Some(Periodic {
+            n,
+            remaining: n,
+            callback: Box::new(callback),
+        });
     }
 
-    fn pop(&mut self) -> usize {
-        let x = self.peak();
-        self.sp -= 1;
-        x
+    // 分岐・呼び出し・戻りによる命令間の辺を、size個のカウンタにハッシュして数える
+    pub fn enable_coverage(&mut self, size: usize) {
+        self.coverage = Some(vec![0; size]);
     }
 
-    fn debug_state(&self) -> String {
-        format!(
-            "pc:{} fp:{} stack:{:?}",
-            self.pc,
-            self.fp,
-            self.stack
-                .clone()
-                .into_iter()
-                .take(self.sp)
-                .collect::<Vec<_>>()
-        )
+    pub fn coverage(&self) -> Option<&[u8]> {
+        self.coverage.as_deref()
     }
 
-    fn run_cmd(&mut self) {
-        println!("[run]{:?}", self.program[self.pc]);
-        println!("[state] {}", self.debug_state());
-        let cmd = self.program[self.pc].clone();
-        match cmd {
-            Cmd::Entry(i) => {
-                self.pc = i;
-                self.push(0);
-            }
-            Cmd::Frame(local_count) => {
-                self.push(self.fp);
-                self.fp = self.sp - 1;
-                self.sp += local_count;
+    pub fn enable_trace(&mut self) {
+        self.trace = Some(Trace::new());
+    }
 
-                self.pc += 1;
-            }
-            Cmd::Ret => {
-                let res = self.peak();
-                self.sp = self.fp;
-                self.pc = self.stack[self.fp - 1];
-                self.fp = self.stack[self.fp];
-                self.push(res);
-            }
-            Cmd::Call(i) => {
-                self.push(self.pc + 1);
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
 
-                self.pc = i;
-            }
-            Cmd::LocalLoad(i) => {
-                self.push(self.stack[self.fp + i + 1]);
+    pub fn enable_golden_trace(&mut self) {
+        self.enable_golden_trace_with(Projection::PcsAndValues);
+    }
 
-                self.pc += 1;
-            }
-            Cmd::LocalStore(i) => {
-                self.stack[self.fp + i + 1] = self.pop();
+    // projectionで比べる分だけ記録する。Pcsならスタックの値は記録しない
+    pub fn enable_golden_trace_with(&mut self, projection: Projection) {
+        self.golden = Some(Recorder::new(projection));
+    }
 
-                self.pc += 1;
-            }
-            Cmd::ArgLoad(i) => {
-                self.push(self.stack[self.fp - i - 2]);
-                self.pc += 1;
-            }
-            Cmd::ArgStore(i) => {
-                self.stack[self.fp - i - 2] = self.pop();
+    pub fn golden_trace(&self) -> Option<&GoldenTrace> {
+        self.golden.as_ref().map(|golden| &golden.trace)
+    }
 
-                self.pc += 1;
-            }
-            Cmd::PopR(i) => {
-                let res = self.pop();
-                self.sp -= i - 1;
-                self.push(res);
+    // 実行を記録しながらrunし、referenceと比べる。参照側をリファクタリング前の実行で作っておけば、
+    // 最初に振る舞いが変わった命令がわかる。エラーで止まった場合もそこまでの記録で比べる
+    pub fn run_and_compare(
+        &mut self,
+        reference: &GoldenTrace,
+        projection: Projection,
+    ) -> (Result<Value, VmError>, Option<Divergence>) {
+        // 呼び出し前の記録の状態は比べ終わったら戻す
+        let previous = self.golden.replace(Recorder::new(projection));
+        let result = self.run();
+        let actual = mem::replace(&mut self.golden, previous).unwrap().trace;
+        (result, reference.compare(&actual, projection))
+    }
 
-                self.pc += 1;
-            }
-            Cmd::Const(x) => {
-                self.push(x);
+    pub fn enable_profiler(&mut self) {
+        self.profile = Some(Profile::new());
+    }
 
-                self.pc += 1;
-            }
-            Cmd::Add => {
-                let x = self.pop();
-                let y = self.pop();
-                self.push(x + y);
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
 
-                self.pc += 1;
-            }
-            Cmd::Mod => {
-                let x = self.pop();
-                let y = self.pop();
-                self.push(x % y);
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
 
-                self.pc += 1;
-            }
-            Cmd::Eq => {
-                let x = self.pop();
-                let y = self.pop();
-                self.push(if x == y { 1 } else { 0 });
+    pub fn set_consts(&mut self, consts: Vec<Value>) {
+        self.consts = consts;
+    }
 
-                self.pc += 1;
+    pub fn set_strings(&mut self, strings: Vec<String>) {
+        self.strings = strings;
+    }
+
+    pub fn set_gc_threshold(&mut self, cells: usize) {
+        self.gc_threshold = Some(cells);
+    }
+
+    // スタック(ローカル変数と引数を含む)とメモ化した引数・戻り値から辿れないブロックを回収し、回収したブロック数を返す
+    pub fn collect_garbage(&mut self) -> usize {
+        self.allocated = 0;
+        let memo = self.memo.iter().flat_map(|memo| memo.values());
+        let roots = self.stack[..self.sp].iter().cloned().chain(memo);
+        self.heap
+            .collect(roots.chain(self.native_allocs.iter().cloned()))
+    }
+
+    // 関数ごとのFuncFlagsを呼び出し時に検査する。flagsにない関数はFuncFlags::ALL
+    pub fn enable_sandbox(&mut self, flags: HashMap<usize, FuncFlags>) {
+        self.sandbox = Some(flags);
+    }
+
+    // 最大capacity件まで、memoizeした関数の結果を覚えて呼び出しを省く
+    pub fn enable_memoization(&mut self, capacity: usize) {
+        self.memo = Some(MemoCache::new(capacity));
+    }
+
+    // funcはarity個の引数だけで戻り値が決まる関数。サンドボックスでPUREにした関数だけメモ化する
+    pub fn memoize(&mut self, func: usize, arity: usize) {
+        self.memoized.insert(func, arity);
+    }
+
+    pub fn memo(&self) -> Option<&MemoCache> {
+        self.memo.as_ref()
+    }
+
+    // funcの呼び出しをメモ化するなら、その引数
+    fn memo_args(&self, func: usize) -> Option<Vec<Value>> {
+        let arity = *self.memoized.get(&func)?;
+        if self.memo.is_none() || !self.flags(func).is_pure() {
+            return None;
+        }
+        let start = self.sp.checked_sub(arity)?;
+        Some(self.stack[start..self.sp].to_vec())
+    }
+
+    fn flags(&self, func: usize) -> FuncFlags {
+        self.sandbox
+            .as_ref()
+            .and_then(|flags| flags.get(&func).cloned())
+            .unwrap_or(FuncFlags::ALL)
+    }
+
+    // arity個の引数を取るホスト関数を登録し、NativeCallで使う番号を返す
+    pub fn register_native(
+        &mut self,
+        arity: usize,
+        f: impl Fn(&mut VmContext, &[Value]) -> Result<Value, VmError> + 'static,
+    ) -> usize {
+        self.natives.push(Native {
+            arity,
+            f: Box::new(f),
+        });
+        self.natives.len() - 1
+    }
+
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.input = input;
+    }
+
+    pub fn set_tracer(&mut self, tracer: Box<dyn Tracer>) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn set_call_observer(&mut self, observer: Box<dyn CallObserver>) {
+        self.call_observer = Some(observer);
+    }
+
+    // 未初期化のローカル変数の読み出しを検出する
+    pub fn enable_sanitizer(&mut self) {
+        self.initialized = Some(vec![false; self.stack.len()]);
+    }
+
+    // 再利用のためにレジスタを初期状態に戻す。poisonなら前回の実行で残った値を塗りつぶす
+    pub fn reset(&mut self, poison: bool) {
+        self.fp = 0;
+        self.sp = 0;
+        self.pc = 0;
+        self.call_stack.clear();
+        self.heap.clear();
+        self.allocated = 0;
+        self.memo_pending.clear();
+        self.halted = false;
+        if poison {
+            for x in &mut self.stack {
+                *x = POISON;
             }
-            Cmd::JumpIf(i) => {
-                let x = self.pop();
-                if x != 0 {
-                    self.pc = i;
-                } else {
-                    self.pc += 1;
-                }
+        }
+        if let Some(writes) = &mut self.last_writes {
+            for x in writes {
+                *x = None;
             }
-            Cmd::Jump(i) => {
-                self.pc = i;
+        }
+        if let Some(initialized) = &mut self.initialized {
+            for x in initialized {
+                *x = false;
             }
         }
-        println!("[result]{}", self.debug_state());
     }
-}
-#[derive(Clone, Debug, PartialEq)]
-pub enum Cmd {
-    Frame(usize),
-    Ret,
-    Call(usize),
-    LocalLoad(usize),
-    LocalStore(usize),
-    ArgLoad(usize),
-    ArgStore(usize),
-    PopR(usize),
-    Const(usize),
-    Add,
-    Mod,
-    Entry(usize),
-    Eq,
-    JumpIf(usize),
-    Jump(usize),
-}
 
-#[test]
-fn test() {
-    assert_eq!(
-        VM::new(vec![
-            Cmd::Entry(1),
-            Cmd::Frame(0),
-            Cmd::Const(1),
-            Cmd::Const(2),
-            Cmd::Call(7),
-            Cmd::PopR(2),
-            Cmd::Ret,
-            Cmd::Frame(0),
-            Cmd::ArgLoad(0),
-            Cmd::ArgLoad(1),
-            Cmd::Add,
-            Cmd::Ret
-        ])
-        .run(),
-        3
-    );
+    pub fn enable_write_audit(&mut self) {
+        self.last_writes = Some(vec![None; self.stack.len()]);
+    }
 
-    assert_eq!(
-        VM::new(vec![
-            Cmd::Entry(1),    // 0
-            Cmd::Frame(0),    // 1
-            Cmd::Const(182),  // 2
-            Cmd::Const(1029), // 3
-            Cmd::Call(7),     // 4
-            Cmd::PopR(2),     // 5
-            Cmd::Ret,         // 6
-            Cmd::Frame(0),    // 7 gcd(a:1, b:0)
-            Cmd::ArgLoad(0),  // 8
-            Cmd::Const(0),    // 9
-            Cmd::Eq,          // 10
-            Cmd::JumpIf(13),  // 11
-            Cmd::Jump(15),    //12
-            Cmd::ArgLoad(1),  //13
-            Cmd::Jump(21),    // 14
-            Cmd::ArgLoad(0),  //15
+    // スタックのaddrに最後に書き込んだ命令のアドレス
+    pub fn last_write(&self, addr: usize) -> Option<usize> {
+        self.last_writes
+            .as_ref()
+            .and_then(|writes| writes.get(addr).cloned().flatten())
+    }
+
+    // 現在のフレームのi番目のローカル変数に最後に書き込んだ命令のアドレス
+    pub fn local_last_write(&self, i: usize) -> Option<usize> {
+        self.local_addr(i)
+            .ok()
+            .and_then(|addr| self.last_write(addr))
+    }
+
+    // 最初の実行で伸ばさずに済むよう、スタックを上限まで確保しておく
+    pub fn warmup(&mut self) {
+        let _ = self.reserve(self.max_stack_size);
+    }
+
+    // 次に実行する命令のアドレス。runがエラーを返した後なら失敗した命令のアドレス
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // 現在のフレームのi番目のローカル変数
+    pub fn local(&self, i: usize) -> Option<Value> {
+        self.local_addr(i).ok().map(|addr| self.stack[addr])
+    }
+
+    // 現在のフレームのi番目の引数
+    pub fn arg(&self, i: usize) -> Option<Value> {
+        self.arg_addr(i).ok().map(|addr| self.stack[addr])
+    }
+
+    // runが返したエラーに、失敗した命令とスタックの状態を添えた説明
+    pub fn error_report(&self, error: &VmError) -> String {
+        let cmd = match self.program.get(self.pc) {
+            Some(cmd) => format!("{:?}", cmd),
+            None => "-".to_string(),
+        };
+        format!(
+            "error: {}\n  at pc {}: {}\n  fp: {}\n  stack: {:?}",
+            error,
+            self.pc,
+            cmd,
+            self.fp,
+            &self.stack[..self.sp]
+        )
+    }
+
+    pub fn run(&mut self) -> Result<Value, VmError> {
+        self.run_cmd()?;
+        while !self.halted {
+            self.run_cmd()?;
+        }
+        self.peak()
+    }
+
+    // 最大limit命令だけ実行する。使い切るとOutOfFuelで止まり、add_fuelしてrunすれば続きから実行できる
+    pub fn run_with_fuel(&mut self, limit: u64) -> Result<Value, VmError> {
+        self.fuel = Some(limit);
+        self.run()
+    }
+
+    // テナントを指定すると、エラーで止まった場合も含めてこの実行の使用量をledgerに足す
+    pub fn run_with(&mut self, options: RunOptions) -> Result<Value, VmError> {
+        if let Some(limit) = options.fuel {
+            self.fuel = Some(limit);
+        }
+        let tenant = match options.tenant {
+            Some(tenant) => tenant,
+            None => return self.run(),
+        };
+        self.usage = Some(Usage::default());
+        let result = self.run();
+        let usage = self.usage.take().unwrap();
+        self.ledger.record(&tenant, &usage);
+        result
+    }
+
+    // テナントごとの使用量
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    pub fn take_ledger(&mut self) -> Ledger {
+        std::mem::take(&mut self.ledger)
+    }
+
+    // 残りの燃料。燃料制限をしていなければNone
+    pub fn fuel(&self) -> Option<u64> {
+        self.fuel
+    }
+
+    pub fn add_fuel(&mut self, n: u64) {
+        self.fuel = Some(self.fuel.unwrap_or(0).saturating_add(n));
+    }
+
+    // 一命令だけ実行し、実行した命令と実行後の状態を返す
+    pub fn step(&mut self) -> Result<(Cmd, VmState), VmError> {
+        if self.halted {
+            return Err(VmError::Halted);
+        }
+        let cmd = self
+            .program
+            .get(self.pc)
+            .cloned()
+            .ok_or(VmError::InvalidPc(self.pc))?;
+        self.step_cmd()?;
+        Ok((
+            cmd,
+            VmState {
+                pc: self.pc,
+                fp: self.fp,
+                sp: self.sp,
+                stack: self.stack[..self.sp].to_vec(),
+            },
+        ))
+    }
+
+    // stepと同じく一命令実行するが、状態を写さない。実行後の状態はviewで見る
+    pub(crate) fn step_cmd(&mut self) -> Result<(), VmError> {
+        if self.halted {
+            return Err(VmError::Halted);
+        }
+        self.run_cmd()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // ホスト向けのスタック操作。LuaのC APIと同じく、インデックスは現在のフレームの底から1始まり、負なら-1がトップ
+    // 実行前にpushした値は、エントリ関数からArgLoadで読める(最後にpushした値がArgLoad(0))
+    // get_topは現在のフレームで積まれている値の数
+    pub fn get_top(&self) -> usize {
+        self.sp - self.frame_base()
+    }
+
+    pub fn push_int(&mut self, x: Value) -> Result<(), VmError> {
+        self.push(x)
+    }
+
+    pub fn pop_int(&mut self) -> Result<Value, VmError> {
+        if self.get_top() == 0 {
+            return Err(VmError::StackUnderflow);
+        }
+        self.pop()
+    }
+
+    pub fn to_int(&self, idx: isize) -> Result<Value, VmError> {
+        let top = self.get_top() as isize;
+        let i = if idx > 0 { idx - 1 } else { top + idx };
+        if idx == 0 || i < 0 || i >= top {
+            return Err(VmError::InvalidIndex(idx));
+        }
+        Ok(self.stack[self.frame_base() + i as usize])
+    }
+
+    // 現在のフレームの最初のローカル変数のアドレス。フレームの外なら0
+    fn frame_base(&self) -> usize {
+        if self.fp == 0 {
+            0
+        } else {
+            self.fp + 1
+        }
+    }
+
+    fn tracks_calls(&self) -> bool {
+        self.call_observer.is_some() || self.profile.is_some() || self.sandbox.is_some()
+    }
+
+    fn notify_call(&mut self, func: usize) {
+        if let Some(observer) = &mut self.call_observer {
+            observer.on_call(func, &self.stack[..self.sp]);
+        }
+        if self.tracks_calls() {
+            self.call_stack.push(func);
+        }
+    }
+
+    fn notify_return(&mut self, result: Value) {
+        if !self.tracks_calls() {
+            return;
+        }
+        if let Some(func) = self.call_stack.pop() {
+            if let Some(observer) = &mut self.call_observer {
+                observer.on_return(func, result);
+            }
+        }
+    }
+
+    // スタックを少なくともlenスロットにする。足りなければ倍々に伸ばす
+    fn reserve(&mut self, len: usize) -> Result<(), VmError> {
+        if len <= self.stack.len() {
+            return Ok(());
+        }
+        if len > self.max_stack_size {
+            return Err(VmError::StackOverflow);
+        }
+        let len = len.max(self.stack.len() * 2).min(self.max_stack_size);
+        self.stack.resize(len, 0);
+        if let Some(writes) = &mut self.last_writes {
+            writes.resize(len, None);
+        }
+        if let Some(initialized) = &mut self.initialized {
+            initialized.resize(len, false);
+        }
+        Ok(())
+    }
+
+    fn store(&mut self, addr: usize, x: Value) -> Result<(), VmError> {
+        self.reserve(addr + 1)?;
+        self.stack[addr] = x;
+        if let Some(writes) = &mut self.last_writes {
+            writes[addr] = Some(self.pc);
+        }
+        if let Some(initialized) = &mut self.initialized {
+            initialized[addr] = true;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, x: Value) -> Result<(), VmError> {
+        self.store(self.sp, x)?;
+        self.sp += 1;
+        Ok(())
+    }
+
+    fn peak(&self) -> Result<Value, VmError> {
+        match self.sp.checked_sub(1) {
+            Some(addr) => Ok(self.stack[addr]),
+            None => Err(VmError::StackUnderflow),
+        }
+    }
+
+    fn pop(&mut self) -> Result<Value, VmError> {
+        let x = self.peak()?;
+        self.sp -= 1;
+        Ok(x)
+    }
+
+    fn local_addr(&self, i: usize) -> Result<usize, VmError> {
+        match self.fp.checked_add(i).and_then(|x| x.checked_add(1)) {
+            Some(addr) if addr < self.sp => Ok(addr),
+            _ => Err(VmError::InvalidLocal(i)),
+        }
+    }
+
+    fn arg_addr(&self, i: usize) -> Result<usize, VmError> {
+        match self.fp.checked_sub(i).and_then(|x| x.checked_sub(2)) {
+            Some(addr) if addr < self.sp => Ok(addr),
+            _ => Err(VmError::InvalidArg(i)),
+        }
+    }
+
+    // 今のフレームに保存された戻りアドレスと旧フレームポインタ
+    // 引数への書き込みなどで壊れていればエラーにする
+    fn saved_frame(&self) -> Result<(usize, usize), VmError> {
+        if self.fp == 0 || self.fp >= self.sp {
+            return Err(VmError::InvalidFrame);
+        }
+        let ret_pc = self.stack[self.fp - 1];
+        let old_fp = self.stack[self.fp];
+        if old_fp < 0 || old_fp as usize >= self.fp {
+            return Err(VmError::InvalidFrame);
+        }
+        if ret_pc < 0 || ret_pc as usize >= self.program.len() {
+            return Err(VmError::InvalidPc(ret_pc as usize));
+        }
+        Ok((ret_pc as usize, old_fp as usize))
+    }
+
+    pub fn view(&self) -> VmView<'_> {
+        VmView {
+            pc: self.pc,
+            fp: self.fp,
+            sp: self.sp,
+            stack: &self.stack[..self.sp],
+        }
+    }
+
+    fn notify_tracer(&mut self, cmd: &Cmd, after: bool) {
+        if let Some(mut tracer) = self.tracer.take() {
+            if after {
+                tracer.on_after_cmd(&self.view(), cmd);
+            } else {
+                tracer.on_before_cmd(&self.view(), cmd);
+            }
+            self.tracer = Some(tracer);
+        }
+    }
+
+    // 一命令実行し、実行できたらスタックの変化を記録する
+    fn run_cmd_recorded(&mut self, mut golden: Recorder) -> Result<(), VmError> {
+        let pc = self.pc;
+        golden.before(&self.stack[..self.sp]);
+        let result = self.run_cmd();
+        if result.is_ok() {
+            golden.after(pc, &self.stack[..self.sp]);
+        }
+        self.golden = Some(golden);
+        result
+    }
+
+    fn run_cmd(&mut self) -> Result<(), VmError> {
+        if let Some(golden) = self.golden.take() {
+            return self.run_cmd_recorded(golden);
+        }
+        if let Some(fuel) = &mut self.fuel {
+            if *fuel == 0 {
+                return Err(VmError::OutOfFuel);
+            }
+            *fuel -= 1;
+        }
+        if let Some(usage) = &mut self.usage {
+            usage.instructions += 1;
+            if self.fuel.is_some() {
+                usage.gas += 1;
+            }
+        }
+        let cmd = match self.program.get(self.pc) {
+            Some(cmd) => cmd.clone(),
+            None => return Err(VmError::InvalidPc(self.pc)),
+        };
+        self.notify_tracer(&cmd, false);
+        let pc = self.pc;
+        if let Some(profile) = &mut self.profile {
+            profile.record(&self.call_stack);
+        }
+        if let Some(trace) = &mut self.trace {
+            trace.record(pc);
+        }
+        // 相対ジャンプ・呼び出しは絶対アドレスのものと同じに扱う
+        let absolute = cmd.absolute(pc);
+        match absolute {
+            Cmd::Entry(i) => {
+                self.notify_call(i);
+                self.push(0)?;
+                self.pc = i;
+            }
+            Cmd::Frame(local_count, max_stack) => {
+                // 呼び出し時に一度だけ、この関数が使う分のスタックを確保する
+                self.reserve(
+                    (self.sp + 1)
+                        .saturating_add(local_count)
+                        .saturating_add(max_stack),
+                )?;
+                self.push(self.fp as Value)?;
+                self.fp = self.sp - 1;
+                if let Some(initialized) = &mut self.initialized {
+                    for x in &mut initialized[self.sp..self.sp + local_count] {
+                        *x = false;
+                    }
+                }
+                self.sp += local_count;
+
+                self.pc += 1;
+            }
+            Cmd::Ret => {
+                let res = self.peak()?;
+                let (ret_pc, old_fp) = self.saved_frame()?;
+                self.sp = self.fp;
+                self.push(res)?;
+                if let Some((sp, _, _)) = self.memo_pending.last() {
+                    // 戻りアドレスの上が戻ろうとしている関数のフレーム
+                    if sp + 1 == self.fp {
+                        let (_, func, args) = self.memo_pending.pop().unwrap();
+                        if let Some(memo) = &mut self.memo {
+                            memo.insert(func, args, res);
+                        }
+                    }
+                }
+                self.fp = old_fp;
+                self.pc = ret_pc;
+                self.notify_return(res);
+            }
+            Cmd::Call(i) => self.call(i)?,
+            Cmd::FuncRef(i) => {
+                self.push(i as Value)?;
+                self.pc += 1;
+            }
+            Cmd::CallIndirect => {
+                let x = self.pop()?;
+                self.call_value(x)?;
+            }
+            Cmd::MakeClosure(i, n) => {
+                let size = n.checked_add(1).ok_or_else(|| self.heap_full(n))?;
+                if self.sp < n {
+                    return Err(VmError::StackUnderflow);
+                }
+                // GCしても捕捉する値が回収されないよう、確保してからpopする
+                self.before_alloc(size)?;
+                let captures = &self.stack[self.sp - n..self.sp];
+                let addr = self
+                    .heap
+                    .alloc_closure(i as Value, captures)
+                    .ok_or_else(|| self.heap_full(size))?;
+                self.sp -= n;
+                self.record_alloc(addr, size);
+                self.push(addr)?;
+
+                self.pc += 1;
+            }
+            Cmd::CallClosure => {
+                let closure = self.peak()?;
+                let x = self.heap.load(closure, 0)?;
+                self.call_value(x)?;
+            }
+            Cmd::CaptureLoad(i) => {
+                let closure = self.stack[self.arg_addr(0)?];
+                // 先頭のセルは関数なので、捕捉した値は1つずれる
+                let offset = Value::try_from(i)
+                    .ok()
+                    .and_then(|i| i.checked_add(1))
+                    .ok_or(VmError::InvalidHeapAccess {
+                        addr: closure,
+                        offset: Value::MAX,
+                    })?;
+                self.push(self.heap.load(closure, offset)?)?;
+
+                self.pc += 1;
+            }
+            Cmd::TailCall(i, argc) => {
+                if let Some(&caller) = self.call_stack.last() {
+                    if self.sandbox.is_some() && !self.flags(caller).allows(self.flags(i)) {
+                        return Err(VmError::PermissionDenied { caller, callee: i });
+                    }
+                }
+                let (_, old_fp) = self.saved_frame()?;
+                // 今の引数の場所と、今のフレームのオペランドスタックの両方にargc個なければならない
+                if self.fp <= argc || self.sp - self.fp - 1 < argc {
+                    return Err(VmError::StackUnderflow);
+                }
+                for j in 0..argc {
+                    let x = self.stack[self.sp - 1 - j];
+                    self.store(self.fp - 2 - j, x)?;
+                }
+                // 戻りアドレスだけ残して、呼び出し直後と同じスタックにする
+                self.sp = self.fp;
+                self.fp = old_fp;
+                // 今の関数は戻らずに終わるので、on_returnは呼ばずに呼び出し先と入れ替える
+                if self.tracks_calls() {
+                    self.call_stack.pop();
+                }
+                self.notify_call(i);
+                self.pc = i;
+            }
+            Cmd::LocalLoad(i) => {
+                let addr = self.local_addr(i)?;
+                if let Some(initialized) = &self.initialized {
+                    if !initialized[addr] {
+                        return Err(VmError::UninitializedLocal {
+                            local: i,
+                            pc: self.pc,
+                        });
+                    }
+                }
+                self.push(self.stack[addr])?;
+
+                self.pc += 1;
+            }
+            Cmd::LocalStore(i) => {
+                let x = self.pop()?;
+                let addr = self.local_addr(i)?;
+                self.store(addr, x)?;
+
+                self.pc += 1;
+            }
+            Cmd::ArgLoad(i) => {
+                let addr = self.arg_addr(i)?;
+                self.push(self.stack[addr])?;
+                self.pc += 1;
+            }
+            Cmd::ArgStore(i) => {
+                let x = self.pop()?;
+                let addr = self.arg_addr(i)?;
+                self.store(addr, x)?;
+
+                self.pc += 1;
+            }
+            Cmd::PopR(i) => {
+                let res = self.pop()?;
+                self.sp = match i.checked_sub(1).and_then(|i| self.sp.checked_sub(i)) {
+                    Some(sp) => sp,
+                    None => return Err(VmError::StackUnderflow),
+                };
+                self.push(res)?;
+
+                self.pc += 1;
+            }
+            Cmd::Const(x) => {
+                self.push(x)?;
+
+                self.pc += 1;
+            }
+            Cmd::Add
+            | Cmd::Sub
+            | Cmd::Mul
+            | Cmd::Div
+            | Cmd::Mod
+            | Cmd::And
+            | Cmd::Or
+            | Cmd::Xor
+            | Cmd::Shl
+            | Cmd::Shr => {
+                self.arith(&cmd)?;
+
+                self.pc += 1;
+            }
+            Cmd::Eq | Cmd::Ne | Cmd::Lt | Cmd::Le | Cmd::Gt | Cmd::Ge => {
+                self.compare(&cmd)?;
+
+                self.pc += 1;
+            }
+            Cmd::Dup => {
+                let x = self.peak()?;
+                self.push(x)?;
+
+                self.pc += 1;
+            }
+            Cmd::Swap => {
+                let x = self.pop()?;
+                let y = self.pop()?;
+                self.push(x)?;
+                self.push(y)?;
+
+                self.pc += 1;
+            }
+            Cmd::Pop => {
+                self.pop()?;
+
+                self.pc += 1;
+            }
+            Cmd::Not => {
+                let x = self.pop()?;
+                self.push(if x == 0 { 1 } else { 0 })?;
+
+                self.pc += 1;
+            }
+            Cmd::Alloc(size) => {
+                self.before_alloc(size)?;
+                let addr = self.heap.alloc(size).ok_or_else(|| self.heap_full(size))?;
+                self.record_alloc(addr, size);
+                self.push(addr)?;
+
+                self.pc += 1;
+            }
+            Cmd::Print => {
+                self.check_host()?;
+                let x = self.pop()?;
+                writeln!(self.output, "{}", x).map_err(|e| VmError::Io(e.kind()))?;
+
+                self.pc += 1;
+            }
+            Cmd::ReadInt => {
+                self.check_host()?;
+                let x = self.read_int()?;
+                self.push(x)?;
+
+                self.pc += 1;
+            }
+            Cmd::NativeCall(i) => {
+                self.check_host()?;
+                let arity = self.natives.get(i).ok_or(VmError::InvalidNative(i))?.arity;
+                if self.sp < arity {
+                    return Err(VmError::StackUnderflow);
+                }
+                let start = self.sp - arity;
+                let args = self.stack[start..self.sp].to_vec();
+                // ホスト関数にVMを渡す間は外しておく
+                let natives = mem::take(&mut self.natives);
+                let res = (natives[i].f)(&mut VmContext { vm: self }, &args);
+                self.natives = natives;
+                self.native_allocs.clear();
+                self.sp = start;
+                self.push(res?)?;
+
+                self.pc += 1;
+            }
+            Cmd::ConstLoad(i) => {
+                let x = *self.consts.get(i).ok_or(VmError::InvalidConst(i))?;
+                self.push(x)?;
+
+                self.pc += 1;
+            }
+            Cmd::StrConst(i) => {
+                let s = self.strings.get(i).ok_or(VmError::InvalidConst(i))?;
+                let bytes = s.clone().into_bytes();
+                self.push_bytes(&bytes)?;
+
+                self.pc += 1;
+            }
+            Cmd::StrConcat => {
+                let x = self.pop()?;
+                let y = self.pop()?;
+                let mut bytes = self.heap.load_bytes(x)?;
+                bytes.extend(self.heap.load_bytes(y)?);
+                self.push_bytes(&bytes)?;
+
+                self.pc += 1;
+            }
+            Cmd::StrLen => {
+                let x = self.pop()?;
+                let len = self.heap.load_bytes(x)?.len();
+                self.push(len as Value)?;
+
+                self.pc += 1;
+            }
+            Cmd::StrEq => {
+                let x = self.pop()?;
+                let y = self.pop()?;
+                let res = self.heap.load_bytes(x)? == self.heap.load_bytes(y)?;
+                self.push(if res { 1 } else { 0 })?;
+
+                self.pc += 1;
+            }
+            Cmd::HeapLoad => {
+                let addr = self.pop()?;
+                let offset = self.pop()?;
+                self.push(self.heap.load(addr, offset)?)?;
+
+                self.pc += 1;
+            }
+            Cmd::HeapStore => {
+                let addr = self.pop()?;
+                let offset = self.pop()?;
+                let x = self.pop()?;
+                self.heap.store(addr, offset, x)?;
+
+                self.pc += 1;
+            }
+            Cmd::JumpIf(i) => {
+                let x = self.pop()?;
+                if x != 0 {
+                    self.pc = i;
+                } else {
+                    self.pc += 1;
+                }
+            }
+            Cmd::Jump(i) => {
+                self.pc = i;
+            }
+            Cmd::JumpRel(_) | Cmd::JumpIfRel(_) | Cmd::CallRel(_) => unreachable!(),
+        }
+        match absolute {
+            Cmd::Entry(_)
+            | Cmd::Ret
+            | Cmd::Call(_)
+            | Cmd::CallIndirect
+            | Cmd::CallClosure
+            | Cmd::TailCall(_, _)
+            | Cmd::JumpIf(_)
+            | Cmd::Jump(_) => self.record_edge(pc, self.pc),
+            _ => {}
+        }
+        self.halted = self.pc == 0;
+        self.notify_tracer(&cmd, true);
+        if let Some(mut periodic) = self.periodic.take() {
+            periodic.remaining -= 1;
+            if periodic.remaining == 0 {
+                periodic.remaining = periodic.n;
+                (periodic.callback)(&self.view());
+            }
+            self.periodic = Some(periodic);
+        }
+        Ok(())
+    }
+
+    // x = pop, y = pop として x OP y を積む
+    fn arith(&mut self, cmd: &Cmd) -> Result<(), VmError> {
+        type Ops = (fn(Value, Value) -> Value, fn(Value, Value) -> Option<Value>);
+        let (wrapping, checked): Ops = match cmd {
+            Cmd::Add => (i64::wrapping_add, i64::checked_add),
+            Cmd::Sub => (i64::wrapping_sub, i64::checked_sub),
+            Cmd::Mul => (i64::wrapping_mul, i64::checked_mul),
+            Cmd::Div => (i64::wrapping_div, i64::checked_div),
+            Cmd::Mod => (i64::wrapping_rem, i64::checked_rem),
+            Cmd::And => (|x, y| x & y, |x, y| Some(x & y)),
+            Cmd::Or => (|x, y| x | y, |x, y| Some(x | y)),
+            Cmd::Xor => (|x, y| x ^ y, |x, y| Some(x ^ y)),
+            Cmd::Shl => (
+                |x, y| x.wrapping_shl(y as u32),
+                |x, y| {
+                    if (0..64).contains(&y) {
+                        Some(x << y)
+                    } else {
+                        None
+                    }
+                },
+            ),
+            Cmd::Shr => (
+                |x, y| x.wrapping_shr(y as u32),
+                |x, y| {
+                    if (0..64).contains(&y) {
+                        Some(x >> y)
+                    } else {
+                        None
+                    }
+                },
+            ),
+            _ => unreachable!(),
+        };
+        let x = self.pop()?;
+        let y = self.pop()?;
+        // ゼロ除算はどちらのモードでもエラー
+        if y == 0 && (*cmd == Cmd::Div || *cmd == Cmd::Mod) {
+            return Err(VmError::DivByZero);
+        }
+        let res = match self.overflow {
+            Overflow::Wrapping => wrapping(x, y),
+            Overflow::Checked => checked(x, y).ok_or(VmError::Overflow)?,
+        };
+        self.push(res)
+    }
+
+    // x = pop, y = pop として x OP y が成り立てば1、そうでなければ0を積む
+    fn compare(&mut self, cmd: &Cmd) -> Result<(), VmError> {
+        let x = self.pop()?;
+        let y = self.pop()?;
+        let res = match cmd {
+            Cmd::Eq => x == y,
+            Cmd::Ne => x != y,
+            Cmd::Lt => x < y,
+            Cmd::Le => x <= y,
+            Cmd::Gt => x > y,
+            Cmd::Ge => x >= y,
+            _ => unreachable!(),
+        };
+        self.push(if res { 1 } else { 0 })
+    }
+
+    fn check_host(&mut self) -> Result<(), VmError> {
+        if let Some(&func) = self.call_stack.last() {
+            if !self.flags(func).may_call_host {
+                return Err(VmError::HostNotAllowed { func });
+            }
+        }
+        if let Some(usage) = &mut self.usage {
+            usage.host_calls += 1;
+        }
+        Ok(())
+    }
+
+    // 空白で区切られた次の整数を読む。読みすぎないよう1バイトずつ読む
+    fn read_int(&mut self) -> Result<Value, VmError> {
+        let mut token = Vec::new();
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) if byte[0].is_ascii_whitespace() => {
+                    if !token.is_empty() {
+                        break;
+                    }
+                }
+                Ok(_) => token.push(byte[0]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(VmError::Io(e.kind())),
+            }
+        }
+        if token.is_empty() {
+            return Err(VmError::Io(io::ErrorKind::UnexpectedEof));
+        }
+        String::from_utf8(token)
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .ok_or(VmError::Io(io::ErrorKind::InvalidData))
+    }
+
+    // 確保の権限を確かめ、必要ならGCする
+    fn before_alloc(&mut self, size: usize) -> Result<(), VmError> {
+        if let Some(&func) = self.call_stack.last() {
+            if !self.flags(func).may_allocate {
+                return Err(VmError::AllocNotAllowed { func });
+            }
+        }
+        if let Some(threshold) = self.gc_threshold {
+            if self.allocated.saturating_add(size) > threshold {
+                self.collect_garbage();
+            }
+        }
+        if let Some(max) = self.max_heap_bytes {
+            let bytes = footprint(size).saturating_mul(CELL_BYTES);
+            if (self.heap.live_cells() * CELL_BYTES).saturating_add(bytes) > max {
+                return Err(VmError::HeapLimitExceeded { pc: self.pc, bytes });
+            }
+        }
+        self.allocated = self.allocated.saturating_add(size);
+        Ok(())
+    }
+
+    // ヒープのセル数の上限(heap::MAX_CELLS)に当たった
+    fn heap_full(&self, size: usize) -> VmError {
+        VmError::HeapLimitExceeded {
+            pc: self.pc,
+            bytes: footprint(size).saturating_mul(CELL_BYTES),
+        }
+    }
+
+    fn record_alloc(&mut self, addr: Value, size: usize) {
+        let bytes = (footprint(size) * CELL_BYTES) as u64;
+        if let Some(profile) = &mut self.profile {
+            profile.record_alloc(&self.call_stack, self.pc, addr as usize, bytes);
+        }
+        if let Some(usage) = &mut self.usage {
+            usage.allocated_bytes += bytes;
+        }
+    }
+
+    fn push_bytes(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.before_alloc(bytes.len() + 1)?;
+        let addr = self
+            .heap
+            .alloc_bytes(bytes)
+            .ok_or_else(|| self.heap_full(bytes.len() + 1))?;
+        self.record_alloc(addr, bytes.len() + 1);
+        self.push(addr)
+    }
+
+    // Call, CallIndirectで関数iを呼ぶ
+    fn call(&mut self, i: usize) -> Result<(), VmError> {
+        if let Some(&caller) = self.call_stack.last() {
+            if self.sandbox.is_some() && !self.flags(caller).allows(self.flags(i)) {
+                return Err(VmError::PermissionDenied { caller, callee: i });
+            }
+        }
+        let args = self.memo_args(i);
+        let cached = match (&args, &mut self.memo) {
+            (Some(args), Some(memo)) => memo.get(i, args),
+            _ => None,
+        };
+        if let Some(res) = cached {
+            // 本体を実行せず、呼び出して戻ってきたのと同じスタックにする
+            self.push((self.pc + 1) as Value)?;
+            self.push(res)?;
+            self.pc += 1;
+        } else {
+            if let Some(args) = args {
+                self.memo_pending.push((self.sp, i, args));
+            }
+            self.notify_call(i);
+            self.push((self.pc + 1) as Value)?;
+
+            self.pc = i;
+        }
+        Ok(())
+    }
+
+    // 値として持っていた関数のアドレスxを呼ぶ
+    fn call_value(&mut self, x: Value) -> Result<(), VmError> {
+        match self.program.get(x as usize) {
+            Some(Cmd::Frame(_, _)) if x >= 0 => self.call(x as usize),
+            _ => Err(VmError::InvalidCallTarget(x)),
+        }
+    }
+
+    fn record_edge(&mut self, from: usize, to: usize) {
+        if let Some(coverage) = &mut self.coverage {
+            if !coverage.is_empty() {
+                let i = (from.wrapping_mul(0x9E37_79B9) ^ to) % coverage.len();
+                coverage[i] = coverage[i].saturating_add(1);
+            }
+        }
+    }
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum Cmd {
+    // Frame(ローカル変数の数, オペランドスタックの最大使用量)
+    Frame(usize, usize),
+    Ret,
+    Call(usize),
+    LocalLoad(usize),
+    LocalStore(usize),
+    ArgLoad(usize),
+    ArgStore(usize),
+    PopR(usize),
+    Const(Value),
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Entry(usize),
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    JumpIf(usize),
+    Jump(usize),
+    // 0で初期化したn個のセルをヒープに確保し、先頭アドレスを積む
+    Alloc(usize),
+    // addr = pop, offset = pop としてヒープのaddr+offsetの値を積む
+    HeapLoad,
+    // addr = pop, offset = pop, x = pop としてヒープのaddr+offsetにxを書く
+    HeapStore,
+    // 文字列定数をヒープに複製し、そのアドレスを積む
+    StrConst(usize),
+    // x = pop, y = pop としてxとyを繋げた文字列を積む
+    StrConcat,
+    StrLen,
+    StrEq,
+    // 定数プールのi番目の値を積む
+    ConstLoad(usize),
+    // i番目のホスト関数を呼ぶ。登録時の引数の数だけpopし(最初に積んだものが最初の引数)、戻り値を積む
+    NativeCall(usize),
+    // popした値を一行で出力する
+    Print,
+    // 入力から空白区切りの整数を一つ読んで積む
+    ReadInt,
+    // この命令のアドレスからの相対アドレスで飛ぶJump, JumpIf, Call。再配置しても書き換えなくてよい
+    JumpRel(isize),
+    JumpIfRel(isize),
+    CallRel(isize),
+    // x = pop, y = pop として x OP y を積む。And, Or, Xorはビットごとの演算
+    And,
+    Or,
+    // x = pop として、xが0なら1、それ以外なら0を積む
+    Not,
+    Xor,
+    // xをyビット左(右)にずらす。Shrは算術シフト。yが0..64の外ならWrappingでは64の剰余を取り、Checkedではエラーにする
+    Shl,
+    Shr,
+    // スタックトップを複製する
+    Dup,
+    // スタックトップとその下を入れ替える
+    Swap,
+    // スタックトップを捨てる
+    Pop,
+    // TailCall(関数のアドレス, 引数の数)。スタックに積んだ引数で今の関数の引数を上書きし、
+    // 今のフレームを捨ててから呼ぶ。呼んだ関数は今の関数の呼び出し元に直接戻る
+    // 引数の数は今の関数が受け取った引数の数以下でなければならない
+    TailCall(usize, usize),
+    // 関数のアドレスを値として積む
+    FuncRef(usize),
+    // popした値をアドレスとする関数を呼ぶ。引数はその下に積んでおく
+    CallIndirect,
+    // MakeClosure(関数のアドレス, 捕捉する値の数)。n個popし(最初に積んだものが0番目)、
+    // 関数のアドレスと捕捉した値を並べたクロージャをヒープに確保してそのアドレスを積む
+    MakeClosure(usize, usize),
+    // スタックトップのクロージャの関数を呼ぶ。クロージャはpopせず、呼ばれた関数の0番目の引数になる
+    // 引数はクロージャの下に積んでおき、PopRで引数とクロージャをまとめて片付ける
+    CallClosure,
+    // 0番目の引数のクロージャが捕捉したi番目の値を積む
+    CaptureLoad(usize),
+}
+
+impl Cmd {
+    // pcにある相対ジャンプ・呼び出しを、同じ飛び先の絶対アドレスのものにする
+    pub fn absolute(&self, pc: usize) -> Cmd {
+        let to = |d: isize| pc.wrapping_add(d as usize);
+        match *self {
+            Cmd::JumpRel(d) => Cmd::Jump(to(d)),
+            Cmd::JumpIfRel(d) => Cmd::JumpIf(to(d)),
+            Cmd::CallRel(d) => Cmd::Call(to(d)),
+            ref cmd => cmd.clone(),
+        }
+    }
+
+    // ヒープかホスト関数を使う命令
+    fn uses_heap(&self) -> bool {
+        matches!(
+            self,
+            Cmd::Alloc(_)
+                | Cmd::HeapLoad
+                | Cmd::HeapStore
+                | Cmd::StrConst(_)
+                | Cmd::StrConcat
+                | Cmd::StrLen
+                | Cmd::StrEq
+                | Cmd::NativeCall(_)
+                | Cmd::MakeClosure(_, _)
+                | Cmd::CallClosure
+                | Cmd::CaptureLoad(_)
+        )
+    }
+}
+
+#[test]
+fn test() {
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 4),
+            Cmd::Const(1),
+            Cmd::Const(2),
+            Cmd::Call(7),
+            Cmd::PopR(2),
+            Cmd::Ret,
+            Cmd::Frame(0, 2),
+            Cmd::ArgLoad(0),
+            Cmd::ArgLoad(1),
+            Cmd::Add,
+            Cmd::Ret
+        ])
+        .run(),
+        Ok(3)
+    );
+
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),    // 0
+            Cmd::Frame(0, 4), // 1
+            Cmd::Const(182),  // 2
+            Cmd::Const(1029), // 3
+            Cmd::Call(7),     // 4
+            Cmd::PopR(2),     // 5
+            Cmd::Ret,         // 6
+            Cmd::Frame(0, 4), // 7 gcd(a:1, b:0)
+            Cmd::ArgLoad(0),  // 8
+            Cmd::Const(0),    // 9
+            Cmd::Eq,          // 10
+            Cmd::JumpIf(13),  // 11
+            Cmd::Jump(15),    //12
+            Cmd::ArgLoad(1),  //13
+            Cmd::Jump(21),    // 14
+            Cmd::ArgLoad(0),  //15
             Cmd::ArgLoad(0),  //16
             Cmd::ArgLoad(1),  //17
             Cmd::Mod,         //18
@@ -222,6 +1744,1396 @@ fn test() {
             Cmd::Ret          //21
         ])
         .run(),
-        7
+        Ok(7)
+    );
+}
+
+#[test]
+fn test_frame_stack_overflow() {
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1000),
+            Cmd::Const(1),
+            Cmd::Ret,
+        ])
+        .run(),
+        Err(VmError::StackOverflow)
+    );
+}
+
+#[test]
+fn test_error() {
+    let run = |cmds: Vec<Cmd>| VM::new(cmds).run();
+    assert_eq!(run(vec![Cmd::Entry(5)]), Err(VmError::InvalidPc(5)));
+    assert_eq!(
+        run(vec![Cmd::Entry(1), Cmd::Add]),
+        Err(VmError::StackUnderflow)
+    );
+    assert_eq!(
+        run(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::Const(0),
+            Cmd::Const(1),
+            Cmd::Mod,
+            Cmd::Ret
+        ]),
+        Err(VmError::DivByZero)
+    );
+    assert_eq!(
+        run(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::ArgLoad(5),
+            Cmd::Ret
+        ]),
+        Err(VmError::InvalidArg(5))
+    );
+    assert_eq!(
+        run(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::LocalLoad(0),
+            Cmd::Ret
+        ]),
+        Err(VmError::InvalidLocal(0))
+    );
+    assert_eq!(
+        run(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::PopR(0),
+            Cmd::Ret
+        ]),
+        Err(VmError::StackUnderflow)
+    );
+    assert_eq!(
+        run(vec![Cmd::Entry(1), Cmd::Const(1), Cmd::Ret]),
+        Err(VmError::InvalidFrame)
+    );
+    // 呼ばれた関数が引数への書き込みで呼び出し元のフレームを壊す
+    let clobber = |arg: usize| {
+        run(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::Call(4),
+            Cmd::Ret,
+            Cmd::Frame(0, 1),
+            Cmd::Const(999999),
+            Cmd::ArgStore(arg),
+            Cmd::Const(0),
+            Cmd::Ret,
+        ])
+    };
+    assert_eq!(clobber(0), Err(VmError::InvalidFrame));
+    assert_eq!(clobber(1), Err(VmError::InvalidPc(999999)));
+}
+
+#[test]
+fn test_heapless() {
+    // 1 + 2 + ... + 10 を再帰で
+    let program = vec![
+        Cmd::Entry(1),    // 0
+        Cmd::Frame(0, 3), // 1
+        Cmd::Const(10),   // 2
+        Cmd::Call(6),     // 3
+        Cmd::PopR(3),     // 4
+        Cmd::Ret,         // 5
+        Cmd::Frame(0, 4), // 6
+        Cmd::ArgLoad(0),  // 7
+        Cmd::JumpIf(10),  // 8
+        Cmd::Jump(18),    // 9
+        Cmd::Const(1),    // 10
+        Cmd::ArgLoad(0),  // 11
+        Cmd::Sub,         // 12
+        Cmd::Call(6),     // 13
+        Cmd::PopR(3),     // 14
+        Cmd::ArgLoad(0),  // 15
+        Cmd::Add,         // 16
+        Cmd::Ret,         // 17
+        Cmd::Const(0),    // 18
+        Cmd::Ret,         // 19
+    ];
+    let mut vm = VM::heapless::<20, 64>(program.clone()).unwrap();
+    assert_eq!(vm.run(), Ok(55));
+    assert_eq!(vm.stack.len(), 64);
+
+    // スタックは伸ばさずにStackOverflowにする
+    let mut deep = program.clone();
+    deep[2] = Cmd::Const(100);
+    let mut vm = VM::heapless::<20, 64>(deep).unwrap();
+    assert_eq!(vm.run(), Err(VmError::StackOverflow));
+    assert_eq!(vm.stack.len(), 64);
+
+    assert_eq!(
+        VM::heapless::<19, 64>(program.clone()).err(),
+        Some(HeaplessError::ProgramTooLarge { len: 20, max: 19 })
+    );
+    let mut alloc = program;
+    alloc[10] = Cmd::Alloc(1);
+    assert_eq!(
+        VM::heapless::<20, 64>(alloc).err(),
+        Some(HeaplessError::UsesHeap(10))
+    );
+}
+
+#[test]
+fn test_write_audit() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),      // 0
+        Cmd::Frame(1, 1),   // 1
+        Cmd::Const(5),      // 2
+        Cmd::LocalStore(0), // 3
+        Cmd::LocalLoad(0),  // 4
+        Cmd::Ret,           // 5
+    ]);
+    vm.enable_write_audit();
+    assert_eq!(vm.run(), Ok(5));
+    assert_eq!(vm.last_write(0), Some(0));
+    assert_eq!(vm.last_write(1), Some(5));
+    assert_eq!(vm.last_write(2), Some(3));
+    assert_eq!(vm.last_write(3), Some(4));
+    assert_eq!(vm.last_write(4), None);
+
+    // LocalStore(0)まで進める。フレームの外は読まない
+    let mut vm = VM::new(vm.program.clone());
+    vm.enable_write_audit();
+    for _ in 0..4 {
+        vm.step().unwrap();
+    }
+    assert_eq!(vm.local_last_write(0), Some(3));
+    assert_eq!(vm.local_last_write(1), None);
+    assert_eq!(vm.local_last_write(usize::MAX), None);
+}
+
+#[test]
+fn test_sanitizer() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),      // 0
+        Cmd::Frame(2, 1),   // 1
+        Cmd::Const(5),      // 2
+        Cmd::LocalStore(0), // 3
+        Cmd::LocalLoad(1),  // 4
+        Cmd::Ret,           // 5
+    ]);
+    vm.enable_sanitizer();
+    assert_eq!(
+        vm.run(),
+        Err(VmError::UninitializedLocal { local: 1, pc: 4 })
+    );
+}
+
+#[test]
+fn test_reset() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 2),
+        Cmd::Const(1),
+        Cmd::Const(2),
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    assert_eq!(vm.run(), Ok(3));
+    vm.reset(true);
+    assert!(vm.stack.iter().all(|x| *x == POISON));
+    assert_eq!(vm.run(), Ok(3));
+    assert_eq!(vm.stack[4], POISON);
+}
+
+#[test]
+fn test_call_observer() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Debug, PartialEq)]
+    enum Event {
+        Call(usize, Vec<Value>),
+        Return(usize, Value),
+    }
+
+    struct Recorder(Rc<RefCell<Vec<Event>>>);
+
+    impl CallObserver for Recorder {
+        fn on_call(&mut self, func: usize, args: &[Value]) {
+            self.0.borrow_mut().push(Event::Call(func, args.to_vec()));
+        }
+
+        fn on_return(&mut self, func: usize, result: Value) {
+            self.0.borrow_mut().push(Event::Return(func, result));
+        }
+    }
+
+    let events = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(1),
+        Cmd::Const(2),
+        Cmd::Call(7),
+        Cmd::PopR(2),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    vm.set_call_observer(Box::new(Recorder(events.clone())));
+    assert_eq!(vm.run(), Ok(3));
+    assert_eq!(
+        *events.borrow(),
+        vec![
+            Event::Call(1, vec![]),
+            Event::Call(7, vec![0, 0, 1, 2]),
+            Event::Return(7, 3),
+            Event::Return(1, 3),
+        ]
+    );
+}
+
+#[test]
+fn test_coverage() {
+    let program = vec![
+        Cmd::Entry(1),    // 0
+        Cmd::Frame(0, 2), // 1
+        Cmd::Const(1),    // 2
+        Cmd::JumpIf(5),   // 3
+        Cmd::Const(2),    // 4
+        Cmd::Const(3),    // 5
+        Cmd::Ret,         // 6
+    ];
+    let mut vm = VM::new(program.clone());
+    vm.enable_coverage(1 << 16);
+    assert_eq!(vm.run(), Ok(3));
+    let coverage = vm.coverage().unwrap();
+    // Entry->1, JumpIf->5, Ret->0
+    assert_eq!(coverage.iter().filter(|x| **x != 0).count(), 3);
+    assert_eq!(coverage.iter().map(|x| *x as usize).sum::<usize>(), 3);
+
+    let mut vm = VM::new(program);
+    assert_eq!(vm.run(), Ok(3));
+    assert_eq!(vm.coverage(), None);
+}
+
+#[test]
+fn test_every_n_instructions() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let pcs = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(1),
+        Cmd::Const(2),
+        Cmd::Call(7),
+        Cmd::PopR(2),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    let recorded = pcs.clone();
+    vm.every_n_instructions(5, move |view| {
+        recorded.borrow_mut().push((view.pc, view.sp))
+    });
+    assert_eq!(vm.run(), Ok(3));
+    // 5命令目のCall(7)の後と、10命令目のRetの後
+    assert_eq!(*pcs.borrow(), vec![(7, 5), (5, 6)]);
+}
+
+#[test]
+fn test_arithmetic() {
+    let run = |a: Value, b: Value, cmd: Cmd, overflow: Overflow| {
+        let mut vm = VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::Const(b),
+            Cmd::Const(a),
+            cmd,
+            Cmd::Ret,
+        ]);
+        vm.set_overflow(overflow);
+        vm.run()
+    };
+    // スタックトップが左辺
+    let wrapping = |a, b, cmd| run(a, b, cmd, Overflow::Wrapping);
+    assert_eq!(wrapping(10, 3, Cmd::Sub), Ok(7));
+    assert_eq!(wrapping(10, 3, Cmd::Mul), Ok(30));
+    assert_eq!(wrapping(10, 3, Cmd::Div), Ok(3));
+    assert_eq!(wrapping(10, 3, Cmd::Mod), Ok(1));
+    assert_eq!(wrapping(0, 1, Cmd::Sub), Ok(-1));
+    assert_eq!(wrapping(-7, 2, Cmd::Div), Ok(-3));
+    assert_eq!(wrapping(-7, 2, Cmd::Mod), Ok(-1));
+    assert_eq!(wrapping(10, 0, Cmd::Div), Err(VmError::DivByZero));
+    assert_eq!(wrapping(Value::MAX, 1, Cmd::Add), Ok(Value::MIN));
+    assert_eq!(wrapping(Value::MIN, -1, Cmd::Div), Ok(Value::MIN));
+
+    let checked = |a, b, cmd| run(a, b, cmd, Overflow::Checked);
+    assert_eq!(checked(0, 1, Cmd::Sub), Ok(-1));
+    assert_eq!(checked(10, 0, Cmd::Mod), Err(VmError::DivByZero));
+    assert_eq!(checked(Value::MAX, 1, Cmd::Add), Err(VmError::Overflow));
+    assert_eq!(checked(Value::MIN, 1, Cmd::Sub), Err(VmError::Overflow));
+    assert_eq!(checked(Value::MAX, 2, Cmd::Mul), Err(VmError::Overflow));
+    assert_eq!(checked(Value::MIN, -1, Cmd::Div), Err(VmError::Overflow));
+}
+
+#[test]
+fn test_bitwise() {
+    let run = |a: Value, b: Value, cmd: Cmd, overflow: Overflow| {
+        let mut vm = VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::Const(b),
+            Cmd::Const(a),
+            cmd,
+            Cmd::Ret,
+        ]);
+        vm.set_overflow(overflow);
+        vm.run()
+    };
+    let wrapping = |a, b, cmd| run(a, b, cmd, Overflow::Wrapping);
+    assert_eq!(wrapping(0b1100, 0b1010, Cmd::And), Ok(0b1000));
+    assert_eq!(wrapping(0b1100, 0b1010, Cmd::Or), Ok(0b1110));
+    assert_eq!(wrapping(0b1100, 0b1010, Cmd::Xor), Ok(0b0110));
+    assert_eq!(wrapping(1, 1, Cmd::And), Ok(1));
+    assert_eq!(wrapping(3, 4, Cmd::Shl), Ok(48));
+    assert_eq!(wrapping(-16, 2, Cmd::Shr), Ok(-4));
+    assert_eq!(wrapping(1, 65, Cmd::Shl), Ok(2));
+    assert_eq!(wrapping(1, 63, Cmd::Shl), Ok(Value::MIN));
+
+    let checked = |a, b, cmd| run(a, b, cmd, Overflow::Checked);
+    assert_eq!(checked(1, 63, Cmd::Shl), Ok(Value::MIN));
+    assert_eq!(checked(1, 64, Cmd::Shl), Err(VmError::Overflow));
+    assert_eq!(checked(1, -1, Cmd::Shr), Err(VmError::Overflow));
+
+    let not = |a| {
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::Const(a),
+            Cmd::Not,
+            Cmd::Ret,
+        ])
+        .run()
+    };
+    assert_eq!(not(0), Ok(1));
+    assert_eq!(not(1), Ok(0));
+    assert_eq!(not(-5), Ok(0));
+}
+
+#[test]
+fn test_stack_ops() {
+    let run = |cmds: Vec<Cmd>| {
+        let mut program = vec![Cmd::Entry(1), Cmd::Frame(0, 3)];
+        program.extend(cmds);
+        program.push(Cmd::Ret);
+        VM::new(program).run()
+    };
+    assert_eq!(run(vec![Cmd::Const(7), Cmd::Dup, Cmd::Mul]), Ok(49));
+    // スタックトップが左辺なので、入れ替えると 3 - 10 が 10 - 3 になる
+    assert_eq!(
+        run(vec![Cmd::Const(10), Cmd::Const(3), Cmd::Swap, Cmd::Sub]),
+        Ok(7)
+    );
+    assert_eq!(run(vec![Cmd::Const(1), Cmd::Const(2), Cmd::Pop]), Ok(1));
+    assert_eq!(VM::new(vec![Cmd::Dup]).run(), Err(VmError::StackUnderflow));
+    assert_eq!(
+        VM::new(vec![Cmd::Const(1), Cmd::Swap]).run(),
+        Err(VmError::StackUnderflow)
+    );
+}
+
+#[test]
+fn test_tail_call() {
+    // count(n) = if n == 0 { 0 } else { count(n - 1) } を、呼び出しごとにフレームを積まずに回す
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::Const(100000),
+        Cmd::Call(6),
+        Cmd::PopR(3),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::JumpIf(11),
+        Cmd::Const(0),
+        Cmd::Ret,
+        Cmd::Const(1),
+        Cmd::ArgLoad(0),
+        Cmd::Sub,
+        Cmd::TailCall(6, 1),
+    ];
+    assert_eq!(VM::new(program).run(), Ok(0));
+    assert_eq!(
+        VM::new(vec![Cmd::TailCall(0, 0)]).run(),
+        Err(VmError::InvalidFrame)
+    );
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::TailCall(1, usize::MAX)
+        ])
+        .run(),
+        Err(VmError::StackUnderflow)
+    );
+}
+
+#[test]
+fn test_call_indirect() {
+    // 2倍する関数と1を足す関数を順に3へ適用する
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(3),
+        Cmd::FuncRef(10),
+        Cmd::CallIndirect,
+        Cmd::PopR(3),
+        Cmd::FuncRef(15),
+        Cmd::CallIndirect,
+        Cmd::PopR(3),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(0),
+        Cmd::Add,
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::Const(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ];
+    assert_eq!(VM::new(program).run(), Ok(7));
+
+    let run = |x: Value| {
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::Const(x),
+            Cmd::CallIndirect,
+            Cmd::Ret,
+        ])
+        .run()
+    };
+    assert_eq!(run(2), Err(VmError::InvalidCallTarget(2)));
+    assert_eq!(run(-1), Err(VmError::InvalidCallTarget(-1)));
+    assert_eq!(run(100), Err(VmError::InvalidCallTarget(100)));
+    assert_eq!(
+        run(1).map_err(|e| e.kind()),
+        Err(VmErrorKind::StackOverflow)
+    );
+}
+
+#[test]
+fn test_closure() {
+    // 10を捕捉したクロージャに5を渡して足す
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(5),
+        Cmd::Const(10),
+        Cmd::MakeClosure(8, 1),
+        Cmd::CallClosure,
+        Cmd::PopR(4),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(1),
+        Cmd::CaptureLoad(0),
+        Cmd::Add,
+        Cmd::Ret,
+    ];
+    let mut vm = VM::new(program.clone());
+    assert_eq!(vm.run(), Ok(15));
+    assert_eq!(vm.heap.live_cells(), footprint(2));
+    assert_eq!((vm.heap.stats().blocks, vm.heap.stats().closures), (0, 1));
+
+    let mut broken = program;
+    broken[10] = Cmd::CaptureLoad(1);
+    assert_eq!(
+        VM::new(broken.clone()).run(),
+        Err(VmError::InvalidHeapAccess { addr: 1, offset: 2 })
+    );
+    broken[10] = Cmd::CaptureLoad(Value::MAX as usize);
+    assert_eq!(
+        VM::new(broken).run(),
+        Err(VmError::InvalidHeapAccess {
+            addr: 1,
+            offset: Value::MAX
+        })
+    );
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::Const(3),
+            Cmd::CallClosure,
+            Cmd::Ret,
+        ])
+        .run(),
+        Err(VmError::InvalidHeapAccess { addr: 3, offset: 0 })
+    );
+    let make = |n| {
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::MakeClosure(1, n),
+        ])
+        .run()
+    };
+    assert_eq!(make(3), Err(VmError::StackUnderflow));
+    assert_eq!(
+        make(usize::MAX),
+        Err(VmError::HeapLimitExceeded {
+            pc: 2,
+            bytes: usize::MAX
+        })
+    );
+}
+
+#[test]
+fn test_compare() {
+    let run = |a: Value, b: Value, cmd: Cmd| {
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 2),
+            Cmd::Const(b),
+            Cmd::Const(a),
+            cmd,
+            Cmd::Ret,
+        ])
+        .run()
+    };
+    for &(a, b) in &[
+        (1, 2),
+        (2, 2),
+        (3, 2),
+        (-1, 1),
+        (1, -1),
+        (Value::MIN, Value::MAX),
+    ] {
+        assert_eq!(run(a, b, Cmd::Eq), Ok((a == b) as Value));
+        assert_eq!(run(a, b, Cmd::Ne), Ok((a != b) as Value));
+        assert_eq!(run(a, b, Cmd::Lt), Ok((a < b) as Value));
+        assert_eq!(run(a, b, Cmd::Le), Ok((a <= b) as Value));
+        assert_eq!(run(a, b, Cmd::Gt), Ok((a > b) as Value));
+        assert_eq!(run(a, b, Cmd::Ge), Ok((a >= b) as Value));
+    }
+    // 符号付きで比較する
+    assert_eq!(run(-1, 1, Cmd::Lt), Ok(1));
+}
+
+#[test]
+fn test_host_stack() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(1),
+        Cmd::Sub,
+        Cmd::Ret,
+    ]);
+    assert_eq!(vm.to_int(-1), Err(VmError::InvalidIndex(-1)));
+    assert_eq!(vm.pop_int(), Err(VmError::StackUnderflow));
+    vm.push_int(10).unwrap();
+    vm.push_int(3).unwrap();
+    assert_eq!(vm.get_top(), 2);
+    assert_eq!(vm.to_int(1), Ok(10));
+    assert_eq!(vm.to_int(-1), Ok(3));
+    assert_eq!(vm.to_int(0), Err(VmError::InvalidIndex(0)));
+    assert_eq!(vm.to_int(3), Err(VmError::InvalidIndex(3)));
+
+    // ArgLoad(1) - ArgLoad(0)
+    assert_eq!(vm.run(), Ok(7));
+    assert_eq!(vm.to_int(-1), Ok(7));
+    assert_eq!(vm.pop_int(), Ok(7));
+    assert_eq!(vm.to_int(-1), Ok(0));
+    assert_eq!(vm.to_int(-2), Ok(3));
+}
+
+#[test]
+#[cfg(feature = "frontend")]
+fn test_growable_stack() {
+    use crate::genprog::{expected, generate, Workload};
+
+    let program = generate(Workload::DeepRecursion, 1000).convert();
+    let mut vm = VM::new(program.clone());
+    assert_eq!(vm.run(), Err(VmError::StackOverflow));
+    assert!(vm.stack.len() <= 1000);
+
+    let mut vm = VM::with_config(
+        program,
+        VmConfig::new()
+            .initial_stack_size(4)
+            .max_stack_size(1 << 16),
+    );
+    assert_eq!(vm.stack.len(), 4);
+    vm.enable_sanitizer();
+    vm.enable_write_audit();
+    assert_eq!(vm.run(), Ok(expected(Workload::DeepRecursion, 1000)));
+    assert!(vm.stack.len() > 1000);
+}
+
+#[test]
+fn test_tracer() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Tracer for Recorder {
+        fn on_before_cmd(&mut self, view: &VmView, cmd: &Cmd) {
+            self.0
+                .borrow_mut()
+                .push(format!("{} {:?} {:?}", view.pc, cmd, view.stack));
+        }
+    }
+
+    let lines = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::Const(5),
+        Cmd::Ret,
+    ]);
+    vm.set_tracer(Box::new(Recorder(lines.clone())));
+    assert_eq!(vm.run(), Ok(5));
+    assert_eq!(
+        *lines.borrow(),
+        vec![
+            "0 Entry(1) []",
+            "1 Frame(0, 1) [0]",
+            "2 Const(5) [0, 0]",
+            "3 Ret [0, 0, 5]",
+        ]
+    );
+}
+
+#[test]
+fn test_error_kind() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 2),
+        Cmd::Const(0),
+        Cmd::Const(1),
+        Cmd::Div,
+        Cmd::Ret,
+    ]);
+    let err = vm.run().unwrap_err();
+    assert_eq!(err.kind(), VmErrorKind::DivByZero);
+    assert_eq!(err.kind().code(), 8);
+    assert_eq!(vm.pc(), 4);
+    assert_eq!(
+        vm.error_report(&err),
+        "error: division by zero\n  at pc 4: Div\n  fp: 1\n  stack: [0, 0]"
+    );
+    assert_eq!(
+        VmError::UninitializedLocal { local: 0, pc: 3 }.kind(),
+        VmErrorKind::UninitializedLocal
+    );
+}
+
+#[test]
+fn test_warmup() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::Const(5),
+        Cmd::Ret,
+    ]);
+    vm.enable_sanitizer();
+    vm.warmup();
+    assert_eq!(vm.stack.len(), 1000);
+    assert_eq!(vm.initialized.as_ref().map(|x| x.len()), Some(1000));
+    assert_eq!(vm.run(), Ok(5));
+}
+
+#[test]
+fn test_step() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::Const(5),
+        Cmd::Ret,
+    ]);
+    assert!(!vm.is_halted());
+    assert_eq!(
+        vm.step(),
+        Ok((
+            Cmd::Entry(1),
+            VmState {
+                pc: 1,
+                fp: 0,
+                sp: 1,
+                stack: vec![0],
+            }
+        ))
+    );
+    assert_eq!(vm.step().map(|(cmd, _)| cmd), Ok(Cmd::Frame(0, 1)));
+    assert_eq!(vm.step().map(|(_, state)| state.stack), Ok(vec![0, 0, 5]));
+    assert!(!vm.is_halted());
+    assert_eq!(
+        vm.step().map(|(_, state)| (state.pc, state.stack)),
+        Ok((0, vec![0, 5]))
+    );
+    assert!(vm.is_halted());
+    assert_eq!(vm.step(), Err(VmError::Halted));
+    vm.reset(false);
+    assert_eq!(vm.run(), Ok(5));
+}
+
+#[test]
+fn test_sandbox() {
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(1),
+        Cmd::Const(2),
+        Cmd::Call(7),
+        Cmd::PopR(2),
+        Cmd::Ret,
+        Cmd::Frame(0, 2),
+        Cmd::ArgLoad(0),
+        Cmd::ArgLoad(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ];
+    let sandboxed = |flags: Vec<(usize, FuncFlags)>| {
+        let mut vm = VM::new(program.clone());
+        vm.enable_sandbox(flags.into_iter().collect());
+        vm.run()
+    };
+    assert_eq!(
+        sandboxed(vec![(1, FuncFlags::PURE), (7, FuncFlags::PURE)]),
+        Ok(3)
+    );
+    assert_eq!(sandboxed(vec![(7, FuncFlags::PURE)]), Ok(3));
+    assert_eq!(
+        sandboxed(vec![(1, FuncFlags::PURE)]),
+        Err(VmError::PermissionDenied {
+            caller: 1,
+            callee: 7
+        })
+    );
+    let host_only = FuncFlags {
+        may_call_host: true,
+        may_allocate: false,
+    };
+    assert_eq!(
+        sandboxed(vec![(1, host_only), (7, FuncFlags::ALL)]),
+        Err(VmError::PermissionDenied {
+            caller: 1,
+            callee: 7
+        })
+    );
+    assert!(FuncFlags::PURE.is_pure());
+}
+
+#[test]
+fn test_heap() {
+    let program = |offset| {
+        vec![
+            Cmd::Entry(1),
+            Cmd::Frame(1, 3),
+            Cmd::Alloc(2),
+            Cmd::LocalStore(0),
+            Cmd::Const(5),
+            Cmd::Const(1),
+            Cmd::LocalLoad(0),
+            Cmd::HeapStore,
+            Cmd::Const(offset),
+            Cmd::LocalLoad(0),
+            Cmd::HeapLoad,
+            Cmd::Ret,
+        ]
+    };
+    let mut vm = VM::new(program(1));
+    assert_eq!(vm.run(), Ok(5));
+    assert_eq!(vm.heap().blocks().collect::<Vec<_>>(), vec![(1, 2)]);
+    vm.reset(false);
+    assert_eq!(vm.heap().blocks().count(), 0);
+
+    assert_eq!(
+        VM::new(program(2)).run(),
+        Err(VmError::InvalidHeapAccess { addr: 1, offset: 2 })
+    );
+
+    let mut vm = VM::new(program(1));
+    vm.enable_sandbox(vec![(1, FuncFlags::PURE)].into_iter().collect());
+    assert_eq!(vm.run(), Err(VmError::AllocNotAllowed { func: 1 }));
+
+    // 上限を指定していなくても、大きすぎる確保はエラーにする
+    assert_eq!(
+        VM::new(vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 1),
+            Cmd::Alloc(usize::MAX)
+        ])
+        .run(),
+        Err(VmError::HeapLimitExceeded {
+            pc: 2,
+            bytes: usize::MAX
+        })
+    );
+}
+
+#[test]
+fn test_memoization() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let cmds = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Const(20),
+        Cmd::Call(6),
+        Cmd::PopR(2),
+        Cmd::Ret,
+        // fib(n:0)
+        Cmd::Frame(0, 4), // 6
+        Cmd::Const(2),
+        Cmd::ArgLoad(0),
+        Cmd::Lt,
+        Cmd::JumpIf(23),
+        Cmd::Const(1),
+        Cmd::ArgLoad(0),
+        Cmd::Sub,
+        Cmd::Call(6),
+        Cmd::PopR(3),
+        Cmd::Const(2),
+        Cmd::ArgLoad(0),
+        Cmd::Sub,
+        Cmd::Call(6),
+        Cmd::PopR(3),
+        Cmd::Add,
+        Cmd::Ret,
+        Cmd::ArgLoad(0), // 23
+        Cmd::Ret,
+    ];
+    let steps = |vm: &mut VM| {
+        let count = Rc::new(Cell::new(0));
+        let counter = count.clone();
+        vm.every_n_instructions(1, move |_| counter.set(counter.get() + 1));
+        assert_eq!(vm.run(), Ok(6765));
+        count.get()
+    };
+
+    let pure = || vec![(6, FuncFlags::PURE)].into_iter().collect();
+    let plain = steps(&mut VM::new(cmds.clone()));
+    let mut vm = VM::new(cmds.clone());
+    vm.enable_memoization(64);
+    vm.memoize(6, 1);
+    vm.enable_sandbox(pure());
+    let memoized = steps(&mut vm);
+    assert!(memoized * 100 < plain);
+    let memo = vm.memo().unwrap();
+    assert_eq!((memo.len(), memo.hits), (21, 18));
+
+    // 2件しか覚えられなくても結果は変わらない
+    let mut vm = VM::new(cmds.clone());
+    vm.enable_memoization(2);
+    vm.memoize(6, 1);
+    vm.enable_sandbox(pure());
+    assert_eq!(vm.run(), Ok(6765));
+
+    // PUREでない関数はメモ化しない。サンドボックスがなければすべての関数がPUREでない
+    let mut vm = VM::new(cmds.clone());
+    vm.enable_memoization(64);
+    vm.memoize(6, 1);
+    vm.enable_sandbox(vec![(6, FuncFlags::ALL)].into_iter().collect());
+    assert_eq!(vm.run(), Ok(6765));
+    assert_eq!(vm.memo().unwrap().hits, 0);
+    let mut vm = VM::new(cmds);
+    vm.enable_memoization(64);
+    vm.memoize(6, 1);
+    assert_eq!(vm.run(), Ok(6765));
+    assert_eq!(vm.memo().unwrap().hits, 0);
+
+    // メモ化した戻り値が指すブロックはGCで回収しない
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 4),
+        Cmd::Alloc(1),
+        Cmd::Call(7),
+        Cmd::PopR(3),
+        Cmd::Const(0),
+        Cmd::Ret,
+        // id(x:0)
+        Cmd::Frame(0, 1), // 7
+        Cmd::ArgLoad(0),
+        Cmd::Ret,
+    ]);
+    vm.enable_memoization(64);
+    vm.memoize(7, 1);
+    vm.enable_sandbox(vec![(7, FuncFlags::PURE)].into_iter().collect());
+    assert_eq!(vm.run(), Ok(0));
+    assert_eq!(vm.memo().unwrap().len(), 1);
+    assert_eq!(vm.collect_garbage(), 0);
+    assert_eq!(vm.heap().blocks().count(), 1);
+}
+
+#[test]
+fn test_gc() {
+    // 100回、2セルのブロックを確保しては捨てる。最後のブロックだけ0番目のローカル変数から辿れる
+    // カウンタはアドレスと見間違えないよう負の数で数える
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(2, 3),
+        Cmd::Const(-100),
+        Cmd::LocalStore(1),
+        Cmd::Alloc(2), // 4
+        Cmd::LocalStore(0),
+        Cmd::Const(1),
+        Cmd::LocalLoad(1),
+        Cmd::Add,
+        Cmd::LocalStore(1),
+        Cmd::Const(0),
+        Cmd::LocalLoad(1),
+        Cmd::Ne,
+        Cmd::JumpIf(4),
+        Cmd::LocalLoad(0),
+        Cmd::Ret,
+    ];
+    let mut vm = VM::new(program.clone());
+    vm.run().unwrap();
+    assert_eq!(vm.heap().live_cells(), 200);
+
+    let mut vm = VM::new(program.clone());
+    vm.set_gc_threshold(10);
+    let addr = vm.run().unwrap();
+    assert!(vm.heap().live_cells() <= 12);
+    assert_eq!(vm.heap().load(addr, 1), Ok(0));
+    let garbage = vm.heap().blocks().count() - 1;
+    assert_eq!(vm.collect_garbage(), garbage);
+    assert_eq!(
+        vm.heap().blocks().collect::<Vec<_>>(),
+        vec![(addr as usize, 2)]
+    );
+
+    let mut vm = VM::with_config(program.clone(), VmConfig::new().max_heap_bytes(64));
+    assert_eq!(
+        vm.run(),
+        Err(VmError::HeapLimitExceeded { pc: 4, bytes: 16 })
+    );
+    assert_eq!(vm.heap().stats().blocks, 4);
+    assert_eq!(vm.heap().stats().high_water_bytes, 64);
+
+    // GCで回収できれば上限内で動き続ける
+    let mut vm = VM::with_config(program, VmConfig::new().max_heap_bytes(64));
+    vm.set_gc_threshold(6);
+    assert!(vm.run().is_ok());
+    assert_eq!(vm.heap().stats().blocks, 100);
+    assert!(vm.heap().stats().high_water_bytes <= 64);
+}
+
+#[test]
+fn test_string() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(1, 3),
+        Cmd::StrConst(1),
+        Cmd::StrConst(0),
+        Cmd::StrConcat,
+        Cmd::LocalStore(0),
+        Cmd::StrConst(2),
+        Cmd::LocalLoad(0),
+        Cmd::StrEq,
+        Cmd::LocalLoad(0),
+        Cmd::StrLen,
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    vm.set_strings(vec![
+        "foo".to_string(),
+        "bar".to_string(),
+        "foobar".to_string(),
+    ]);
+    assert_eq!(vm.run(), Ok(7));
+    let addr = vm.heap().blocks().map(|(addr, _)| addr).nth(2).unwrap();
+    assert_eq!(vm.heap().load_bytes(addr as Value), Ok(b"foobar".to_vec()));
+
+    assert_eq!(vm.heap().stats().strings, 4);
+
+    let mut vm = VM::new(vec![Cmd::StrConst(0)]);
+    assert_eq!(vm.run(), Err(VmError::InvalidConst(0)));
+
+    let mut vm = VM::new(vec![Cmd::Const(0), Cmd::StrLen]);
+    assert_eq!(
+        vm.run(),
+        Err(VmError::InvalidHeapAccess { addr: 0, offset: 0 })
+    );
+}
+
+#[test]
+fn test_const_load() {
+    let program = Program {
+        consts: vec![1 << 40, 3],
+        strings: vec!["ab".to_string()],
+        code: vec![
+            Cmd::Entry(1),
+            Cmd::Frame(0, 3),
+            Cmd::ConstLoad(1),
+            Cmd::StrConst(0),
+            Cmd::StrLen,
+            Cmd::ConstLoad(0),
+            Cmd::Add,
+            Cmd::Add,
+            Cmd::Ret,
+        ],
+    };
+    let mut vm = VM::from_program(program, VmConfig::new());
+    assert_eq!(vm.run(), Ok((1 << 40) + 5));
+
+    assert_eq!(
+        VM::new(vec![Cmd::ConstLoad(0)]).run(),
+        Err(VmError::InvalidConst(0))
+    );
+}
+
+#[test]
+fn test_io() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // 2つ読んで、それぞれと和を出力する
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 3),
+        Cmd::ReadInt,
+        Cmd::ReadInt,
+        Cmd::Add,
+        Cmd::ReadInt,
+        Cmd::Print,
+        Cmd::Print,
+        Cmd::Const(0),
+        Cmd::Ret,
+    ];
+    let output = Rc::new(RefCell::new(Vec::new()));
+    let mut vm = VM::new(program.clone());
+    vm.set_input(Box::new(&b" 3\n-4  10 rest"[..]));
+    vm.set_output(Box::new(Output(output.clone())));
+    assert_eq!(vm.run(), Ok(0));
+    assert_eq!(&output.borrow()[..], b"10\n-1\n");
+
+    let mut vm = VM::new(program.clone());
+    vm.set_input(Box::new(&b"1 x"[..]));
+    assert_eq!(vm.run(), Err(VmError::Io(io::ErrorKind::InvalidData)));
+    let mut vm = VM::new(program.clone());
+    vm.set_input(Box::new(&b"1 "[..]));
+    assert_eq!(vm.run(), Err(VmError::Io(io::ErrorKind::UnexpectedEof)));
+
+    let mut vm = VM::new(program);
+    vm.set_input(Box::new(&b"1 2 3"[..]));
+    vm.enable_sandbox(vec![(1, FuncFlags::PURE)].into_iter().collect());
+    assert_eq!(vm.run(), Err(VmError::HostNotAllowed { func: 1 }));
+}
+
+#[test]
+fn test_native_call() {
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 3),
+        Cmd::Const(10),
+        Cmd::Const(3),
+        Cmd::NativeCall(0),
+        Cmd::StrConst(0),
+        Cmd::NativeCall(1),
+        Cmd::Add,
+        Cmd::Ret,
+    ]);
+    vm.set_strings(vec!["hello".to_string()]);
+    let sub = vm.register_native(2, |_, args| Ok(args[0] - args[1]));
+    let len = vm.register_native(1, |context, args| {
+        Ok(context.read_bytes(args[0])?.len() as Value)
+    });
+    assert_eq!((sub, len), (0, 1));
+    assert_eq!(vm.run(), Ok(12));
+
+    let mut vm = VM::new(vec![Cmd::Const(0), Cmd::NativeCall(0)]);
+    vm.register_native(1, |_, _| Err(VmError::DivByZero));
+    assert_eq!(vm.run(), Err(VmError::DivByZero));
+    assert_eq!(
+        VM::new(vec![Cmd::NativeCall(0)]).run(),
+        Err(VmError::InvalidNative(0))
+    );
+
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::NativeCall(0),
+        Cmd::Ret,
+    ]);
+    vm.register_native(0, |_, _| Ok(0));
+    vm.enable_sandbox(vec![(1, FuncFlags::PURE)].into_iter().collect());
+    assert_eq!(vm.run(), Err(VmError::HostNotAllowed { func: 1 }));
+
+    // ホスト関数での確保もAllocと同じく数え、制限する
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 3),
+        Cmd::Const(8),
+        Cmd::Const(7),
+        Cmd::NativeCall(0),
+        Cmd::Ret,
+    ];
+    // 2つ目の確保でGCが走っても、1つ目のブロックは回収されない
+    let pair = |context: &mut VmContext, args: &[Value]| {
+        let a = context.alloc(2)?;
+        let b = context.alloc(2)?;
+        context.write(a, 0, args[0])?;
+        context.write(a, 1, b)?;
+        context.write(b, 0, args[1])?;
+        Ok(a)
+    };
+    let mut vm = VM::new(program.clone());
+    vm.register_native(2, pair);
+    vm.set_gc_threshold(2);
+    let addr = vm.run().unwrap();
+    assert_eq!(vm.heap().load(addr, 0), Ok(8));
+    let b = vm.heap().load(addr, 1).unwrap();
+    assert_eq!(vm.heap().load(b, 0), Ok(7));
+    assert_eq!(vm.heap().stats().blocks, 2);
+
+    let mut vm = VM::with_config(program.clone(), VmConfig::new().max_heap_bytes(16));
+    vm.register_native(2, pair);
+    assert_eq!(
+        vm.run(),
+        Err(VmError::HeapLimitExceeded { pc: 4, bytes: 16 })
+    );
+
+    let mut vm = VM::new(program);
+    vm.register_native(2, pair);
+    let flags = FuncFlags {
+        may_call_host: true,
+        may_allocate: false,
+    };
+    vm.enable_sandbox(vec![(1, flags)].into_iter().collect());
+    assert_eq!(vm.run(), Err(VmError::AllocNotAllowed { func: 1 }));
+}
+
+#[test]
+fn test_fuel() {
+    // 100周してから戻るループ
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(1, 2),
+        Cmd::Const(-100),
+        Cmd::LocalStore(0),
+        Cmd::Const(1), // 4
+        Cmd::LocalLoad(0),
+        Cmd::Add,
+        Cmd::LocalStore(0),
+        Cmd::Const(0),
+        Cmd::LocalLoad(0),
+        Cmd::Ne,
+        Cmd::JumpIf(4),
+        Cmd::Const(7),
+        Cmd::Ret,
+    ];
+    let mut vm = VM::new(program.clone());
+    assert_eq!(vm.run_with_fuel(10_000), Ok(7));
+    let used = 10_000 - vm.fuel().unwrap();
+    assert_eq!(used, 4 + 8 * 100 + 2);
+
+    let mut vm = VM::new(program.clone());
+    assert_eq!(vm.run_with_fuel(50), Err(VmError::OutOfFuel));
+    assert_eq!(vm.fuel(), Some(0));
+    assert_eq!(vm.run(), Err(VmError::OutOfFuel));
+    vm.add_fuel(used - 51);
+    assert_eq!(vm.run(), Err(VmError::OutOfFuel));
+    vm.add_fuel(1);
+    assert_eq!(vm.run(), Ok(7));
+    assert_eq!(vm.fuel(), Some(0));
+
+    let mut vm = VM::new(program);
+    assert_eq!(vm.fuel(), None);
+    assert_eq!(vm.run(), Ok(7));
+}
+
+#[test]
+fn test_relative() {
+    // 前に置いた命令の数によらず同じ結果になる
+    let program = |padding: usize| {
+        let mut cmds = vec![Cmd::Entry(padding + 1)];
+        cmds.extend(vec![Cmd::Const(0); padding]);
+        cmds.extend(vec![
+            Cmd::Frame(0, 3),
+            Cmd::Const(2),
+            Cmd::CallRel(3),
+            Cmd::PopR(2),
+            Cmd::Ret,
+            // f(x) = if x == 2 { 1 } else { 0 }
+            Cmd::Frame(0, 2),
+            Cmd::Const(2),
+            Cmd::ArgLoad(0),
+            Cmd::Eq,
+            Cmd::JumpIfRel(3),
+            Cmd::Const(0),
+            Cmd::JumpRel(2),
+            Cmd::Const(1),
+            Cmd::Ret,
+        ]);
+        cmds
+    };
+    assert_eq!(VM::new(program(0)).run(), Ok(1));
+    assert_eq!(VM::new(program(5)).run(), Ok(1));
+    assert_eq!(Cmd::JumpRel(-6).absolute(12), Cmd::Jump(6));
+    assert_eq!(
+        VM::new(vec![Cmd::JumpRel(-1)]).run(),
+        Err(VmError::InvalidPc(usize::MAX))
+    );
+}
+
+#[test]
+fn test_billing() {
+    let program = vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 2),
+        Cmd::Alloc(2),
+        Cmd::Pop,
+        Cmd::Const(3),
+        Cmd::Print,
+        Cmd::Const(4),
+        Cmd::Ret,
+    ];
+    let bytes = (footprint(2) * CELL_BYTES) as u64;
+    let mut vm = VM::new(program.clone());
+    vm.set_output(Box::new(io::sink()));
+    assert_eq!(vm.run_with(RunOptions::new().tenant("acme")), Ok(4));
+    assert_eq!(
+        vm.ledger().usage("acme"),
+        Some(&Usage {
+            instructions: 8,
+            gas: 0,
+            host_calls: 1,
+            allocated_bytes: bytes,
+        })
+    );
+
+    // 燃料切れで止まった分も記録する
+    let mut vm2 = VM::new(program);
+    vm2.set_output(Box::new(io::sink()));
+    assert_eq!(
+        vm2.run_with(RunOptions::new().tenant("acme").fuel(4)),
+        Err(VmError::OutOfFuel)
+    );
+    assert_eq!(
+        vm2.run_with(RunOptions::new().tenant("other").fuel(10)),
+        Ok(4)
+    );
+    let mut ledger = vm.take_ledger();
+    assert!(vm.ledger().is_empty());
+    ledger.merge(vm2.ledger());
+    assert_eq!(
+        ledger.usage("acme"),
+        Some(&Usage {
+            instructions: 12,
+            gas: 4,
+            host_calls: 1,
+            allocated_bytes: 2 * bytes,
+        })
+    );
+    assert_eq!(ledger.usage("other").unwrap().host_calls, 1);
+    assert_eq!(ledger.total().instructions, 16);
+
+    // テナントを指定しなければ記録しない
+    let mut vm = VM::new(vec![
+        Cmd::Entry(1),
+        Cmd::Frame(0, 1),
+        Cmd::Const(1),
+        Cmd::Ret,
+    ]);
+    assert_eq!(vm.run_with(RunOptions::new()), Ok(1));
+    assert!(vm.ledger().is_empty());
+}
+
+#[test]
+fn test_golden_trace() {
+    use crate::golden::Step;
+    // gcd(1029, 182)
+    let program = |modulo: Cmd| {
+        vec![
+            Cmd::Entry(1),    // 0
+            Cmd::Frame(0, 4), // 1
+            Cmd::Const(182),  // 2
+            Cmd::Const(1029), // 3
+            Cmd::Call(7),     // 4
+            Cmd::PopR(4),     // 5
+            Cmd::Ret,         // 6
+            Cmd::Frame(0, 4), // 7
+            Cmd::ArgLoad(1),  // 8
+            Cmd::Const(0),    // 9
+            Cmd::Eq,          // 10
+            Cmd::JumpIf(17),  // 11
+            Cmd::ArgLoad(1),  // 12
+            Cmd::ArgLoad(0),  // 13
+            modulo,           // 14
+            Cmd::ArgLoad(1),  // 15
+            Cmd::Jump(19),    // 16
+            Cmd::ArgLoad(0),  // 17
+            Cmd::Ret,         // 18
+            Cmd::Call(7),     // 19
+            Cmd::PopR(4),     // 20
+            Cmd::Ret,         // 21
+        ]
+    };
+    let mut vm = VM::new(program(Cmd::Mod));
+    vm.enable_golden_trace();
+    assert_eq!(vm.run(), Ok(7));
+    let reference = GoldenTrace::from_text(&vm.golden_trace().unwrap().to_text()).unwrap();
+    assert_eq!(reference.steps[2], Step::new(2, &[0, 0], &[0, 0, 182]));
+
+    let mut vm = VM::new(program(Cmd::Mod));
+    assert_eq!(
+        vm.run_and_compare(&reference, Projection::PcsAndValues),
+        (Ok(7), None)
+    );
+
+    // 剰余の代わりに減算にすると、同じ経路を通るが値が変わる
+    let mut vm = VM::new(program(Cmd::Sub));
+    let (_, divergence) = vm.run_and_compare(&reference, Projection::PcsAndValues);
+    let divergence = divergence.unwrap();
+    assert_eq!(divergence.index, 12);
+    assert_eq!(divergence.expected.unwrap().pushed, vec![119]);
+    assert_eq!(divergence.actual.unwrap().pushed, vec![847]);
+    assert_eq!(divergence.context.len(), 3);
+    let mut vm = VM::new(program(Cmd::Sub));
+    let (_, divergence) = vm.run_and_compare(&reference, Projection::Pcs);
+    assert!(divergence.unwrap().index > 12);
+
+    // pcだけ比べるときは値を記録しない
+    let mut vm = VM::new(program(Cmd::Mod));
+    vm.enable_golden_trace_with(Projection::Pcs);
+    assert_eq!(vm.run(), Ok(7));
+    let pcs = vm.golden_trace().unwrap();
+    assert_eq!(pcs.steps.len(), reference.steps.len());
+    assert!(pcs
+        .steps
+        .iter()
+        .all(|s| s.popped.is_empty() && s.pushed.is_empty()));
+    assert_eq!(reference.compare(pcs, Projection::Pcs), None);
+
+    // 比べ終わったら呼び出し前の記録の状態に戻る
+    let mut vm = VM::new(program(Cmd::Mod));
+    assert_eq!(
+        vm.run_and_compare(&reference, Projection::Pcs),
+        (Ok(7), None)
     );
+    assert_eq!(vm.golden_trace(), None);
 }